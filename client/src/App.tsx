@@ -1,4 +1,4 @@
-import { useCallback, useState } from "react";
+import { useCallback, useEffect, useState } from "react";
 import { useDropzone } from "react-dropzone";
 import SyncLoader from "react-spinners/SyncLoader";
 import * as api from "./api";
@@ -8,10 +8,50 @@ import { LoginView, useLoginStatus } from "./Login";
 
 import "./App.css";
 
+/** Interval between two refreshes of the queue position */
+const QUEUE_POLL_MS = 1000;
+
+/** Position of the image in the processing queue, while `working` */
+function useQueueMessage(working: boolean): string | undefined {
+  const [message, setMessage] = useState<string | undefined>(undefined);
+
+  useEffect(() => {
+    if (!working) {
+      setMessage(undefined);
+      return;
+    }
+
+    const refresh = () => {
+      api
+        .getQueue()
+        .then((status: api.QueueStatus) => {
+          const job = status.jobs.find((j) => j.position !== null);
+          if (!job) {
+            setMessage(undefined);
+          } else if (job.estimated_wait_seconds === null) {
+            setMessage(`Position ${job.position} in the queue`);
+          } else {
+            const wait = Math.ceil(job.estimated_wait_seconds);
+            setMessage(
+              `Position ${job.position} in the queue, about ${wait} s`
+            );
+          }
+        })
+        .catch((err: any) => console.error(err));
+    };
+    refresh();
+    const timer = setInterval(refresh, QUEUE_POLL_MS);
+    return () => clearInterval(timer);
+  }, [working]);
+
+  return message;
+}
+
 function Dropzone() {
   const [displayUrl, setDisplayBlob] = useBlobUrlState();
   const [working, setWorking] = useState(false);
   const [error, setError] = useState<string | undefined>(undefined);
+  const queueMessage = useQueueMessage(working);
   const onDrop = useCallback(
     (acceptedFiles: File[]) => {
       if (acceptedFiles.length === 0) {
@@ -42,7 +82,17 @@ function Dropzone() {
   );
   const { getRootProps, getInputProps, isDragActive } = useDropzone({
     onDrop,
-    accept: ["image/png", "image/jpeg"],
+    accept: [
+      "image/png",
+      "image/jpeg",
+      ".dng",
+      ".cr2",
+      ".nef",
+      ".arw",
+      ".orf",
+      ".rw2",
+      ".raf",
+    ],
     disabled: working,
   });
 
@@ -58,6 +108,7 @@ function Dropzone() {
         {working && (
           <div className="loader">
             <SyncLoader color="#0262c8" />
+            {queueMessage && <div className="queue">{queueMessage}</div>}
           </div>
         )}
         {displayUrl && <img src={displayUrl} alt="" />}
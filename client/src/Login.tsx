@@ -1,6 +1,6 @@
 import React, { useCallback, useEffect, useState } from "react";
 import * as api from "./api";
-import type { LoginResponse, MeResponse, SessionInfo } from "./api";
+import type { LoginResponse, MeResponse, UserProfile } from "./api";
 
 function LoginForm({ checkLoginStatus }: { checkLoginStatus: () => void }) {
   const [working, setWorking] = useState(false);
@@ -45,8 +45,8 @@ function LoginForm({ checkLoginStatus }: { checkLoginStatus: () => void }) {
       .register(login, password)
       .then((result: LoginResponse) => {
         setWorking(false);
-        if (!result.success && result.error) {
-          setError(result.error);
+        if (!result.success) {
+          setError(api.errorMessage(result) ?? "Registration failed");
         } else {
           void onLogin();
         }
@@ -86,7 +86,7 @@ export type LoginStatus =
   | "unknown"
   | "checking"
   | "not_authenticated"
-  | SessionInfo;
+  | UserProfile;
 
 export function useLoginStatus(): [LoginStatus, () => void] {
   const [status, setStatus] = useState<LoginStatus>("unknown");
@@ -101,8 +101,8 @@ export function useLoginStatus(): [LoginStatus, () => void] {
     api
       .getMe()
       .then((result: MeResponse) => {
-        if (result.session) {
-          setStatus(result.session);
+        if (result.profile) {
+          setStatus(result.profile);
         } else {
           setStatus("not_authenticated");
         }
@@ -1,4 +1,10 @@
+import type { MeResponse } from "./bindings/MeResponse";
+import type { QueueStatus } from "./bindings/QueueStatus";
+
 export {};
+export type { MeResponse, QueueStatus };
+export type { UserProfile } from "./bindings/UserProfile";
+
 const API = "/api";
 
 export async function run(file: Blob): Promise<Blob> {
@@ -12,12 +18,29 @@ export async function run(file: Blob): Promise<Blob> {
     cache: "no-store",
   });
 
+  if (!r.ok) {
+    throw new Error(`${r.status} ${r.statusText}`);
+  }
+
   return await r.blob();
 }
 
+export interface FieldError {
+  readonly field: string;
+  readonly message: string;
+}
+
 export interface LoginResponse {
   readonly success: boolean;
   readonly error?: string;
+  readonly fields?: FieldError[];
+}
+
+export function errorMessage(response: LoginResponse): string | undefined {
+  if (response.fields && response.fields.length > 0) {
+    return response.fields.map((f) => f.message).join(". ");
+  }
+  return response.error;
 }
 
 export async function login(
@@ -60,14 +83,6 @@ export async function logout(): Promise<void> {
   });
 }
 
-export interface SessionInfo {
-  readonly login: string;
-}
-
-export interface MeResponse {
-  readonly session?: SessionInfo;
-}
-
 export async function getMe(): Promise<MeResponse> {
   const r = await fetch(`${API}/me`, {
     method: "GET",
@@ -76,3 +91,15 @@ export async function getMe(): Promise<MeResponse> {
   });
   return await r.json();
 }
+
+export async function getQueue(): Promise<QueueStatus> {
+  const r = await fetch(`${API}/queue`, {
+    method: "GET",
+    credentials: "include",
+    cache: "no-store",
+  });
+  if (!r.ok) {
+    throw new Error(`${r.status} ${r.statusText}`);
+  }
+  return await r.json();
+}
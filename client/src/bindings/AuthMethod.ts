@@ -0,0 +1 @@
+export type AuthMethod = "session" | "api_token";
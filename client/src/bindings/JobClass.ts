@@ -0,0 +1 @@
+export type JobClass = "interactive" | "batch";
@@ -0,0 +1,3 @@
+import type { JobClass } from "./JobClass";
+
+export interface JobStatus { class: JobClass, position: number | null, estimated_wait_seconds: number | null, queued_at: string, }
@@ -0,0 +1,3 @@
+import type { UserProfile } from "./UserProfile";
+
+export interface MeResponse { version: number, profile: UserProfile | null, }
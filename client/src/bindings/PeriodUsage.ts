@@ -0,0 +1,4 @@
+import type { QuotaLimits } from "./QuotaLimits";
+import type { Usage } from "./Usage";
+
+export interface PeriodUsage { used: Usage, limits: QuotaLimits, resets_at: string, }
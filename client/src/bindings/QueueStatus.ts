@@ -0,0 +1,3 @@
+import type { JobStatus } from "./JobStatus";
+
+export interface QueueStatus { queued: number, running: number, slots: number, jobs: Array<JobStatus>, }
@@ -0,0 +1 @@
+export interface QuotaLimits { requests: number | null, megapixels: number | null, compute_seconds: number | null, }
@@ -0,0 +1 @@
+export interface QuotaOverride { daily_requests: number | null, daily_megapixels: number | null, daily_compute_seconds: number | null, monthly_requests: number | null, monthly_megapixels: number | null, monthly_compute_seconds: number | null, }
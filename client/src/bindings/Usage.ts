@@ -0,0 +1 @@
+export interface Usage { requests: number, megapixels: number, compute_seconds: number, }
@@ -0,0 +1,3 @@
+import type { PeriodUsage } from "./PeriodUsage";
+
+export interface UsageReport { daily: PeriodUsage, monthly: PeriodUsage, }
@@ -0,0 +1,5 @@
+import type { AuthMethod } from "./AuthMethod";
+import type { Role } from "./Role";
+import type { UsageReport } from "./UsageReport";
+
+export interface UserProfile { id: number, login: string, role: Role, created_at: string | null, auth: AuthMethod, expires_at: string | null, usage: UsageReport, }
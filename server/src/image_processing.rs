@@ -1,11 +1,59 @@
 mod conversions;
 pub(self) use conversions::{image_to_tensor, tensor_to_image};
 
+mod raw;
+pub use raw::RawOptions;
+
+mod decoding;
+pub(self) use decoding::decode_image;
+
+mod region;
+pub use region::RegionOptions;
+pub(self) use region::{enhance_region, RegionError, Selection};
+
+mod light_detection;
+pub use light_detection::LightOptions;
+pub(self) use light_detection::{enhance_if_needed, LightReport};
+
+mod comparison;
+pub use comparison::ComparisonOptions;
+pub(self) use comparison::render_comparison;
+
 mod mirnet_model;
 pub(self) use mirnet_model::MirnetModel;
 
+mod scheduler;
+pub(self) use scheduler::JobClass;
+pub use scheduler::{get_queue, Scheduler, SchedulerOptions};
+
+mod pipeline;
+pub use pipeline::ProcessOptions;
+pub(self) use pipeline::{encode_png, process_image_bytes, ProcessError};
+
+mod uploads;
+pub(self) use uploads::{get_uploads, Upload, Uploads};
+
 mod single_file;
 pub use single_file::run as run_single_file;
 
 mod endpoint;
 pub use endpoint::process_image;
+pub(self) use endpoint::process_options_from_query;
+
+mod batch;
+pub use batch::process_batch;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ts_rs::TS;
+
+    /// Write the TypeScript definitions of the queue to `client/src/bindings`, the committed files
+    /// have to be the same afterwards
+    #[test]
+    fn export_bindings() {
+        JobClass::export().unwrap();
+        scheduler::JobStatus::export().unwrap();
+        scheduler::QueueStatus::export().unwrap();
+    }
+}
@@ -0,0 +1,587 @@
+use crate::users::{
+    exceeded_quota, record_audit, record_usage, reserve_request, AuditEntry, AuditEvent,
+    AuthenticatedUser, ClientInfo, QuotaOptions, Usage, UserDb,
+};
+
+use super::{
+    encode_png, get_uploads, process_image_bytes, process_options_from_query, ComparisonOptions,
+    JobClass, LightOptions, LightReport, MirnetModel, ProcessError, ProcessOptions, RawOptions,
+    RegionOptions, Scheduler, Upload, Uploads,
+};
+use actix_multipart::Multipart;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::web;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info_span, instrument, warn, Instrument};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Maximum number of images processed in a single batch
+const MAX_BATCH_FILES: usize = 500;
+
+/// Limits of the archives uploaded as a batch
+struct ArchiveLimits {
+    /// Maximum number of entries, directories included
+    files: usize,
+    /// Maximum uncompressed size of a single file
+    entry_size: u64,
+    /// Maximum uncompressed size of all the files
+    total_size: u64,
+}
+
+const ARCHIVE_LIMITS: ArchiveLimits = ArchiveLimits {
+    files: MAX_BATCH_FILES,
+    entry_size: 200 * 1024 * 1024,
+    total_size: 1024 * 1024 * 1024,
+};
+
+/// Extensions of the RAW camera files, most can't be recognized from their content
+const RAW_EXTENSIONS: [&str; 24] = [
+    "3fr", "arw", "cr2", "crw", "dcr", "dcs", "dng", "erf", "iiq", "kdc", "mef", "mos", "mrw",
+    "nef", "nrw", "orf", "pef", "raf", "raw", "rw2", "rwl", "sr2", "srf", "srw",
+];
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BatchStatus {
+    Ok,
+    Error,
+    /// Not processed because the user reached one of their quotas
+    Skipped,
+}
+
+/// Result of one of the images of the batch, as written in `manifest.json`
+#[derive(Serialize)]
+struct ManifestEntry {
+    input: String,
+    output: Option<String>,
+    status: BatchStatus,
+    error: Option<String>,
+    light: Option<LightReport>,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    processed: usize,
+    failed: usize,
+    skipped: usize,
+    files: Vec<ManifestEntry>,
+}
+
+fn is_zip(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"PK\x03\x04")
+}
+
+/// The images of the batch, either all the `input` fields or the content of a single ZIP archive
+fn batch_files(inputs: Vec<Upload>) -> Result<Vec<(String, Vec<u8>)>, ProcessError> {
+    let files = if inputs.len() == 1 && is_zip(&inputs[0].bytes) {
+        extract_archive(&inputs[0].bytes, &ARCHIVE_LIMITS)?
+    } else {
+        inputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, upload)| {
+                // Only keep the last component, the name ends up in the output archive
+                let name = upload
+                    .filename
+                    .as_deref()
+                    .and_then(|name| Path::new(name).file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| format!("input-{}", i + 1));
+                (name, upload.bytes)
+            })
+            .collect()
+    };
+
+    if files.is_empty() {
+        return Err(ProcessError::ErrorBadRequest("No file to process".into()));
+    }
+    if files.len() > MAX_BATCH_FILES {
+        return Err(ProcessError::ErrorBadRequest(format!(
+            "Too many files in batch ({}), the maximum is {}",
+            files.len(),
+            MAX_BATCH_FILES
+        )));
+    }
+
+    Ok(files)
+}
+
+/// Whether an archive entry should be processed: an image recognized from its content or a RAW
+/// camera file. Other entries (text files, resource forks, thumbnails databases, ...) are skipped
+fn is_image_entry(name: &str, content: &[u8]) -> bool {
+    let raw_extension = Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .map_or(false, |extension| {
+            RAW_EXTENSIONS.contains(&extension.as_str())
+        });
+
+    raw_extension || image::guess_format(content).is_ok()
+}
+
+/// Extract the images of the archive, refusing archives with too many entries or whose content
+/// decompresses to more than the size limits, whatever the sizes declared in the archive
+fn extract_archive(
+    bytes: &[u8],
+    limits: &ArchiveLimits,
+) -> Result<Vec<(String, Vec<u8>)>, ProcessError> {
+    let invalid = |e: zip::result::ZipError| {
+        ProcessError::ErrorBadRequest(format!("Invalid archive: {:?}", e))
+    };
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(invalid)?;
+    if archive.len() > limits.files {
+        return Err(ProcessError::ErrorBadRequest(format!(
+            "Too many files in archive ({}), the maximum is {}",
+            archive.len(),
+            limits.files
+        )));
+    }
+
+    let mut files = Vec::new();
+    let mut total_size = 0;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(invalid)?;
+        if file.is_dir() || file.name().starts_with("__MACOSX/") {
+            continue;
+        }
+        // Refuse absolute paths and paths going up, the names are reused in the output archive
+        let name = match file.enclosed_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(ProcessError::ErrorBadRequest(format!(
+                    "Invalid file name in archive: {}",
+                    file.name()
+                )))
+            }
+        };
+
+        // The declared size can't be trusted, read one byte past the limit to detect overflows
+        let limit = limits.entry_size.min(limits.total_size - total_size);
+        let mut content = Vec::new();
+        file.take(limit + 1)
+            .read_to_end(&mut content)
+            .map_err(|e| ProcessError::ErrorBadRequest(format!("Invalid archive: {:?}", e)))?;
+        if content.len() as u64 > limits.entry_size {
+            return Err(ProcessError::ErrorBadRequest(format!(
+                "File {} in archive is too big",
+                name
+            )));
+        }
+        if content.len() as u64 > limit {
+            return Err(ProcessError::ErrorBadRequest(
+                "Archive content is too big".into(),
+            ));
+        }
+
+        total_size += content.len() as u64;
+        if is_image_entry(&name, &content) {
+            files.push((name, content));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Name of the result in the output archive: the file name of the input without its directories
+/// and with a png extension, made unique
+fn output_name(input: &str, used: &mut HashSet<String>) -> String {
+    let stem = Path::new(input)
+        .file_stem()
+        .map_or_else(|| "output".into(), |stem| stem.to_string_lossy());
+
+    let mut name = format!("{}.png", stem);
+    let mut index = 2;
+    while used.contains(&name) || name == "manifest.json" {
+        name = format!("{}-{}.png", stem, index);
+        index += 1;
+    }
+    used.insert(name.clone());
+
+    name
+}
+
+/// What is kept from one image of the batch to the next
+struct BatchState {
+    archive: ZipWriter<Cursor<Vec<u8>>>,
+    model: Option<MirnetModel>,
+    used_names: HashSet<String>,
+}
+
+fn archive_error(error: impl Debug) -> ProcessError {
+    ProcessError::ErrorInternalServerError(format!("Can't write archive: {:?}", error))
+}
+
+/// Process an image of the batch and add the result to the archive. Returns its entry in the
+/// manifest with the size of the image
+fn process_batch_file_blocking(
+    state: &mut BatchState,
+    input: String,
+    bytes: &[u8],
+    mask: Option<&[u8]>,
+    options: &ProcessOptions,
+) -> Result<(ManifestEntry, Usage), ProcessError> {
+    let mut usage = Usage::default();
+    let processed = process_image_bytes(bytes, mask, options, &mut state.model);
+    let result = processed.and_then(|processed| {
+        usage.megapixels += processed.megapixels;
+        let png = encode_png(&processed.image)?;
+        Ok((png, processed.light_report))
+    });
+
+    match result {
+        Ok((png, light_report)) => {
+            let output = output_name(&input, &mut state.used_names);
+            state
+                .archive
+                .start_file(output.as_str(), FileOptions::default())
+                .map_err(archive_error)?;
+            state.archive.write_all(&png).map_err(archive_error)?;
+
+            let entry = ManifestEntry {
+                input,
+                output: Some(output),
+                status: BatchStatus::Ok,
+                error: None,
+                light: Some(light_report),
+            };
+            Ok((entry, usage))
+        }
+        Err(error) => {
+            warn!(%input, ?error, "Failed to process batch file");
+            let entry = ManifestEntry {
+                input,
+                output: None,
+                status: BatchStatus::Error,
+                error: Some(error.to_string()),
+                light: None,
+            };
+            Ok((entry, usage))
+        }
+    }
+}
+
+/// Process the images one at a time, each one waits for the turn of the user in the scheduler so
+/// that the other users aren't stuck behind the batch. The usage of each image is recorded when
+/// it's done, and the quotas are checked again before the next one: once one of them is reached
+/// the remaining images are skipped.
+///
+/// When the client disconnects the request future is dropped, which stops the batch at the next
+/// image and takes its job out of the queue if it was waiting for a slot
+async fn process_batch_files(
+    uploads: Uploads,
+    options: ProcessOptions,
+    scheduler: Arc<Scheduler>,
+    user_db: &UserDb,
+    quotas: &QuotaOptions,
+    user_id: i32,
+) -> Result<Vec<u8>, ProcessError> {
+    let blocking_error = |e: BlockingError| {
+        ProcessError::ErrorInternalServerError(format!("Processing was cancelled: {:?}", e))
+    };
+
+    let mask = Arc::new(uploads.mask.map(|m| m.bytes));
+    let inputs = uploads.inputs;
+    let files = web::block(move || batch_files(inputs))
+        .await
+        .map_err(blocking_error)??;
+    let options = Arc::new(options);
+
+    let mut state = BatchState {
+        archive: ZipWriter::new(Cursor::new(Vec::new())),
+        model: None,
+        used_names: HashSet::new(),
+    };
+    let mut entries = Vec::with_capacity(files.len());
+    let mut exceeded = None;
+
+    for (input, bytes) in files {
+        if exceeded.is_none() {
+            // The request of the batch itself was reserved before it started
+            exceeded = exceeded_quota(user_db, quotas, user_id, 1)
+                .await
+                .map_err(|e| {
+                    ProcessError::ErrorInternalServerError(format!("Can't check quota: {:?}", e))
+                })?
+                .map(|exceeded| exceeded.message);
+        }
+        if let Some(message) = &exceeded {
+            entries.push(ManifestEntry {
+                input,
+                output: None,
+                status: BatchStatus::Skipped,
+                error: Some(message.clone()),
+                light: None,
+            });
+            continue;
+        }
+
+        let mask = mask.clone();
+        let options = options.clone();
+        // Only take a blocking thread once the image has a slot
+        let slot = scheduler.acquire(user_id, JobClass::Batch).await;
+        let (returned_state, result) = web::block(move || {
+            let result =
+                process_batch_file_blocking(&mut state, input, &bytes, mask.as_deref(), &options);
+            let result = result.map(|(entry, mut usage)| {
+                usage.compute_seconds += slot.elapsed().as_secs_f64();
+                (entry, usage)
+            });
+            // The slot is only freed once the job is done, even if the client left meanwhile
+            drop(slot);
+            (state, result)
+        })
+        .await
+        .map_err(blocking_error)?;
+        state = returned_state;
+
+        let (entry, usage) = result?;
+        record_usage(user_db, user_id, usage).await;
+        entries.push(entry);
+    }
+
+    let count = |status| entries.iter().filter(|e| e.status == status).count();
+    let manifest = Manifest {
+        processed: count(BatchStatus::Ok),
+        failed: count(BatchStatus::Error),
+        skipped: count(BatchStatus::Skipped),
+        files: entries,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| {
+        ProcessError::ErrorInternalServerError(format!("Can't write manifest: {:?}", e))
+    })?;
+    let mut archive = state.archive;
+    archive
+        .start_file("manifest.json", FileOptions::default())
+        .map_err(archive_error)?;
+    archive.write_all(&manifest).map_err(archive_error)?;
+
+    Ok(archive.finish().map_err(archive_error)?.into_inner())
+}
+
+/// Process several images sent as multiple `input` fields or as a single ZIP archive, the
+/// response is a ZIP archive of the results with a `manifest.json` describing each file
+#[instrument(
+    skip(payload, auth, user_db, quotas, scheduler),
+    fields(user_id = auth.user.id)
+)]
+pub async fn process_batch(
+    payload: Multipart,
+    raw_options: web::Query<RawOptions>,
+    region_options: web::Query<RegionOptions>,
+    light_options: web::Query<LightOptions>,
+    comparison_options: web::Query<ComparisonOptions>,
+    auth: AuthenticatedUser,
+    client: ClientInfo,
+    user_db: web::Data<UserDb>,
+    quotas: web::Data<QuotaOptions>,
+    scheduler: web::Data<Scheduler>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // A batch counts as a single request, its size and duration are what the other quotas limit
+    if let Err(response) = reserve_request(&user_db, &quotas, auth.user.id).await {
+        return Ok(response);
+    }
+
+    let uploads = get_uploads(payload).await?;
+    let files = uploads.inputs.len();
+    let options = process_options_from_query(
+        raw_options,
+        region_options,
+        light_options,
+        comparison_options,
+    );
+    let result = process_batch_files(
+        uploads,
+        options,
+        scheduler.into_inner(),
+        &user_db,
+        &quotas,
+        auth.user.id,
+    )
+    .instrument(info_span!("Processing batch request"))
+    .await;
+
+    let failure = result.as_ref().err().map(|err| err.to_string());
+    let details = match &failure {
+        Some(failure) => failure.clone(),
+        None => format!("{} uploaded files", files),
+    };
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_id: Some(auth.user.id),
+            actor_login: Some(auth.user.login.clone()),
+            details: Some(details),
+            ..AuditEntry::new(AuditEvent::ProcessBatch, &client, failure.as_deref())
+        },
+    )
+    .await;
+
+    let output_bytes = result?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/zip")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"enhanced.zip\"",
+        ))
+        .body(output_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough of a PNG file for its format to be recognized
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    const LIMITS: ArchiveLimits = ArchiveLimits {
+        files: 4,
+        entry_size: 100,
+        total_size: 150,
+    };
+
+    fn image_content(size: usize) -> Vec<u8> {
+        let mut content = PNG_HEADER.to_vec();
+        content.resize(size, 0);
+        content
+    }
+
+    fn archive(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            archive.start_file(*name, FileOptions::default()).unwrap();
+            archive.write_all(content).unwrap();
+        }
+        archive.finish().unwrap().into_inner()
+    }
+
+    fn bad_request(result: Result<Vec<(String, Vec<u8>)>, ProcessError>) -> String {
+        match result {
+            Err(ProcessError::ErrorBadRequest(message)) => message,
+            Err(error) => panic!("Unexpected error {:?}", error),
+            Ok(files) => panic!("Unexpected success with {} files", files.len()),
+        }
+    }
+
+    fn names(files: &[(String, Vec<u8>)]) -> Vec<&str> {
+        files.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn extract_images() {
+        let bytes = archive(&[
+            ("a.png", image_content(20)),
+            ("photos/b.png", image_content(30)),
+            ("c.NEF", vec![1; 10]),
+        ]);
+
+        let files = extract_archive(&bytes, &LIMITS).unwrap();
+
+        assert_eq!(names(&files), ["a.png", "photos/b.png", "c.NEF"]);
+        assert_eq!(files[1].1, image_content(30));
+    }
+
+    #[test]
+    fn skip_other_entries() {
+        let bytes = archive(&[
+            ("notes.txt", b"not an image".to_vec()),
+            (".DS_Store", vec![0; 10]),
+            ("__MACOSX/._a.png", image_content(10)),
+            ("a.png", image_content(10)),
+        ]);
+
+        let files = extract_archive(&bytes, &LIMITS).unwrap();
+
+        assert_eq!(names(&files), ["a.png"]);
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let entries: Vec<_> = ["a.png", "b.png", "c.png", "d.png", "e.png"]
+            .iter()
+            .map(|name| (*name, image_content(10)))
+            .collect();
+
+        let message = bad_request(extract_archive(&archive(&entries), &LIMITS));
+        assert!(
+            message.starts_with("Too many files in archive"),
+            "{}",
+            message
+        );
+
+        assert!(extract_archive(&archive(&entries[..4]), &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn entry_too_big() {
+        let bytes = archive(&[("a.png", image_content(101))]);
+        let message = bad_request(extract_archive(&bytes, &LIMITS));
+        assert_eq!(message, "File a.png in archive is too big");
+
+        let bytes = archive(&[("a.png", image_content(100))]);
+        assert!(extract_archive(&bytes, &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn archive_too_big() {
+        let bytes = archive(&[
+            ("a.png", image_content(80)),
+            ("b.png", image_content(60)),
+            ("c.png", image_content(20)),
+        ]);
+        let message = bad_request(extract_archive(&bytes, &LIMITS));
+        assert_eq!(message, "Archive content is too big");
+
+        // Skipped entries count too, they are decompressed to be recognized
+        let bytes = archive(&[("notes.txt", vec![b'a'; 80]), ("b.png", image_content(80))]);
+        let message = bad_request(extract_archive(&bytes, &LIMITS));
+        assert_eq!(message, "Archive content is too big");
+    }
+
+    #[test]
+    fn names_outside_of_archive() {
+        for name in ["../a.png", "photos/../../a.png", "/tmp/a.png"] {
+            let bytes = archive(&[("b.png", image_content(10)), (name, image_content(10))]);
+            let message = bad_request(extract_archive(&bytes, &LIMITS));
+            assert_eq!(message, format!("Invalid file name in archive: {}", name));
+        }
+    }
+
+    #[test]
+    fn uploaded_file_names() {
+        let upload = |filename: Option<&str>| Upload {
+            filename: filename.map(String::from),
+            bytes: image_content(10),
+        };
+        let files = batch_files(vec![
+            upload(Some("../../a.png")),
+            upload(None),
+            upload(Some("/tmp/photos/b.png")),
+        ])
+        .unwrap();
+
+        assert_eq!(names(&files), ["a.png", "input-2", "b.png"]);
+    }
+
+    #[test]
+    fn output_names() {
+        let mut used = HashSet::new();
+
+        assert_eq!(output_name("a.jpg", &mut used), "a.png");
+        assert_eq!(output_name("photos/a.NEF", &mut used), "a-2.png");
+        assert_eq!(output_name("other/a.png", &mut used), "a-3.png");
+        assert_eq!(output_name("archive.tar.gz", &mut used), "archive.tar.png");
+        assert_eq!(output_name("photos/b", &mut used), "b.png");
+        assert_eq!(output_name("b-2.png", &mut used), "b-2.png");
+        assert_eq!(output_name("b.jpg", &mut used), "b-3.png");
+        assert_eq!(output_name("", &mut used), "output.png");
+    }
+}
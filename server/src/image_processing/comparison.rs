@@ -0,0 +1,213 @@
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+/// Color stops of the difference heatmap, from no difference to the largest one
+const HEATMAP: [[f32; 3]; 5] = [
+    [0.0, 0.0, 0.0],
+    [40.0, 11.0, 84.0],
+    [188.0, 55.0, 84.0],
+    [249.0, 142.0, 9.0],
+    [252.0, 255.0, 164.0],
+];
+
+/// How the original and the enhanced images are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompareLayout {
+    /// Original on the left, enhanced on the right
+    SideBySide,
+    /// Original on the top, enhanced on the bottom
+    TopBottom,
+    /// Original on the top-left half, enhanced on the bottom-right half
+    Diagonal,
+    /// Heatmap of the per-pixel difference
+    Difference,
+}
+
+impl FromStr for CompareLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "side-by-side" => Ok(CompareLayout::SideBySide),
+            "top-bottom" => Ok(CompareLayout::TopBottom),
+            "diagonal" => Ok(CompareLayout::Diagonal),
+            "difference" => Ok(CompareLayout::Difference),
+            _ => Err(format!(
+                "invalid comparison layout '{}', expected 'side-by-side', 'top-bottom', 'diagonal' or 'difference'",
+                s
+            )),
+        }
+    }
+}
+
+/// Options to return a before/after comparison instead of the enhanced image
+#[derive(Debug, Clone, Default, Deserialize, StructOpt)]
+#[serde(default)]
+pub struct ComparisonOptions {
+    /// Output a comparison with the original: "side-by-side", "top-bottom", "diagonal" or "difference"
+    #[structopt(long)]
+    pub compare: Option<CompareLayout>,
+}
+
+/// Render a composite image of the original and the enhanced one
+pub fn render_comparison(
+    original: &DynamicImage,
+    enhanced: &RgbImage,
+    layout: CompareLayout,
+) -> RgbImage {
+    let (width, height) = original.dimensions();
+    let original = original.to_rgb8();
+    let resized;
+    let enhanced = if enhanced.dimensions() == (width, height) {
+        enhanced
+    } else {
+        resized = image::imageops::resize(enhanced, width, height, FilterType::Triangle);
+        &resized
+    };
+
+    match layout {
+        CompareLayout::SideBySide => RgbImage::from_fn(width * 2, height, |x, y| {
+            if x < width {
+                *original.get_pixel(x, y)
+            } else {
+                *enhanced.get_pixel(x - width, y)
+            }
+        }),
+        CompareLayout::TopBottom => RgbImage::from_fn(width, height * 2, |x, y| {
+            if y < height {
+                *original.get_pixel(x, y)
+            } else {
+                *enhanced.get_pixel(x, y - height)
+            }
+        }),
+        CompareLayout::Diagonal => RgbImage::from_fn(width, height, |x, y| {
+            let position = x as f32 / width as f32 + y as f32 / height as f32;
+            if position < 1.0 {
+                *original.get_pixel(x, y)
+            } else {
+                *enhanced.get_pixel(x, y)
+            }
+        }),
+        CompareLayout::Difference => difference_heatmap(&original, enhanced),
+    }
+}
+
+/// Heatmap of the mean absolute channel difference, normalized on the largest difference
+fn difference_heatmap(original: &RgbImage, enhanced: &RgbImage) -> RgbImage {
+    let differences: Vec<f32> = original
+        .pixels()
+        .zip(enhanced.pixels())
+        .map(|(a, b)| {
+            a.0.iter()
+                .zip(b.0.iter())
+                .map(|(a, b)| (*a as f32 - *b as f32).abs())
+                .sum::<f32>()
+                / 3.0
+        })
+        .collect();
+    let max = differences.iter().cloned().fold(0.0, f32::max).max(1.0);
+
+    let mut heatmap = RgbImage::new(original.width(), original.height());
+    for (pixel, difference) in heatmap.pixels_mut().zip(differences) {
+        *pixel = heatmap_color(difference / max);
+    }
+
+    heatmap
+}
+
+/// Color of a value between 0 and 1 interpolated between the heatmap stops
+fn heatmap_color(value: f32) -> Rgb<u8> {
+    let position = value.clamp(0.0, 1.0) * (HEATMAP.len() - 1) as f32;
+    let index = (position.floor() as usize).min(HEATMAP.len() - 2);
+    let t = position - index as f32;
+    let (from, to) = (HEATMAP[index], HEATMAP[index + 1]);
+
+    Rgb([
+        (from[0] + (to[0] - from[0]) * t).round() as u8,
+        (from[1] + (to[1] - from[1]) * t).round() as u8,
+        (from[2] + (to[2] - from[2]) * t).round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image where each pixel encodes its position and a tag telling the images apart
+    fn tagged_image(width: u32, height: u32, tag: u8) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, tag]))
+    }
+
+    #[test]
+    fn side_by_side() {
+        let original = DynamicImage::ImageRgb8(tagged_image(6, 4, 1));
+        let enhanced = tagged_image(6, 4, 2);
+
+        let output = render_comparison(&original, &enhanced, CompareLayout::SideBySide);
+
+        assert_eq!(output.dimensions(), (12, 4));
+        for (x, y, pixel) in output.enumerate_pixels() {
+            let expected = if x < 6 {
+                Rgb([x as u8, y as u8, 1])
+            } else {
+                Rgb([x as u8 - 6, y as u8, 2])
+            };
+            assert_eq!(*pixel, expected, "at {}x{}", x, y);
+        }
+    }
+
+    #[test]
+    fn top_bottom() {
+        let original = DynamicImage::ImageRgb8(tagged_image(6, 4, 1));
+        let enhanced = tagged_image(6, 4, 2);
+
+        let output = render_comparison(&original, &enhanced, CompareLayout::TopBottom);
+
+        assert_eq!(output.dimensions(), (6, 8));
+        assert_eq!(*output.get_pixel(5, 3), Rgb([5, 3, 1]));
+        assert_eq!(*output.get_pixel(5, 4), Rgb([5, 0, 2]));
+    }
+
+    #[test]
+    fn diagonal() {
+        let original = DynamicImage::ImageRgb8(tagged_image(6, 4, 1));
+        let enhanced = tagged_image(6, 4, 2);
+
+        let output = render_comparison(&original, &enhanced, CompareLayout::Diagonal);
+
+        assert_eq!(output.dimensions(), (6, 4));
+        assert_eq!(output.get_pixel(0, 0).0[2], 1);
+        assert_eq!(output.get_pixel(5, 3).0[2], 2);
+    }
+
+    #[test]
+    fn enhanced_resized_to_original() {
+        let original = DynamicImage::ImageRgb8(RgbImage::from_pixel(6, 4, Rgb([0; 3])));
+        let enhanced = RgbImage::from_pixel(12, 10, Rgb([200; 3]));
+
+        let output = render_comparison(&original, &enhanced, CompareLayout::SideBySide);
+
+        assert_eq!(output.dimensions(), (12, 4));
+        assert_eq!(*output.get_pixel(5, 3), Rgb([0; 3]));
+        assert_eq!(*output.get_pixel(6, 0), Rgb([200; 3]));
+        assert_eq!(*output.get_pixel(11, 3), Rgb([200; 3]));
+    }
+
+    #[test]
+    fn difference() {
+        let original = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([100; 3])));
+        let mut enhanced = RgbImage::from_pixel(4, 4, Rgb([100; 3]));
+        enhanced.put_pixel(1, 2, Rgb([200; 3]));
+
+        let output = render_comparison(&original, &enhanced, CompareLayout::Difference);
+
+        assert_eq!(output.dimensions(), (4, 4));
+        assert_eq!(*output.get_pixel(0, 0), Rgb([0, 0, 0]));
+        assert_eq!(*output.get_pixel(1, 2), Rgb([252, 255, 164]));
+    }
+}
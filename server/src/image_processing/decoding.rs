@@ -0,0 +1,35 @@
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageError, ImageFormat};
+use std::io::Cursor;
+use thiserror::Error;
+
+use super::raw::{decode_raw, RawError, RawOptions};
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("unable to guess format: {0}")]
+    UnknownFormat(#[from] std::io::Error),
+
+    #[error("invalid image: {0}")]
+    InvalidImage(#[from] ImageError),
+
+    #[error("invalid raw image: {0}")]
+    InvalidRaw(#[from] RawError),
+}
+
+/// Decode an image in any format supported by the `image` crate or a RAW camera file.
+///
+/// Most RAW formats are TIFF containers whose first image is a small preview, so files that look
+/// like TIFF or that aren't recognized go through the RAW decoder first.
+pub fn decode_image(bytes: &[u8], raw_options: &RawOptions) -> Result<DynamicImage, DecodeError> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+
+    match reader.format() {
+        Some(ImageFormat::Tiff) => match decode_raw(bytes, raw_options) {
+            Ok(image) => Ok(image),
+            Err(_) => Ok(reader.decode()?),
+        },
+        Some(_) => Ok(reader.decode()?),
+        None => Ok(decode_raw(bytes, raw_options)?),
+    }
+}
@@ -1,112 +1,123 @@
-use crate::authenticate;
-use crate::users::UserDb;
-
-use super::{image_to_tensor, tensor_to_image, MirnetModel};
-use actix_identity::Identity;
+use crate::users::{
+    record_audit, record_usage, reserve_request, AuditEntry, AuditEvent, AuthenticatedUser,
+    ClientInfo, QuotaOptions, Usage, UserDb,
+};
+
+use super::{
+    encode_png, get_uploads, process_image_bytes, ComparisonOptions, JobClass, LightOptions,
+    LightReport, ProcessError, ProcessOptions, RawOptions, RegionOptions, Scheduler, Uploads,
+};
 use actix_multipart::Multipart;
 use actix_web::http::StatusCode;
 use actix_web::web;
 use actix_web::HttpResponse;
-use futures::{StreamExt, TryStreamExt};
-use image::io::Reader as ImageReader;
-use image::png::PngEncoder;
-use image::{EncodableLayout, ImageEncoder};
-use std::io::{Cursor, Write};
-use thiserror::Error;
 use tracing::info_span;
 use tracing::instrument;
-use tracing::trace;
-
-async fn get_input_bytes(mut payload: Multipart) -> Result<Vec<u8>, actix_web::Error> {
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        if field.name() == "input" {
-            let mut bytes: Vec<u8> = Vec::new();
-
-            while let Some(chunk) = field.next().await {
-                let data = chunk?;
-                trace!("Writing {} bytes", data.len());
-                bytes.write_all(&data)?;
-            }
-
-            trace!("Found input with {} bytes", bytes.len());
-
-            return Ok(bytes);
-        }
-    }
-
-    Err(actix_web::error::ErrorBadRequest("Field not found"))
-}
-
-#[derive(Error, Debug)]
-pub enum ProcessError {
-    #[error("bad request: {0}")]
-    ErrorBadRequest(String),
-
-    #[error("internal server error: {0}")]
-    ErrorInternalServerError(String),
-}
-
-impl From<ProcessError> for actix_web::Error {
-    fn from(process_error: ProcessError) -> Self {
-        match process_error {
-            ProcessError::ErrorBadRequest(m) => actix_web::error::ErrorBadRequest(m),
-            ProcessError::ErrorInternalServerError(m) => {
-                actix_web::error::ErrorInternalServerError(m)
-            }
-        }
+use tracing::Instrument;
+
+/// Build the processing options from the query string, each part is parsed separately as
+/// flattened structures don't work with non-string query values
+pub fn process_options_from_query(
+    raw: web::Query<RawOptions>,
+    region: web::Query<RegionOptions>,
+    light: web::Query<LightOptions>,
+    comparison: web::Query<ComparisonOptions>,
+) -> ProcessOptions {
+    ProcessOptions {
+        raw: raw.into_inner(),
+        region: region.into_inner(),
+        light: light.into_inner(),
+        comparison: comparison.into_inner(),
     }
 }
 
-fn process_image_blocking(input_bytes: Vec<u8>) -> Result<Vec<u8>, ProcessError> {
+/// Process the image and encode the result, the size of the image is added to `usage`
+fn process_image_blocking(
+    uploads: Uploads,
+    options: ProcessOptions,
+    usage: &mut Usage,
+) -> Result<(Vec<u8>, LightReport), ProcessError> {
     let span = info_span!("Processing image request");
-    let _ = span.enter();
-
-    let input_image = ImageReader::new(Cursor::new(input_bytes))
-        .with_guessed_format()
-        .map_err(|e| ProcessError::ErrorBadRequest(format!("Unable to guess format: {:?}", e)))?
-        .decode()
-        .map_err(|e| ProcessError::ErrorBadRequest(format!("Invalid image: {:?}", e)))?;
-
-    let input_tensor = image_to_tensor(&input_image);
-    drop(input_image);
+    let _entered = span.enter();
 
-    let model = MirnetModel::new("model").map_err(|e| {
-        ProcessError::ErrorInternalServerError(format!("Can't initialize model: {:?}", e))
-    })?;
+    let input = &uploads.inputs[0].bytes;
+    let mask = uploads.mask.as_ref().map(|m| m.bytes.as_slice());
+    let processed = process_image_bytes(input, mask, &options, &mut None)?;
+    usage.megapixels += processed.megapixels;
+    drop(uploads);
 
-    let output_tensor = model.run(&input_tensor).map_err(|e| {
-        ProcessError::ErrorInternalServerError(format!("Error running model: {:?}", e))
-    })?;
-    let output_image = tensor_to_image(&output_tensor).map_err(|e| {
-        ProcessError::ErrorInternalServerError(format!("Can't convert output: {:?}", e))
-    })?;
+    let output_bytes = encode_png(&processed.image)?;
 
-    let mut output_bytes = Vec::<u8>::new();
-    PngEncoder::new(&mut output_bytes)
-        .write_image(
-            output_image.as_bytes(),
-            output_image.width(),
-            output_image.height(),
-            image::ColorType::Rgb8,
-        )
-        .map_err(|e| {
-            ProcessError::ErrorInternalServerError(format!("Can't encode output: {:?}", e))
-        })?;
-
-    Ok(output_bytes)
+    Ok((output_bytes, processed.light_report))
 }
 
-#[instrument(skip(payload, id, user_db))]
+#[instrument(
+    skip(payload, auth, user_db, quotas, scheduler),
+    fields(user_id = auth.user.id)
+)]
 pub async fn process_image(
     payload: Multipart,
-    id: Identity,
+    raw_options: web::Query<RawOptions>,
+    region_options: web::Query<RegionOptions>,
+    light_options: web::Query<LightOptions>,
+    comparison_options: web::Query<ComparisonOptions>,
+    auth: AuthenticatedUser,
+    client: ClientInfo,
     user_db: web::Data<UserDb>,
+    quotas: web::Data<QuotaOptions>,
+    scheduler: web::Data<Scheduler>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    authenticate!(&id, &user_db);
-    let input_bytes = get_input_bytes(payload).await?;
-    let output_bytes = web::block(|| process_image_blocking(input_bytes)).await??;
+    if let Err(response) = reserve_request(&user_db, &quotas, auth.user.id).await {
+        return Ok(response);
+    }
+
+    let uploads = get_uploads(payload).await?;
+    let options = process_options_from_query(
+        raw_options,
+        region_options,
+        light_options,
+        comparison_options,
+    );
+    let user_id = auth.user.id;
+    // The wait for the turn of the user doesn't hold a thread, the blocking pool is only used
+    // once the job has a slot
+    let slot = scheduler
+        .into_inner()
+        .acquire(user_id, JobClass::Interactive)
+        .instrument(info_span!("Waiting for a processing slot"))
+        .await;
+    let (result, usage) = web::block(move || {
+        let mut usage = Usage::default();
+        let result = process_image_blocking(uploads, options, &mut usage);
+        usage.compute_seconds += slot.elapsed().as_secs_f64();
+        // The slot is only freed once the job is done, even if the client left meanwhile
+        drop(slot);
+        (result, usage)
+    })
+    .await?;
+
+    // The request itself was counted before processing, failed requests count too as the time
+    // spent on them is just as lost
+    record_usage(&user_db, user_id, usage).await;
+
+    let failure = result.as_ref().err().map(|err| err.to_string());
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_id: Some(auth.user.id),
+            actor_login: Some(auth.user.login.clone()),
+            ..AuditEntry::new(AuditEvent::ProcessImage, &client, failure.as_deref())
+        },
+    )
+    .await;
+
+    let (output_bytes, light_report) = result?;
+
+    let mut response = HttpResponse::build(StatusCode::OK);
+    response.content_type("image/png");
+    for header in light_report.headers() {
+        response.insert_header(header);
+    }
 
-    Ok(HttpResponse::build(StatusCode::OK)
-        .content_type("image/png")
-        .body(output_bytes))
+    Ok(response.body(output_bytes))
 }
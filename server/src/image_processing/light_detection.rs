@@ -0,0 +1,378 @@
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, RgbImage};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// Luminance under which a pixel is counted as dark
+const DARK_PIXEL_LUMINANCE: f32 = 0.1;
+
+/// How images that aren't detected as low-light are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightMode {
+    /// Enhance every image, without looking at its exposure
+    Always,
+    /// Return images that aren't low-light unchanged
+    Skip,
+    /// Only partially apply the enhancement to images that aren't low-light
+    Gentle,
+}
+
+impl FromStr for LightMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "always" => Ok(LightMode::Always),
+            "skip" => Ok(LightMode::Skip),
+            "gentle" => Ok(LightMode::Gentle),
+            _ => Err(format!(
+                "invalid light mode '{}', expected 'always', 'skip' or 'gentle'",
+                s
+            )),
+        }
+    }
+}
+
+/// Options of the low-light detection done before running the model
+#[derive(Debug, Clone, Deserialize, StructOpt)]
+#[serde(default)]
+pub struct LightOptions {
+    /// Handling of images that aren't low-light: "always", "skip" or "gentle"
+    #[structopt(long, default_value = "always")]
+    pub light_mode: LightMode,
+
+    /// Mean luminance (between 0 and 1) under which an image is considered low-light
+    #[structopt(long, default_value = "0.25")]
+    pub low_light_threshold: f32,
+
+    /// Strength (between 0 and 1) of the enhancement of images that aren't low-light in gentle mode
+    #[structopt(long, default_value = "0.3")]
+    pub gentle_strength: f32,
+}
+
+impl Default for LightOptions {
+    fn default() -> Self {
+        Self {
+            light_mode: LightMode::Always,
+            low_light_threshold: 0.25,
+            gentle_strength: 0.3,
+        }
+    }
+}
+
+/// Luminance statistics of an image, all values are between 0 and 1
+#[derive(Debug, Clone, Serialize)]
+pub struct LightAnalysis {
+    pub mean_luminance: f32,
+    pub median_luminance: f32,
+    pub p95_luminance: f32,
+    pub dark_fraction: f32,
+    pub low_light: bool,
+}
+
+/// What was done with the image following the analysis
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightDecision {
+    Enhanced,
+    Skipped,
+    Gentle,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LightReport {
+    pub decision: LightDecision,
+    #[serde(flatten)]
+    pub analysis: LightAnalysis,
+}
+
+impl LightReport {
+    /// Headers added to HTTP responses to describe the decision
+    pub fn headers(&self) -> [(&'static str, String); 5] {
+        [
+            (
+                "X-Light-Decision",
+                format!("{:?}", self.decision).to_lowercase(),
+            ),
+            (
+                "X-Luminance-Mean",
+                format!("{:.4}", self.analysis.mean_luminance),
+            ),
+            (
+                "X-Luminance-Median",
+                format!("{:.4}", self.analysis.median_luminance),
+            ),
+            (
+                "X-Luminance-P95",
+                format!("{:.4}", self.analysis.p95_luminance),
+            ),
+            (
+                "X-Dark-Fraction",
+                format!("{:.4}", self.analysis.dark_fraction),
+            ),
+        ]
+    }
+}
+
+impl std::fmt::Display for LightReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} ({}, mean {:.3}, median {:.3}, p95 {:.3}, dark {:.1}%)",
+            self.decision,
+            if self.analysis.low_light {
+                "low-light"
+            } else {
+                "well exposed"
+            },
+            self.analysis.mean_luminance,
+            self.analysis.median_luminance,
+            self.analysis.p95_luminance,
+            self.analysis.dark_fraction * 100.0
+        )
+    }
+}
+
+/// Compute the luminance histogram of the image (Rec. 709 weights on the encoded values) and
+/// classify it as low-light if the mean is under the threshold
+pub fn analyze_light(image: &DynamicImage, threshold: f32) -> LightAnalysis {
+    let mut histogram = [0u64; 256];
+    let mut sum = 0u64;
+    for (_, _, pixel) in image.pixels() {
+        let [r, g, b, _] = pixel.0;
+        let luminance = (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8;
+        histogram[luminance as usize] += 1;
+        sum += luminance as u64;
+    }
+
+    let count = (image.width() as u64 * image.height() as u64).max(1);
+    let percentile = |p: f64| {
+        let target = (count as f64 * p).ceil() as u64;
+        let mut cumulative = 0;
+        for (value, n) in histogram.iter().enumerate() {
+            cumulative += n;
+            if cumulative >= target {
+                return value as f32 / 255.0;
+            }
+        }
+        1.0
+    };
+    let dark_limit = (DARK_PIXEL_LUMINANCE * 255.0) as usize;
+    let dark: u64 = histogram[..dark_limit].iter().sum();
+
+    let mean_luminance = sum as f32 / count as f32 / 255.0;
+    LightAnalysis {
+        mean_luminance,
+        median_luminance: percentile(0.5),
+        p95_luminance: percentile(0.95),
+        dark_fraction: dark as f32 / count as f32,
+        low_light: mean_luminance < threshold,
+    }
+}
+
+/// Analyze the image and enhance it according to the light options
+pub fn enhance_if_needed<E>(
+    image: &DynamicImage,
+    options: &LightOptions,
+    enhance: impl FnOnce() -> Result<RgbImage, E>,
+) -> Result<(RgbImage, LightReport), E> {
+    let analysis = analyze_light(image, options.low_light_threshold);
+
+    let decision = match (analysis.low_light, options.light_mode) {
+        (true, _) | (false, LightMode::Always) => LightDecision::Enhanced,
+        (false, LightMode::Skip) => LightDecision::Skipped,
+        (false, LightMode::Gentle) => LightDecision::Gentle,
+    };
+
+    let output = match decision {
+        LightDecision::Enhanced => enhance()?,
+        LightDecision::Skipped => image.to_rgb8(),
+        LightDecision::Gentle => {
+            let strength = options.gentle_strength.clamp(0.0, 1.0);
+            let mut output = enhance()?;
+            // The pixels are blended one to one, a padded or cropped output would be shifted
+            if output.dimensions() != image.dimensions() {
+                output = image::imageops::resize(
+                    &output,
+                    image.width(),
+                    image.height(),
+                    FilterType::Triangle,
+                );
+            }
+            for ((_, _, original), pixel) in image.pixels().zip(output.pixels_mut()) {
+                for (value, original_value) in pixel.0.iter_mut().zip(original.0) {
+                    let original_value = original_value as f32;
+                    *value = (original_value + (*value as f32 - original_value) * strength).round()
+                        as u8;
+                }
+            }
+            output
+        }
+    };
+
+    Ok((output, LightReport { decision, analysis }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray_image(width: u32, height: u32, value: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, image::Rgb([value; 3])))
+    }
+
+    fn options(light_mode: LightMode, gentle_strength: f32) -> LightOptions {
+        LightOptions {
+            light_mode,
+            gentle_strength,
+            ..LightOptions::default()
+        }
+    }
+
+    /// Enhancement returning a white image of the given size, counting its calls
+    fn enhance_white(
+        width: u32,
+        height: u32,
+        calls: &mut u32,
+    ) -> impl FnOnce() -> Result<RgbImage, ()> + '_ {
+        move || {
+            *calls += 1;
+            Ok(RgbImage::from_pixel(width, height, image::Rgb([255; 3])))
+        }
+    }
+
+    #[test]
+    fn dark_image() {
+        let analysis = analyze_light(&gray_image(10, 10, 10), 0.25);
+
+        assert!(analysis.low_light);
+        assert_eq!(analysis.mean_luminance, 10.0 / 255.0);
+        assert_eq!(analysis.median_luminance, 10.0 / 255.0);
+        assert_eq!(analysis.dark_fraction, 1.0);
+    }
+
+    #[test]
+    fn bright_image() {
+        let analysis = analyze_light(&gray_image(10, 10, 200), 0.25);
+
+        assert!(!analysis.low_light);
+        assert_eq!(analysis.mean_luminance, 200.0 / 255.0);
+        assert_eq!(analysis.p95_luminance, 200.0 / 255.0);
+        assert_eq!(analysis.dark_fraction, 0.0);
+    }
+
+    #[test]
+    fn threshold_boundary() {
+        let image = gray_image(4, 4, 64);
+
+        // Low-light means strictly under the threshold
+        assert!(!analyze_light(&image, 64.0 / 255.0).low_light);
+        assert!(analyze_light(&image, 65.0 / 255.0).low_light);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut image = RgbImage::new(10, 10);
+        for (x, _, pixel) in image.enumerate_pixels_mut() {
+            if x >= 5 {
+                *pixel = image::Rgb([255; 3]);
+            }
+        }
+
+        let analysis = analyze_light(&DynamicImage::ImageRgb8(image), 0.25);
+
+        assert_eq!(analysis.mean_luminance, 0.5);
+        assert_eq!(analysis.median_luminance, 0.0);
+        assert_eq!(analysis.p95_luminance, 1.0);
+        assert_eq!(analysis.dark_fraction, 0.5);
+        assert!(!analysis.low_light);
+    }
+
+    #[test]
+    fn empty_image() {
+        let analysis = analyze_light(&gray_image(0, 0, 0), 0.25);
+
+        assert_eq!(analysis.mean_luminance, 0.0);
+        assert_eq!(analysis.dark_fraction, 0.0);
+    }
+
+    #[test]
+    fn low_light_always_enhanced() {
+        let image = gray_image(10, 8, 10);
+        for mode in [LightMode::Always, LightMode::Skip, LightMode::Gentle] {
+            let mut calls = 0;
+            let (output, report) = enhance_if_needed(
+                &image,
+                &options(mode, 0.5),
+                enhance_white(10, 8, &mut calls),
+            )
+            .unwrap();
+
+            assert_eq!(report.decision, LightDecision::Enhanced);
+            assert_eq!(output.get_pixel(0, 0).0, [255; 3]);
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn well_exposed_skipped() {
+        let image = gray_image(10, 8, 200);
+        let mut calls = 0;
+        let (output, report) = enhance_if_needed(
+            &image,
+            &options(LightMode::Skip, 0.5),
+            enhance_white(10, 8, &mut calls),
+        )
+        .unwrap();
+
+        assert_eq!(report.decision, LightDecision::Skipped);
+        assert_eq!(output, image.to_rgb8());
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn well_exposed_gentle() {
+        let image = gray_image(10, 8, 200);
+        let mut calls = 0;
+        let (output, report) = enhance_if_needed(
+            &image,
+            &options(LightMode::Gentle, 0.5),
+            enhance_white(10, 8, &mut calls),
+        )
+        .unwrap();
+
+        assert_eq!(report.decision, LightDecision::Gentle);
+        assert!(output.pixels().all(|pixel| pixel.0 == [228; 3]));
+        assert_eq!(calls, 1);
+
+        // The strength is clamped to a full enhancement
+        let mut calls = 0;
+        let (output, _) = enhance_if_needed(
+            &image,
+            &options(LightMode::Gentle, 2.0),
+            enhance_white(10, 8, &mut calls),
+        )
+        .unwrap();
+        assert!(output.pixels().all(|pixel| pixel.0 == [255; 3]));
+    }
+
+    #[test]
+    fn gentle_output_of_another_size() {
+        let image = gray_image(10, 8, 200);
+        for (width, height) in [(12, 10), (8, 8), (10, 6)] {
+            let mut calls = 0;
+            let (output, _) = enhance_if_needed(
+                &image,
+                &options(LightMode::Gentle, 0.5),
+                enhance_white(width, height, &mut calls),
+            )
+            .unwrap();
+
+            assert_eq!(output.dimensions(), (10, 8));
+            assert!(output.pixels().all(|pixel| pixel.0 == [228; 3]));
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Context;
 use anyhow::Result as AnyResult;
+use image::{DynamicImage, RgbImage};
 use std::path::Path;
 use tensorflow::Graph;
 use tensorflow::SavedModelBundle;
@@ -8,6 +9,8 @@ use tensorflow::SessionRunArgs;
 use tensorflow::Tensor;
 use tensorflow::DEFAULT_SERVING_SIGNATURE_DEF_KEY;
 
+use super::{image_to_tensor, tensor_to_image};
+
 pub struct MirnetModel {
     graph: Graph,
     bundle: SavedModelBundle,
@@ -51,4 +54,11 @@ impl MirnetModel {
         let output = args.fetch(token_output)?;
         Ok(output)
     }
+
+    /// Run the model on an image, the alpha layer of the input is ignored
+    pub fn enhance(&self, image: &DynamicImage) -> AnyResult<RgbImage> {
+        let input = image_to_tensor(image);
+        let output = self.run(&input)?;
+        Ok(tensor_to_image(&output)?)
+    }
 }
@@ -0,0 +1,140 @@
+use image::png::PngEncoder;
+use image::{DynamicImage, EncodableLayout, ImageEncoder, RgbImage};
+use structopt::StructOpt;
+use thiserror::Error;
+use tracing::info;
+
+use super::{
+    decode_image, enhance_if_needed, enhance_region, render_comparison, ComparisonOptions,
+    LightOptions, LightReport, MirnetModel, RawOptions, RegionError, RegionOptions, Selection,
+};
+
+#[derive(Error, Debug)]
+pub enum ProcessError {
+    #[error("bad request: {0}")]
+    ErrorBadRequest(String),
+
+    #[error("internal server error: {0}")]
+    ErrorInternalServerError(String),
+}
+
+impl From<RegionError> for ProcessError {
+    fn from(region_error: RegionError) -> Self {
+        match region_error {
+            RegionError::SizeMismatch { .. } => {
+                ProcessError::ErrorInternalServerError(format!("{}", region_error))
+            }
+            _ => ProcessError::ErrorBadRequest(format!("{}", region_error)),
+        }
+    }
+}
+
+impl From<ProcessError> for actix_web::Error {
+    fn from(process_error: ProcessError) -> Self {
+        match process_error {
+            ProcessError::ErrorBadRequest(m) => actix_web::error::ErrorBadRequest(m),
+            ProcessError::ErrorInternalServerError(m) => {
+                actix_web::error::ErrorInternalServerError(m)
+            }
+        }
+    }
+}
+
+/// All the options of the processing, shared by the HTTP API and the command line
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct ProcessOptions {
+    #[structopt(flatten)]
+    pub raw: RawOptions,
+
+    #[structopt(flatten)]
+    pub region: RegionOptions,
+
+    #[structopt(flatten)]
+    pub light: LightOptions,
+
+    #[structopt(flatten)]
+    pub comparison: ComparisonOptions,
+}
+
+pub struct ProcessedImage {
+    pub image: RgbImage,
+    pub light_report: LightReport,
+    /// Size of the input image, in millions of pixels
+    pub megapixels: f64,
+}
+
+/// Load the model the first time it's needed so that skipped images don't pay for it
+fn get_model(model: &mut Option<MirnetModel>) -> Result<&MirnetModel, ProcessError> {
+    if model.is_none() {
+        let loaded = MirnetModel::new("model").map_err(|e| {
+            ProcessError::ErrorInternalServerError(format!("Can't initialize model: {:?}", e))
+        })?;
+        *model = Some(loaded);
+    }
+
+    Ok(model.as_ref().expect("model was just loaded"))
+}
+
+/// Decode, analyze and enhance an image following the options.
+///
+/// `model` is loaded on first use and can be reused between calls.
+pub fn process_image_bytes(
+    input: &[u8],
+    mask: Option<&[u8]>,
+    options: &ProcessOptions,
+    model: &mut Option<MirnetModel>,
+) -> Result<ProcessedImage, ProcessError> {
+    let input_image = decode_image(input, &options.raw)
+        .map_err(|e| ProcessError::ErrorBadRequest(format!("{}", e)))?;
+    let mask_image = match mask {
+        Some(mask) => Some(image::load_from_memory(mask).map_err(|e| {
+            ProcessError::ErrorBadRequest(format!("Invalid mask: {:?}", e))
+        })?),
+        None => None,
+    };
+    let selection = Selection::new(options.region.region, mask_image)?;
+
+    let (output_image, light_report) = enhance_if_needed(&input_image, &options.light, || {
+        let model = get_model(model)?;
+
+        let enhance = |image: &DynamicImage| {
+            model.enhance(image).map_err(|e| {
+                ProcessError::ErrorInternalServerError(format!("Error running model: {:?}", e))
+            })
+        };
+        match &selection {
+            Some(selection) => {
+                enhance_region(&input_image, selection, options.region.feather, enhance)
+            }
+            None => enhance(&input_image),
+        }
+    })?;
+    info!(%light_report, "Light analysis");
+
+    let image = match options.comparison.compare {
+        Some(layout) => render_comparison(&input_image, &output_image, layout),
+        None => output_image,
+    };
+
+    Ok(ProcessedImage {
+        image,
+        light_report,
+        megapixels: f64::from(input_image.width()) * f64::from(input_image.height()) / 1e6,
+    })
+}
+
+pub fn encode_png(image: &RgbImage) -> Result<Vec<u8>, ProcessError> {
+    let mut output_bytes = Vec::<u8>::new();
+    PngEncoder::new(&mut output_bytes)
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image::ColorType::Rgb8,
+        )
+        .map_err(|e| {
+            ProcessError::ErrorInternalServerError(format!("Can't encode output: {:?}", e))
+        })?;
+
+    Ok(output_bytes)
+}
@@ -0,0 +1,326 @@
+use std::io::Cursor;
+use std::str::FromStr;
+
+use image::{DynamicImage, RgbImage};
+use rawloader::{RawImage, RawImageData, CFA};
+use serde::Deserialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RawError {
+    #[error("unable to decode raw file: {0}")]
+    Decode(String),
+
+    #[error("unsupported raw file with {0} components per pixel")]
+    UnsupportedComponents(usize),
+
+    #[error("invalid raw dimensions {width}x{height}")]
+    InvalidDimensions { width: usize, height: usize },
+}
+
+/// White balance applied to the sensor values of RAW camera files
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum WhiteBalance {
+    /// Multipliers recorded by the camera when the picture was taken
+    Camera,
+    /// Keep the sensor values as-is
+    None,
+    /// Explicit red, green and blue multipliers
+    Custom([f32; 3]),
+}
+
+impl FromStr for WhiteBalance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "camera" => Ok(WhiteBalance::Camera),
+            "none" => Ok(WhiteBalance::None),
+            custom => {
+                let multipliers = custom
+                    .split(',')
+                    .map(|m| m.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("invalid white balance multiplier: {}", e))?;
+
+                match multipliers[..] {
+                    [r, g, b] if r > 0.0 && g > 0.0 && b > 0.0 => {
+                        Ok(WhiteBalance::Custom([r, g, b]))
+                    }
+                    _ => Err(format!(
+                        "invalid white balance '{}', expected 'camera', 'none' or 3 positive multipliers 'r,g,b'",
+                        s
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for WhiteBalance {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Options used when the input is a RAW camera file, ignored for other formats
+#[derive(Debug, Clone, Deserialize, StructOpt)]
+#[serde(default)]
+pub struct RawOptions {
+    /// Exposure compensation in EV applied to RAW camera files
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub raw_exposure: f32,
+
+    /// White balance of RAW camera files: "camera", "none" or "r,g,b" multipliers
+    #[structopt(long, default_value = "camera")]
+    pub raw_white_balance: WhiteBalance,
+}
+
+impl Default for RawOptions {
+    fn default() -> Self {
+        Self {
+            raw_exposure: 0.0,
+            raw_white_balance: WhiteBalance::Camera,
+        }
+    }
+}
+
+/// Linear sRGB (D65) from CIE XYZ
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.240454, -1.537139, -0.498531],
+    [-0.969266, 1.876011, 0.041556],
+    [0.055643, -0.204026, 1.057225],
+];
+
+/// Decode a RAW camera file (DNG, CR2, NEF, ARW, ...) to an 8-bit sRGB image.
+///
+/// The sensor data is normalized using the black and white levels, white balanced, exposure
+/// compensated and demosaiced to linear RGB before being converted to sRGB for the model.
+pub fn decode_raw(bytes: &[u8], options: &RawOptions) -> Result<DynamicImage, RawError> {
+    let raw = rawloader::decode(&mut Cursor::new(bytes))
+        .map_err(|e| RawError::Decode(format!("{:?}", e)))?;
+
+    let linear = raw_to_linear_rgb(&raw, options)?;
+    let [top, right, bottom, left] = raw.crops;
+    let width = raw.width.saturating_sub(left + right);
+    let height = raw.height.saturating_sub(top + bottom);
+    if width == 0 || height == 0 {
+        return Err(RawError::InvalidDimensions { width, height });
+    }
+
+    let color_matrix = camera_to_srgb(&raw);
+    let mut image = RgbImage::new(width as u32, height as u32);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let i = 3 * ((y as usize + top) * raw.width + x as usize + left);
+        let camera = [linear[i], linear[i + 1], linear[i + 2]];
+        for (c, value) in pixel.0.iter_mut().enumerate() {
+            let srgb = color_matrix[c][0] * camera[0]
+                + color_matrix[c][1] * camera[1]
+                + color_matrix[c][2] * camera[2];
+            *value = (linear_to_srgb(srgb) * 255.0).round() as u8;
+        }
+    }
+
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+/// Produce white balanced, exposure compensated linear camera RGB values, 3 per pixel
+fn raw_to_linear_rgb(raw: &RawImage, options: &RawOptions) -> Result<Vec<f32>, RawError> {
+    let (width, height) = (raw.width, raw.height);
+    if width == 0 || height == 0 {
+        return Err(RawError::InvalidDimensions { width, height });
+    }
+
+    let gain = 2f32.powf(options.raw_exposure);
+    let multipliers = white_balance_multipliers(raw, options.raw_white_balance);
+    let level = |value: f32, color: usize| {
+        normalize(value, raw.blacklevels[color], raw.whitelevels[color]) * multipliers[color] * gain
+    };
+    let sample = |i: usize| match &raw.data {
+        RawImageData::Integer(data) => data[i] as f32,
+        RawImageData::Float(data) => data[i],
+    };
+
+    match raw.cpp {
+        3 => Ok((0..width * height * 3)
+            .map(|i| level(sample(i), i % 3))
+            .collect()),
+        1 if raw.cfa.is_valid() => {
+            let mosaic: Vec<f32> = (0..width * height)
+                .map(|i| level(sample(i), raw.cfa.color_at(i / width, i % width)))
+                .collect();
+            Ok(demosaic_bilinear(&raw.cfa, width, height, &mosaic))
+        }
+        // Monochrome sensors have no color filter, their values are used for the 3 channels
+        // with the levels and white balance of green so that they stay gray
+        1 => Ok((0..width * height)
+            .flat_map(|i| [level(sample(i), 1); 3])
+            .collect()),
+        cpp => Err(RawError::UnsupportedComponents(cpp)),
+    }
+}
+
+/// Sensor value scaled so that the black level is 0 and the white level is 1, values under the
+/// black level are clamped to 0 and highlights above the white level are kept
+fn normalize(value: f32, black: u16, white: u16) -> f32 {
+    let (black, white) = (f32::from(black), f32::from(white));
+    let range = (white - black).max(1.0);
+    ((value - black) / range).max(0.0)
+}
+
+/// Per-CFA-color multipliers normalized on green, the 4th color is treated as a second green
+fn white_balance_multipliers(raw: &RawImage, white_balance: WhiteBalance) -> [f32; 4] {
+    match white_balance {
+        WhiteBalance::None => [1.0; 4],
+        WhiteBalance::Custom([r, g, b]) => [r / g, 1.0, b / g, 1.0],
+        WhiteBalance::Camera => {
+            let coeffs = raw.wb_coeffs;
+            let valid = |c: f32| c.is_finite() && c > 0.0;
+            if !valid(coeffs[0]) || !valid(coeffs[1]) || !valid(coeffs[2]) {
+                return [1.0; 4];
+            }
+            let green = coeffs[1];
+            let fourth = if valid(coeffs[3]) { coeffs[3] } else { green };
+            [coeffs[0] / green, 1.0, coeffs[2] / green, fourth / green]
+        }
+    }
+}
+
+/// Bilinear demosaic working for any CFA pattern: each missing channel is the average of the
+/// neighbours of that color in the surrounding 3x3 window
+fn demosaic_bilinear(cfa: &CFA, width: usize, height: usize, mosaic: &[f32]) -> Vec<f32> {
+    let channel_of = |row: usize, col: usize| match cfa.color_at(row, col) {
+        3 => 1,
+        color => color,
+    };
+
+    let mut rgb = vec![0.0; width * height * 3];
+    for row in 0..height {
+        for col in 0..width {
+            let own_channel = channel_of(row, col);
+            let mut sums = [0.0f32; 3];
+            let mut counts = [0u32; 3];
+
+            for nrow in row.saturating_sub(1)..=(row + 1).min(height - 1) {
+                for ncol in col.saturating_sub(1)..=(col + 1).min(width - 1) {
+                    let channel = channel_of(nrow, ncol);
+                    sums[channel] += mosaic[nrow * width + ncol];
+                    counts[channel] += 1;
+                }
+            }
+
+            let i = 3 * (row * width + col);
+            for channel in 0..3 {
+                rgb[i + channel] = if channel == own_channel {
+                    mosaic[row * width + col]
+                } else if counts[channel] > 0 {
+                    sums[channel] / counts[channel] as f32
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+
+    rgb
+}
+
+/// Matrix from camera RGB to linear sRGB, identity if the camera color matrix is unknown
+fn camera_to_srgb(raw: &RawImage) -> [[f32; 3]; 3] {
+    let cam_to_xyz = raw.cam_to_xyz_normalized();
+    let mut matrix = [[0.0f32; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| XYZ_TO_SRGB[i][k] * cam_to_xyz[k][j]).sum();
+        }
+    }
+
+    let known = matrix.iter().flatten().any(|v| *v != 0.0);
+    if known && matrix.iter().flatten().all(|v| v.is_finite()) {
+        matrix
+    } else {
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    }
+}
+
+/// sRGB transfer function, input and output are between 0 and 1 (input is clamped)
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_levels() {
+        assert_eq!(normalize(512.0, 512, 4096), 0.0);
+        assert_eq!(normalize(4096.0, 512, 4096), 1.0);
+        assert_eq!(normalize(2304.0, 512, 4096), 0.5);
+        // Values under the black level are clamped, highlights are kept
+        assert_eq!(normalize(100.0, 512, 4096), 0.0);
+        assert!(normalize(5000.0, 512, 4096) > 1.0);
+    }
+
+    #[test]
+    fn normalize_invalid_levels() {
+        // A white level under the black level must not divide by 0 or make values negative
+        assert_eq!(normalize(10.0, 10, 10), 0.0);
+        assert_eq!(normalize(11.0, 10, 5), 1.0);
+        assert_eq!(normalize(0.0, 10, 5), 0.0);
+    }
+
+    /// RGGB mosaic of a uniform color
+    fn rggb_mosaic(cfa: &CFA, width: usize, height: usize, color: [f32; 3]) -> Vec<f32> {
+        (0..width * height)
+            .map(|i| match cfa.color_at(i / width, i % width) {
+                3 => color[1],
+                c => color[c],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn demosaic_uniform_color() {
+        let cfa = CFA::new("RGGB");
+        let (width, height) = (6, 4);
+        let color = [0.8, 0.5, 0.2];
+        let mosaic = rggb_mosaic(&cfa, width, height, color);
+
+        let rgb = demosaic_bilinear(&cfa, width, height, &mosaic);
+
+        assert_eq!(rgb.len(), width * height * 3);
+        for pixel in rgb.chunks(3) {
+            for (value, expected) in pixel.iter().zip(color) {
+                assert!((value - expected).abs() < 1e-6, "{:?}", pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn demosaic_keeps_sensor_values() {
+        let cfa = CFA::new("RGGB");
+        let (width, height) = (4, 4);
+        let mosaic: Vec<f32> = (0..width * height).map(|i| i as f32).collect();
+
+        let rgb = demosaic_bilinear(&cfa, width, height, &mosaic);
+
+        // Top left is red, its right neighbour green and the pixel under it blue
+        assert_eq!(rgb[0], 0.0);
+        assert_eq!(rgb[3 + 1], 1.0);
+        assert_eq!(rgb[3 * (width + 1) + 2], 5.0);
+        // Red of the green pixel at (1, 0) is the average of the red ones at (0, 0) and (2, 0)
+        assert_eq!(rgb[3 * width], 4.0);
+        // Missing blue of the top left pixel is the one of (1, 1)
+        assert_eq!(rgb[2], 5.0);
+    }
+}
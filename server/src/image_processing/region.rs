@@ -0,0 +1,383 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, RgbImage};
+use serde::Deserialize;
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RegionError {
+    #[error("the selected region is empty or outside of the image")]
+    EmptySelection,
+
+    #[error("a region and a mask can't be used at the same time")]
+    RegionAndMask,
+
+    #[error("enhanced region is {actual_width}x{actual_height}, expected {expected_width}x{expected_height}")]
+    SizeMismatch {
+        expected_width: u32,
+        expected_height: u32,
+        actual_width: u32,
+        actual_height: u32,
+    },
+}
+
+/// A rectangle in image pixels, parsed from `x,y,width,height`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rectangle {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Rectangle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid region value: {}", e))?;
+
+        match values[..] {
+            [x, y, width, height] => Ok(Rectangle {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(format!(
+                "invalid region '{}', expected 'x,y,width,height'",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Rectangle {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Options restricting the enhancement to a part of the image
+#[derive(Debug, Clone, Default, Deserialize, StructOpt)]
+#[serde(default)]
+pub struct RegionOptions {
+    /// Only enhance this rectangle of the image, as "x,y,width,height"
+    #[structopt(long)]
+    pub region: Option<Rectangle>,
+
+    /// Grayscale mask of the part to enhance (white is enhanced, black is kept)
+    ///
+    /// Over HTTP the mask is sent as a `mask` multipart field instead.
+    #[serde(skip)]
+    #[structopt(long, parse(from_os_str))]
+    pub mask: Option<PathBuf>,
+
+    /// Width in pixels of the transition between the enhanced region and the original image
+    #[structopt(long, default_value = "0")]
+    pub feather: u32,
+}
+
+/// The part of the image that should be enhanced
+pub enum Selection {
+    Rectangle(Rectangle),
+    Mask(GrayImage),
+}
+
+impl Selection {
+    /// Selection from the region option or a decoded mask image, `None` to enhance everything
+    pub fn new(
+        region: Option<Rectangle>,
+        mask: Option<DynamicImage>,
+    ) -> Result<Option<Selection>, RegionError> {
+        match (region, mask) {
+            (Some(_), Some(_)) => Err(RegionError::RegionAndMask),
+            (Some(rect), None) => Ok(Some(Selection::Rectangle(rect))),
+            (None, Some(mask)) => Ok(Some(Selection::Mask(mask.to_luma8()))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Enhance only the selected part of `image` and blend the result back into the original.
+///
+/// Only the bounding box of the selection is sent to `enhance`, the blend uses the selection as
+/// alpha, softened over `feather` pixels.
+pub fn enhance_region<E>(
+    image: &DynamicImage,
+    selection: &Selection,
+    feather: u32,
+    enhance: impl FnOnce(&DynamicImage) -> Result<RgbImage, E>,
+) -> Result<RgbImage, E>
+where
+    E: From<RegionError>,
+{
+    let alpha = selection_alpha(image.width(), image.height(), selection, feather);
+    let (x, y, width, height) = bounding_box(&alpha).ok_or(RegionError::EmptySelection)?;
+
+    let enhanced = enhance(&image.crop_imm(x, y, width, height))?;
+    if enhanced.dimensions() != (width, height) {
+        return Err(RegionError::SizeMismatch {
+            expected_width: width,
+            expected_height: height,
+            actual_width: enhanced.width(),
+            actual_height: enhanced.height(),
+        }
+        .into());
+    }
+
+    let mut output = image.to_rgb8();
+    for (ex, ey, enhanced_pixel) in enhanced.enumerate_pixels() {
+        let a = alpha.get_pixel(x + ex, y + ey).0[0] as f32 / 255.0;
+        let pixel = output.get_pixel_mut(x + ex, y + ey);
+        for (value, enhanced_value) in pixel.0.iter_mut().zip(enhanced_pixel.0) {
+            let original = *value as f32;
+            *value = (original + (enhanced_value as f32 - original) * a).round() as u8;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Alpha map of the selection at the image size, 255 being fully enhanced
+fn selection_alpha(width: u32, height: u32, selection: &Selection, feather: u32) -> GrayImage {
+    match selection {
+        Selection::Rectangle(rect) => {
+            let right = rect.x.saturating_add(rect.width).min(width);
+            let bottom = rect.y.saturating_add(rect.height).min(height);
+
+            GrayImage::from_fn(width, height, |x, y| {
+                if x < rect.x || x >= right || y < rect.y || y >= bottom {
+                    return Luma([0]);
+                }
+                if feather == 0 {
+                    return Luma([255]);
+                }
+
+                // Feathered inwards so that nothing outside of the rectangle is changed
+                let distance = (x - rect.x)
+                    .min(right - 1 - x)
+                    .min(y - rect.y)
+                    .min(bottom - 1 - y)
+                    + 1;
+                let a = (distance as f32 / feather as f32).min(1.0);
+                Luma([(a * 255.0).round() as u8])
+            })
+        }
+        // An empty mask selects nothing, it can't be resized
+        Selection::Mask(mask) if mask.width() == 0 || mask.height() == 0 => {
+            GrayImage::new(width, height)
+        }
+        Selection::Mask(mask) => {
+            let mask = if mask.dimensions() == (width, height) {
+                mask.clone()
+            } else {
+                image::imageops::resize(mask, width, height, FilterType::Triangle)
+            };
+
+            if feather == 0 {
+                mask
+            } else {
+                image::imageops::blur(&mask, feather as f32 / 2.0)
+            }
+        }
+    }
+}
+
+/// Smallest rectangle containing all the non-zero pixels, as `(x, y, width, height)`
+fn bounding_box(alpha: &GrayImage) -> Option<(u32, u32, u32, u32)> {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in alpha.enumerate_pixels() {
+        if pixel.0[0] == 0 {
+            continue;
+        }
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+        });
+    }
+
+    bounds.map(|(left, top, right, bottom)| (left, top, right - left + 1, bottom - top + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray_image(width: u32, height: u32, value: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, image::Rgb([value; 3])))
+    }
+
+    fn enhance_white(image: &DynamicImage) -> Result<RgbImage, RegionError> {
+        Ok(RgbImage::from_pixel(
+            image.width(),
+            image.height(),
+            image::Rgb([255; 3]),
+        ))
+    }
+
+    fn rectangle(x: u32, y: u32, width: u32, height: u32) -> Selection {
+        Selection::Rectangle(Rectangle {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    #[test]
+    fn parse_rectangle() {
+        assert_eq!(
+            " 1, 2,3 ,4".parse::<Rectangle>(),
+            Ok(Rectangle {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4
+            })
+        );
+        assert!("1,2,3".parse::<Rectangle>().is_err());
+        assert!("1,2,3,4,5".parse::<Rectangle>().is_err());
+        assert!("-1,2,3,4".parse::<Rectangle>().is_err());
+        assert!("a,b,c,d".parse::<Rectangle>().is_err());
+        assert!("".parse::<Rectangle>().is_err());
+    }
+
+    #[test]
+    fn region_and_mask() {
+        let region = "0,0,1,1".parse().ok();
+        let mask = Some(gray_image(1, 1, 255));
+        assert!(matches!(
+            Selection::new(region, mask),
+            Err(RegionError::RegionAndMask)
+        ));
+        assert!(Selection::new(None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn rectangle_clamped_to_image() {
+        let image = gray_image(10, 8, 0);
+        let mut cropped = None;
+        let output = enhance_region(&image, &rectangle(6, 4, 100, u32::MAX), 0, |crop| {
+            cropped = Some(crop.dimensions());
+            enhance_white(crop)
+        })
+        .unwrap();
+
+        assert_eq!(cropped, Some((4, 4)));
+        assert_eq!(output.dimensions(), (10, 8));
+        assert_eq!(output.get_pixel(6, 4).0, [255; 3]);
+        assert_eq!(output.get_pixel(9, 7).0, [255; 3]);
+        assert_eq!(output.get_pixel(5, 4).0, [0; 3]);
+        assert_eq!(output.get_pixel(6, 3).0, [0; 3]);
+    }
+
+    #[test]
+    fn rectangle_outside_of_image() {
+        let image = gray_image(10, 8, 0);
+        for selection in [
+            rectangle(10, 0, 5, 5),
+            rectangle(0, 8, 5, 5),
+            rectangle(2, 2, 0, 3),
+        ] {
+            let result = enhance_region(&image, &selection, 4, |_| -> Result<_, RegionError> {
+                panic!("Nothing should be enhanced")
+            });
+            assert!(matches!(result, Err(RegionError::EmptySelection)));
+        }
+    }
+
+    #[test]
+    fn rectangle_feathered_inwards() {
+        let alpha = selection_alpha(10, 10, &rectangle(2, 2, 6, 6), 3);
+
+        assert_eq!(alpha.get_pixel(1, 4).0[0], 0);
+        assert_eq!(alpha.get_pixel(2, 4).0[0], 85);
+        assert_eq!(alpha.get_pixel(3, 4).0[0], 170);
+        assert_eq!(alpha.get_pixel(4, 4).0[0], 255);
+        assert_eq!(alpha.get_pixel(7, 7).0[0], 85);
+        assert_eq!(alpha.get_pixel(8, 7).0[0], 0);
+        assert_eq!(bounding_box(&alpha), Some((2, 2, 6, 6)));
+    }
+
+    #[test]
+    fn feather_blends_with_original() {
+        let image = gray_image(10, 10, 0);
+        let output = enhance_region(&image, &rectangle(2, 2, 6, 6), 3, enhance_white).unwrap();
+
+        assert_eq!(output.get_pixel(2, 4).0, [85; 3]);
+        assert_eq!(output.get_pixel(4, 4).0, [255; 3]);
+        assert_eq!(output.get_pixel(1, 4).0, [0; 3]);
+    }
+
+    #[test]
+    fn bounding_box_of_mask() {
+        let mut alpha = GrayImage::new(8, 6);
+        assert_eq!(bounding_box(&alpha), None);
+
+        alpha.put_pixel(5, 1, Luma([1]));
+        assert_eq!(bounding_box(&alpha), Some((5, 1, 1, 1)));
+        alpha.put_pixel(2, 4, Luma([200]));
+        assert_eq!(bounding_box(&alpha), Some((2, 1, 4, 4)));
+    }
+
+    #[test]
+    fn empty_mask() {
+        let image = gray_image(10, 8, 0);
+        let black = Selection::Mask(GrayImage::new(10, 8));
+        let no_pixels = Selection::Mask(GrayImage::new(0, 0));
+
+        for selection in [black, no_pixels] {
+            for feather in [0, 3] {
+                let result = enhance_region(&image, &selection, feather, enhance_white);
+                assert!(matches!(result, Err(RegionError::EmptySelection)));
+            }
+        }
+    }
+
+    #[test]
+    fn mask_resized_to_image() {
+        let image = gray_image(10, 8, 0);
+        let mut mask = GrayImage::new(20, 16);
+        for y in 0..16 {
+            for x in 10..20 {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+
+        let output = enhance_region(&image, &Selection::Mask(mask), 0, enhance_white).unwrap();
+
+        assert_eq!(output.dimensions(), (10, 8));
+        assert_eq!(output.get_pixel(0, 0).0, [0; 3]);
+        assert_eq!(output.get_pixel(9, 7).0, [255; 3]);
+    }
+
+    #[test]
+    fn enhanced_size_mismatch() {
+        let image = gray_image(10, 8, 0);
+        let result = enhance_region(&image, &rectangle(0, 0, 4, 4), 0, |_| {
+            Ok::<_, RegionError>(RgbImage::new(5, 4))
+        });
+
+        assert!(matches!(
+            result,
+            Err(RegionError::SizeMismatch {
+                expected_width: 4,
+                expected_height: 4,
+                actual_width: 5,
+                actual_height: 4,
+            })
+        ));
+    }
+}
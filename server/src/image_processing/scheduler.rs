@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use anyhow::{bail, Result as AnyResult};
+use chrono::{DateTime, Utc};
+use futures::channel::oneshot;
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{debug, instrument};
+use ts_rs::TS;
+
+use crate::users::AuthenticatedUser;
+
+/// Weight of the last job duration in the moving average used for the wait estimates
+const AVERAGE_SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct SchedulerOptions {
+    /// Number of images processed at the same time
+    #[structopt(long, env = "MIRNET_INFERENCE_SLOTS", default_value = "1")]
+    pub inference_slots: usize,
+
+    /// Share of the processing given to the interactive requests of a user (`/api/run`)
+    #[structopt(long, env = "MIRNET_INTERACTIVE_WEIGHT", default_value = "4")]
+    pub interactive_weight: u32,
+
+    /// Share of the processing given to the batch requests of a user (`/api/batch`)
+    #[structopt(long, env = "MIRNET_BATCH_WEIGHT", default_value = "1")]
+    pub batch_weight: u32,
+}
+
+impl SchedulerOptions {
+    pub fn validate(&self) -> AnyResult<()> {
+        if self.inference_slots == 0 {
+            bail!("--inference-slots must be at least 1");
+        }
+        if self.interactive_weight == 0 || self.batch_weight == 0 {
+            bail!("Scheduling weights must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+/// Priority class of a job, each class gets a share of the processing following its weight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub enum JobClass {
+    /// A single image a user is waiting for
+    Interactive,
+    /// One of the images of a batch
+    Batch,
+}
+
+struct QueuedJob {
+    id: u64,
+    user_id: i32,
+    class: JobClass,
+    /// Virtual time at which the job would be done if the processing was shared perfectly
+    finish: f64,
+    queued_at: DateTime<Utc>,
+    /// Wakes up the request waiting for the job to start
+    start: oneshot::Sender<()>,
+}
+
+struct RunningJob {
+    id: u64,
+    user_id: i32,
+    class: JobClass,
+    queued_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    last_id: u64,
+    /// Finish time of the last started job, the self-clocked virtual time
+    virtual_time: f64,
+    /// Finish time of the last job of each user with jobs not started yet
+    last_finish: HashMap<i32, f64>,
+    /// Waiting jobs, in the order they start
+    queue: Vec<QueuedJob>,
+    running: Vec<RunningJob>,
+    /// Moving average of the duration of a job, unknown until one is done
+    average_seconds: Option<f64>,
+}
+
+/// Weighted fair queuing of the image processing in front of the model.
+///
+/// Each user is a flow: their jobs start in the order of their virtual finish time, which
+/// advances by the inverse of the weight of the job class. A user with a big batch only gets
+/// their share of the slots, and the single images of the other users pass in between.
+///
+/// Jobs wait for their turn asynchronously, no thread is held until they start.
+pub struct Scheduler {
+    options: SchedulerOptions,
+    state: Mutex<SchedulerState>,
+}
+
+/// A job in the scheduler, it holds a slot once `Scheduler::acquire` returns and leaves the
+/// scheduler when dropped, whether it started or not
+pub struct Slot {
+    scheduler: Arc<Scheduler>,
+    id: u64,
+    started: Option<Instant>,
+}
+
+impl Slot {
+    /// Time since the job started, without the wait
+    pub fn elapsed(&self) -> Duration {
+        self.started
+            .map_or(Duration::ZERO, |started| started.elapsed())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let elapsed = self.started.map(|started| started.elapsed());
+        self.scheduler.leave(self.id, elapsed);
+    }
+}
+
+impl Scheduler {
+    pub fn new(options: SchedulerOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SchedulerState> {
+        // The state is consistent between the statements that can panic
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn weight(&self, class: JobClass) -> u32 {
+        match class {
+            JobClass::Interactive => self.options.interactive_weight,
+            JobClass::Batch => self.options.batch_weight,
+        }
+    }
+
+    fn enqueue(&self, user_id: i32, class: JobClass) -> (u64, oneshot::Receiver<()>) {
+        let mut state = self.state();
+        state.last_id += 1;
+        let id = state.last_id;
+
+        let start = match state.last_finish.get(&user_id) {
+            Some(last_finish) => last_finish.max(state.virtual_time),
+            None => state.virtual_time,
+        };
+        let finish = start + 1.0 / f64::from(self.weight(class));
+        state.last_finish.insert(user_id, finish);
+
+        let (sender, receiver) = oneshot::channel();
+        let index = state.queue.partition_point(|job| job.finish <= finish);
+        state.queue.insert(
+            index,
+            QueuedJob {
+                id,
+                user_id,
+                class,
+                finish,
+                queued_at: Utc::now(),
+                start: sender,
+            },
+        );
+        debug!(id, user_id, ?class, position = index + 1, "Queued job");
+        self.dispatch(&mut state);
+
+        (id, receiver)
+    }
+
+    /// Start the first jobs of the queue while there are free slots
+    fn dispatch(&self, state: &mut SchedulerState) {
+        while state.running.len() < self.options.inference_slots && !state.queue.is_empty() {
+            let job = state.queue.remove(0);
+            state.virtual_time = state.virtual_time.max(job.finish);
+            let virtual_time = state.virtual_time;
+            // Users with nothing left in the queue start again from the virtual time
+            state.last_finish.retain(|_, finish| *finish > virtual_time);
+            state.running.push(RunningJob {
+                id: job.id,
+                user_id: job.user_id,
+                class: job.class,
+                queued_at: job.queued_at,
+            });
+            // The receiver only goes away with the slot, which removes the job again
+            let _ = job.start.send(());
+        }
+    }
+
+    /// Remove a job, queued or running, and give its slot to the next one. `elapsed` is the time
+    /// the job ran, `None` when it was cancelled before starting
+    fn leave(&self, id: u64, elapsed: Option<Duration>) {
+        let mut state = self.state();
+        state.queue.retain(|job| job.id != id);
+        state.running.retain(|job| job.id != id);
+        if let Some(elapsed) = elapsed {
+            let seconds = elapsed.as_secs_f64();
+            state.average_seconds = Some(match state.average_seconds {
+                Some(average) => average + (seconds - average) * AVERAGE_SMOOTHING,
+                None => seconds,
+            });
+        }
+        self.dispatch(&mut state);
+    }
+
+    /// Wait for the turn of a job of the user and return its slot, the job runs until the slot
+    /// is dropped. Dropping the future while it waits removes the job from the queue
+    pub async fn acquire(self: &Arc<Self>, user_id: i32, class: JobClass) -> Slot {
+        let (id, start) = self.enqueue(user_id, class);
+        let mut slot = Slot {
+            scheduler: self.clone(),
+            id,
+            started: None,
+        };
+
+        // The sender is only dropped without a message when the job was removed, which takes the
+        // slot, so both cases mean that the job is running
+        let _ = start.await;
+        slot.started = Some(Instant::now());
+
+        slot
+    }
+
+    /// The jobs of a user with their position in the queue
+    fn status(&self, user_id: i32) -> QueueStatus {
+        let state = self.state();
+        let slots = self.options.inference_slots;
+
+        let running = state
+            .running
+            .iter()
+            .filter(|job| job.user_id == user_id)
+            .map(|job| JobStatus {
+                class: job.class,
+                position: None,
+                estimated_wait_seconds: None,
+                queued_at: job.queued_at,
+            });
+        let queued = state
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| job.user_id == user_id)
+            .map(|(index, job)| JobStatus {
+                class: job.class,
+                position: Some(index + 1),
+                // Each round frees the slots once, the jobs ahead start by rounds
+                estimated_wait_seconds: state
+                    .average_seconds
+                    .map(|average| average * ((index + state.running.len()) / slots) as f64),
+                queued_at: job.queued_at,
+            });
+
+        QueueStatus {
+            queued: state.queue.len(),
+            running: state.running.len(),
+            slots,
+            jobs: running.chain(queued).collect(),
+        }
+    }
+}
+
+/// A job of the user, running when it has no position
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct JobStatus {
+    class: JobClass,
+    /// Position in the queue, 1 is the next job to start
+    position: Option<usize>,
+    /// Unknown until a job was done since the server started
+    estimated_wait_seconds: Option<f64>,
+    queued_at: DateTime<Utc>,
+}
+
+/// Load of the processing queue, with the jobs of the user
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct QueueStatus {
+    /// Jobs of all the users waiting to start
+    queued: usize,
+    running: usize,
+    slots: usize,
+    jobs: Vec<JobStatus>,
+}
+
+#[instrument(name = "Queue Status", skip(auth, scheduler), fields(user_id = auth.user.id))]
+pub async fn get_queue(auth: AuthenticatedUser, scheduler: web::Data<Scheduler>) -> HttpResponse {
+    HttpResponse::Ok().json(scheduler.status(auth.user.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::future::Future;
+    use std::pin::Pin;
+
+    type Waiting<'a> = Pin<Box<dyn Future<Output = Slot> + 'a>>;
+
+    fn scheduler(inference_slots: usize) -> Arc<Scheduler> {
+        Arc::new(Scheduler::new(SchedulerOptions {
+            inference_slots,
+            interactive_weight: 4,
+            batch_weight: 1,
+        }))
+    }
+
+    /// A job that started right away
+    fn start(scheduler: &Arc<Scheduler>, user_id: i32, class: JobClass) -> Slot {
+        scheduler
+            .acquire(user_id, class)
+            .now_or_never()
+            .expect("The job didn't start")
+    }
+
+    /// A job waiting for its turn
+    fn wait(scheduler: &Arc<Scheduler>, user_id: i32, class: JobClass) -> Waiting<'_> {
+        let mut job: Waiting<'_> = Box::pin(scheduler.acquire(user_id, class));
+        assert!(job.as_mut().now_or_never().is_none(), "The job started");
+        job
+    }
+
+    /// Positions of the queued jobs of a user, with `None` for the running ones
+    fn positions(scheduler: &Scheduler, user_id: i32) -> Vec<Option<usize>> {
+        let status = scheduler.status(user_id);
+        status.jobs.iter().map(|job| job.position).collect()
+    }
+
+    /// Finish the running job and the next ones one at a time, and return the names of the
+    /// waiting jobs in the order they started
+    fn grant_order(
+        mut running: Slot,
+        mut waiting: Vec<(&'static str, Waiting<'_>)>,
+    ) -> Vec<&'static str> {
+        let mut order = Vec::new();
+        while !waiting.is_empty() {
+            drop(running);
+            let mut started = Vec::new();
+            for (index, (_, job)) in waiting.iter_mut().enumerate() {
+                if let Some(slot) = job.as_mut().now_or_never() {
+                    started.push((index, slot));
+                }
+            }
+            assert_eq!(started.len(), 1, "A single slot was freed");
+            let (index, slot) = started.pop().unwrap();
+            order.push(waiting.remove(index).0);
+            running = slot;
+        }
+
+        order
+    }
+
+    #[test]
+    fn grant_order_follows_weights() {
+        let scheduler = scheduler(1);
+        let running = start(&scheduler, 1, JobClass::Batch);
+        let waiting = vec![
+            ("batch 1a", wait(&scheduler, 1, JobClass::Batch)),
+            ("batch 1b", wait(&scheduler, 1, JobClass::Batch)),
+            ("batch 1c", wait(&scheduler, 1, JobClass::Batch)),
+            ("interactive 2a", wait(&scheduler, 2, JobClass::Interactive)),
+            ("interactive 2b", wait(&scheduler, 2, JobClass::Interactive)),
+            ("batch 3a", wait(&scheduler, 3, JobClass::Batch)),
+        ];
+
+        // The interactive jobs pass the batch ones, and the batch users take turns
+        assert_eq!(
+            grant_order(running, waiting),
+            [
+                "interactive 2a",
+                "interactive 2b",
+                "batch 1a",
+                "batch 3a",
+                "batch 1b",
+                "batch 1c",
+            ]
+        );
+        let status = scheduler.status(1);
+        assert_eq!((status.queued, status.running), (0, 0));
+    }
+
+    #[test]
+    fn several_slots() {
+        let scheduler = scheduler(2);
+        let first = start(&scheduler, 1, JobClass::Batch);
+        let _second = start(&scheduler, 1, JobClass::Batch);
+        let mut third = wait(&scheduler, 2, JobClass::Batch);
+
+        drop(first);
+        assert!(third.as_mut().now_or_never().is_some());
+    }
+
+    #[test]
+    fn dropped_waiter_frees_its_place() {
+        let scheduler = scheduler(1);
+        let running = start(&scheduler, 1, JobClass::Interactive);
+        let cancelled = wait(&scheduler, 1, JobClass::Interactive);
+        let mut next = wait(&scheduler, 2, JobClass::Interactive);
+        assert_eq!(positions(&scheduler, 2), [Some(2)]);
+
+        drop(cancelled);
+        assert_eq!(positions(&scheduler, 1), [None]);
+        assert_eq!(positions(&scheduler, 2), [Some(1)]);
+        assert_eq!(scheduler.status(2).queued, 1);
+
+        drop(running);
+        let started = next.as_mut().now_or_never();
+        assert!(started.is_some());
+        assert_eq!(scheduler.status(1).running, 1);
+    }
+
+    #[test]
+    fn status_positions() {
+        let scheduler = scheduler(1);
+        let running = start(&scheduler, 1, JobClass::Batch);
+        let _waiting = [
+            wait(&scheduler, 1, JobClass::Batch),
+            wait(&scheduler, 1, JobClass::Batch),
+            wait(&scheduler, 2, JobClass::Interactive),
+            wait(&scheduler, 2, JobClass::Interactive),
+        ];
+
+        // Queue: 2, 2, 1, 1
+        assert_eq!(positions(&scheduler, 1), [None, Some(3), Some(4)]);
+        assert_eq!(positions(&scheduler, 2), [Some(1), Some(2)]);
+        let status = scheduler.status(2);
+        assert_eq!((status.queued, status.running, status.slots), (4, 1, 1));
+        assert!(status
+            .jobs
+            .iter()
+            .all(|job| job.estimated_wait_seconds.is_none()));
+
+        // Once a job is done, the waits are estimated from its duration
+        drop(running);
+        assert_eq!(positions(&scheduler, 1), [Some(2), Some(3)]);
+        assert_eq!(positions(&scheduler, 2), [None, Some(1)]);
+        let status = scheduler.status(1);
+        assert!(status
+            .jobs
+            .iter()
+            .all(|job| job.estimated_wait_seconds.is_some()));
+    }
+}
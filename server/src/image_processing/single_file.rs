@@ -1,31 +1,32 @@
 use anyhow::Context;
 use anyhow::Result as AnyResult;
-use image::io::Reader as ImageReader;
-use image::GenericImageView;
 use std::path::Path;
 
-use super::MirnetModel;
-use super::{image_to_tensor, tensor_to_image};
+use super::{process_image_bytes, ProcessOptions};
 
-pub fn run(input: impl AsRef<Path>, output_png: impl AsRef<Path>) -> AnyResult<()> {
-    let model = MirnetModel::new("model")?;
-
-    // Create input variables for our addition
-    let img_reader = ImageReader::open(input).context("Failed to read image")?;
-    let img = img_reader.decode().context("Failed to decode image")?;
-
-    println!("Opened image {}x{}", img.height(), img.width());
-
-    println!("Reading pixels to tensor...");
-    let input = image_to_tensor(&img);
+pub fn run(
+    input: impl AsRef<Path>,
+    output_png: impl AsRef<Path>,
+    options: &ProcessOptions,
+) -> AnyResult<()> {
+    let bytes = std::fs::read(input).context("Failed to read image")?;
+    let mask = match &options.region.mask {
+        Some(mask) => Some(std::fs::read(mask).context("Failed to read mask")?),
+        None => None,
+    };
 
     println!("Running...");
-    let output = model.run(&input)?;
-    let output_image = tensor_to_image(&output)?;
-
-    output_image.save_with_format(output_png, image::ImageFormat::Png)?;
-
-    println!("out: {:?}", &output);
+    let processed = process_image_bytes(&bytes, mask.as_deref(), options, &mut None)?;
+    println!(
+        "Output image {}x{}",
+        processed.image.width(),
+        processed.image.height()
+    );
+    println!("Light analysis: {}", processed.light_report);
+
+    processed
+        .image
+        .save_with_format(output_png, image::ImageFormat::Png)?;
 
     Ok(())
 }
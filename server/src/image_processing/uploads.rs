@@ -0,0 +1,56 @@
+use actix_multipart::{Field, Multipart};
+use futures::{StreamExt, TryStreamExt};
+use std::io::Write;
+use tracing::trace;
+
+/// A file sent in one of the multipart fields of a request
+pub struct Upload {
+    pub filename: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+/// Files sent to the processing endpoints, each image in an `input` field and the optional mask
+/// in a `mask` field
+pub struct Uploads {
+    pub inputs: Vec<Upload>,
+    pub mask: Option<Upload>,
+}
+
+async fn read_field(field: &mut Field) -> Result<Upload, actix_web::Error> {
+    let filename = field
+        .content_disposition()
+        .get_filename()
+        .map(|f| f.to_string());
+    let mut bytes: Vec<u8> = Vec::new();
+
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        trace!("Writing {} bytes", data.len());
+        bytes.write_all(&data)?;
+    }
+
+    Ok(Upload { filename, bytes })
+}
+
+pub async fn get_uploads(mut payload: Multipart) -> Result<Uploads, actix_web::Error> {
+    let mut inputs = Vec::new();
+    let mut mask = None;
+
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if field.name() == "input" {
+            let upload = read_field(&mut field).await?;
+            trace!("Found input with {} bytes", upload.bytes.len());
+            inputs.push(upload);
+        } else if field.name() == "mask" && mask.is_none() {
+            let upload = read_field(&mut field).await?;
+            trace!("Found mask with {} bytes", upload.bytes.len());
+            mask = Some(upload);
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Field not found"));
+    }
+
+    Ok(Uploads { inputs, mask })
+}
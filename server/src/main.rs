@@ -1,10 +1,21 @@
-use crate::image_processing::{process_image, run_single_file};
-use crate::users::{get_me, login, logout, register, UserDb};
+use crate::image_processing::{
+    get_queue, process_batch, process_image, run_single_file, ProcessOptions, Scheduler,
+    SchedulerOptions,
+};
+use crate::users::{
+    change_password, create_admin, create_invite, create_token, delete_account, delete_user,
+    disable_user, enable_user, export_account, export_users, get_me, get_usage, get_user_quotas,
+    import_users, issue_password_reset, list_audit, list_invites, list_lockouts, list_sessions,
+    list_tokens, list_users, login, logout, logout_user, register, reset_password,
+    reset_user_quotas, revoke_invite, revoke_session, revoke_token, set_role, set_user_quotas,
+    spawn_audit_pruning, spawn_session_cleanup, AuditOptions, CookieOptions, DatabaseOptions,
+    HashingOptions, QuotaOptions, RegistrationOptions, SessionOptions, SqliteUserDb,
+    ThrottleOptions, UserDb, ValidationOptions,
+};
 use actix_cors::Cors;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_identity::IdentityService;
 use actix_web::{web, App, HttpServer};
 use anyhow::Result as AnyResult;
-use rusqlite::Connection;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use tracing::{info, instrument};
@@ -33,39 +44,221 @@ struct Opt {
 
     #[structopt(short, long, default_value = "127.0.0.1")]
     host: String,
+
+    /// Try the pending database migrations without applying them and exit
+    #[structopt(long)]
+    check_migrations: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    #[structopt(flatten)]
+    process: ProcessOptions,
+
+    #[structopt(flatten)]
+    scheduler: SchedulerOptions,
+
+    #[structopt(flatten)]
+    database: DatabaseOptions,
+
+    #[structopt(flatten)]
+    cookies: CookieOptions,
+
+    #[structopt(flatten)]
+    sessions: SessionOptions,
+
+    #[structopt(flatten)]
+    audit: AuditOptions,
+
+    #[structopt(flatten)]
+    quotas: QuotaOptions,
+
+    #[structopt(flatten)]
+    throttle: ThrottleOptions,
+
+    #[structopt(flatten)]
+    registration: RegistrationOptions,
+
+    #[structopt(flatten)]
+    validation: ValidationOptions,
+
+    #[structopt(flatten)]
+    hashing: HashingOptions,
+}
+
+async fn open_user_db(database: &DatabaseOptions) -> AnyResult<UserDb> {
+    let user_db = database.open()?;
+    user_db.initialize().await?;
+
+    Ok(user_db)
 }
 
-#[instrument]
-async fn server(host: String, port: u16, static_dir: PathBuf) -> AnyResult<()> {
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Give the admin role to a user, creating the account if needed
+    CreateAdmin { login: String },
+
+    /// Print a one-time password reset token for a user
+    ResetPassword { login: String },
+
+    /// Copy the SQLite database to a new file, the server can keep running
+    Backup {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Write all the accounts, password hashes included, to a JSON file
+    ExportUsers {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Create the accounts of a file written by export-users, existing logins are skipped
+    ImportUsers {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+}
+
+async fn run_command(
+    command: Command,
+    database: &DatabaseOptions,
+    validation: &ValidationOptions,
+    hashing: &HashingOptions,
+) -> AnyResult<()> {
+    let user_db = open_user_db(database).await?;
+    match command {
+        Command::CreateAdmin { login } => {
+            match create_admin(&user_db, validation, hashing, &login).await? {
+                Some(token) => println!(
+                    "Created admin '{}', set its password with the reset token {}",
+                    login, token
+                ),
+                None => println!("'{}' is now an admin", login),
+            }
+        }
+        Command::ResetPassword { login } => {
+            println!("{}", issue_password_reset(&user_db, &login).await?)
+        }
+        Command::Backup { output } => {
+            user_db.backup(output.clone()).await?;
+            println!("Backed up {:?} to {:?}", database.db_path, output);
+        }
+        Command::ExportUsers { output } => {
+            let count = export_users(&user_db, &output).await?;
+            println!("Exported {} users to {:?}", count, output);
+        }
+        Command::ImportUsers { input } => {
+            let summary = import_users(&user_db, &input).await?;
+            println!(
+                "Imported {} users, skipped {} existing ones",
+                summary.imported, summary.skipped
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(cookies))]
+async fn server(
+    host: String,
+    port: u16,
+    static_dir: PathBuf,
+    scheduler: SchedulerOptions,
+    database: DatabaseOptions,
+    cookies: CookieOptions,
+    sessions: SessionOptions,
+    audit: AuditOptions,
+    quotas: QuotaOptions,
+    throttle: ThrottleOptions,
+    registration: RegistrationOptions,
+    validation: ValidationOptions,
+    hashing: HashingOptions,
+) -> AnyResult<()> {
     std::env::set_var("RUST_LOG", "debug");
     tracing_subscriber::fmt::init();
 
-    let user_db = UserDb::new(Connection::open("users.db")?);
-    user_db.initialize().await?;
+    cookies.validate()?;
+    hashing.validate()?;
+    quotas.validate()?;
+    scheduler.validate()?;
+    let cookie_key = cookies.load_key()?;
+
+    let user_db = open_user_db(&database).await?;
+    spawn_session_cleanup(user_db.clone(), &sessions, &throttle);
+    spawn_audit_pruning(user_db.clone(), &audit);
 
     info!("Serving on {}:{}", &host, port);
     info!("Static files will be served from {:?}", &static_dir);
 
     let user_db = web::Data::new(user_db);
+    let sessions = web::Data::new(sessions);
+    let quotas = web::Data::new(quotas);
+    let scheduler = web::Data::new(Scheduler::new(scheduler));
+    let throttle = web::Data::new(throttle);
+    let registration = web::Data::new(registration);
+    let validation = web::Data::new(validation);
+    let hashing = web::Data::new(hashing);
     HttpServer::new(move || {
         let cors = Cors::permissive();
 
         App::new()
             .wrap(TracingLogger::default())
             .wrap(cors)
-            .wrap(IdentityService::new(
-                // All-zero key used as we only store unique session IDs for now
-                CookieIdentityPolicy::new(&[0; 32])
-                    .name("auth-cookie")
-                    .secure(false),
-            ))
+            .wrap(IdentityService::new(cookies.identity_policy(&cookie_key)))
             .route("/api/register", web::post().to(register))
             .route("/api/me", web::get().to(get_me))
+            .route("/api/me", web::delete().to(delete_account))
+            .route("/api/me/export", web::get().to(export_account))
+            .route("/api/me/usage", web::get().to(get_usage))
+            .route("/api/me/password", web::post().to(change_password))
+            .route("/api/password-reset", web::post().to(reset_password))
+            .route("/api/me/sessions", web::get().to(list_sessions))
+            .route("/api/me/sessions/{id}", web::delete().to(revoke_session))
+            .route("/api/me/tokens", web::get().to(list_tokens))
+            .route("/api/me/tokens", web::post().to(create_token))
+            .route("/api/me/tokens/{id}", web::delete().to(revoke_token))
             .route("/api/login", web::post().to(login))
             .route("/api/logout", web::post().to(logout))
+            .route("/api/admin/users", web::get().to(list_users))
+            .route("/api/admin/users/{id}", web::delete().to(delete_user))
+            .route("/api/admin/users/{id}/role", web::post().to(set_role))
+            .route(
+                "/api/admin/users/{id}/disable",
+                web::post().to(disable_user),
+            )
+            .route("/api/admin/users/{id}/enable", web::post().to(enable_user))
+            .route("/api/admin/users/{id}/logout", web::post().to(logout_user))
+            .route(
+                "/api/admin/users/{id}/quotas",
+                web::get().to(get_user_quotas),
+            )
+            .route(
+                "/api/admin/users/{id}/quotas",
+                web::put().to(set_user_quotas),
+            )
+            .route(
+                "/api/admin/users/{id}/quotas",
+                web::delete().to(reset_user_quotas),
+            )
+            .route("/api/admin/lockouts", web::get().to(list_lockouts))
+            .route("/api/admin/invites", web::get().to(list_invites))
+            .route("/api/admin/invites", web::post().to(create_invite))
+            .route("/api/admin/invites/{id}", web::delete().to(revoke_invite))
+            .route("/api/admin/audit", web::get().to(list_audit))
             .route("/api/run", web::post().to(process_image))
+            .route("/api/batch", web::post().to(process_batch))
+            .route("/api/queue", web::get().to(get_queue))
             .service(actix_files::Files::new("/", &static_dir).show_files_listing().redirect_to_slash_directory().index_file("index.html"))
             .app_data(user_db.clone())
+            .app_data(sessions.clone())
+            .app_data(quotas.clone())
+            .app_data(scheduler.clone())
+            .app_data(throttle.clone())
+            .app_data(registration.clone())
+            .app_data(validation.clone())
+            .app_data(hashing.clone())
     })
     .bind(format!("{}:{}", &host, port))?
     .run()
@@ -77,10 +270,37 @@ async fn server(host: String, port: u16, static_dir: PathBuf) -> AnyResult<()> {
 #[actix_web::main]
 async fn main() -> AnyResult<()> {
     let opt = Opt::from_args();
-    if let Some(path) = opt.input {
-        run_single_file(path, "out.png")?;
+    if opt.check_migrations {
+        let user_db = SqliteUserDb::open(&opt.database)?;
+        let pending = user_db.check_migrations().await?;
+        if pending.is_empty() {
+            println!("The database is up to date");
+        }
+        for name in pending {
+            println!("Pending migration {}", name);
+        }
+    } else if let Some(command) = opt.command {
+        opt.hashing.validate()?;
+        run_command(command, &opt.database, &opt.validation, &opt.hashing).await?;
+    } else if let Some(path) = opt.input {
+        run_single_file(path, "out.png", &opt.process)?;
     } else {
-        server(opt.host, opt.port, opt.static_dir).await?;
+        server(
+            opt.host,
+            opt.port,
+            opt.static_dir,
+            opt.scheduler,
+            opt.database,
+            opt.cookies,
+            opt.sessions,
+            opt.audit,
+            opt.quotas,
+            opt.throttle,
+            opt.registration,
+            opt.validation,
+            opt.hashing,
+        )
+        .await?;
     }
 
     Ok(())
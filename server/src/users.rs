@@ -1,11 +1,102 @@
 mod user_db;
-pub use user_db::UserDb;
+pub use user_db::{
+    ApiTokenInfo, AuditEntry, AuditEvent, AuditOutcome, DatabaseOptions, DbError, QuotaOverride,
+    Role, SessionInfo, StorageKind, Usage, UserDb, UserInfo, UserStore,
+};
+
+mod sqlite_db;
+pub use sqlite_db::SqliteUserDb;
+#[cfg(test)]
+pub(crate) use sqlite_db::TemporaryDb;
+
+mod memory_db;
+pub use memory_db::MemoryUserDb;
+
+mod migrations;
 
 mod hashing;
-pub(self) use hashing::{hash_password, verify_password};
+pub use hashing::HashingOptions;
+pub(self) use hashing::{
+    hash_password, hash_password_blocking, needs_rehash, verify_password_blocking,
+};
 
 mod endpoints;
-pub use endpoints::{get_me, login, logout, register, LoginArgs, LoginResponse};
+pub use endpoints::{login, logout, register, LoginArgs, LoginResponse, RegisterArgs};
+
+mod profile;
+pub use profile::{get_me, MeResponse, UserProfile};
 
 mod auth;
-pub use auth::get_session_from_identity;
+pub use auth::{
+    get_session_from_identity, AdminUser, AuthError, AuthenticatedUser, Credentials, SessionUser,
+};
+
+mod cookies;
+pub use cookies::CookieOptions;
+
+mod tokens;
+pub use tokens::{create_token, list_tokens, revoke_token, ApiTokenResponse};
+pub(self) use tokens::{generate_token, hash_token};
+
+mod client_info;
+pub use client_info::ClientInfo;
+
+mod sessions;
+pub use sessions::{list_sessions, revoke_session, spawn_session_cleanup, SessionOptions};
+
+mod passwords;
+pub use passwords::{change_password, issue_password_reset, reset_password};
+
+mod throttling;
+pub(self) use throttling::{purge_login_failures, LoginThrottle};
+pub use throttling::ThrottleOptions;
+
+mod admin;
+pub use admin::{
+    create_admin, delete_user, disable_user, enable_user, list_lockouts, list_users, logout_user,
+    set_role,
+};
+
+mod registration;
+pub(self) use registration::RegistrationMode;
+pub use registration::{create_invite, list_invites, revoke_invite, RegistrationOptions};
+
+mod audit;
+pub use audit::{list_audit, record_audit, spawn_audit_pruning, AuditOptions};
+
+mod quotas;
+pub use quotas::{
+    exceeded_quota, get_usage, get_user_quotas, record_usage, reserve_request, reset_user_quotas,
+    set_user_quotas, QuotaOptions, UsageReport,
+};
+
+mod account;
+pub use account::{delete_account, export_account};
+
+mod transfer;
+pub use transfer::{export_users, import_users, ImportSummary};
+
+mod validation;
+pub use validation::ValidationOptions;
+pub(self) use validation::{normalize_login, FieldError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ts_rs::TS;
+
+    /// Write the TypeScript definitions of the responses to `client/src/bindings`, the committed
+    /// files have to be the same afterwards
+    #[test]
+    fn export_bindings() {
+        profile::AuthMethod::export().unwrap();
+        UserProfile::export().unwrap();
+        MeResponse::export().unwrap();
+        Role::export().unwrap();
+        Usage::export().unwrap();
+        QuotaOverride::export().unwrap();
+        quotas::QuotaLimits::export().unwrap();
+        quotas::PeriodUsage::export().unwrap();
+        UsageReport::export().unwrap();
+    }
+}
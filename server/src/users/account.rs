@@ -0,0 +1,268 @@
+use std::io::{Cursor, Write};
+
+use actix_identity::Identity;
+use actix_web::http::{header, StatusCode};
+use actix_web::web;
+use actix_web::HttpResponse;
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use super::audit::AuditEntryResponse;
+use super::sessions::session_public_id;
+use super::user_db::{AuditEntry, AuditEvent, AuditFilter, Role, Usage, UserDb};
+use super::{
+    record_audit, verify_password_blocking, ApiTokenResponse, AuthenticatedUser, ClientInfo,
+    LoginResponse, LoginThrottle, ThrottleOptions,
+};
+
+/// Number of audit entries read at once when exporting them
+const EXPORT_AUDIT_PAGE: u32 = 1000;
+
+#[derive(Deserialize)]
+pub struct DeleteAccountArgs {
+    password: String,
+}
+
+enum DeleteAccountOutcome {
+    Deleted,
+    InvalidPassword,
+    LastAdmin,
+    Locked(DateTime<Utc>),
+}
+
+async fn delete_account_core(
+    auth: &AuthenticatedUser,
+    user_db: &UserDb,
+    throttle_options: &ThrottleOptions,
+    client: &ClientInfo,
+    args: &DeleteAccountArgs,
+) -> AnyResult<DeleteAccountOutcome> {
+    // Guessing the password here is as good as guessing it on login, it's throttled the same way
+    let now = Utc::now();
+    let throttle = LoginThrottle::new(
+        user_db,
+        throttle_options,
+        &auth.user.login,
+        client.ip.as_deref(),
+    );
+    if let Some(locked_until) = throttle.locked_until(now).await? {
+        info!(%locked_until, "Account deletion while locked");
+        return Ok(DeleteAccountOutcome::Locked(locked_until));
+    }
+
+    let valid_password =
+        verify_password_blocking(args.password.clone(), auth.user.password_hash.clone()).await?;
+    if !valid_password {
+        throttle.record_failure(now).await?;
+        return Ok(DeleteAccountOutcome::InvalidPassword);
+    }
+    throttle.record_success().await?;
+
+    // Someone has to be left to manage the server
+    if auth.user.role == Role::Admin {
+        let admins = user_db
+            .list_users()
+            .await?
+            .iter()
+            .filter(|user| user.role == Role::Admin && !user.disabled)
+            .count();
+        if admins <= 1 {
+            return Ok(DeleteAccountOutcome::LastAdmin);
+        }
+    }
+
+    user_db.delete_user(auth.user.id).await?;
+
+    Ok(DeleteAccountOutcome::Deleted)
+}
+
+/// Delete the account of the user with their sessions and tokens, the password is asked again
+/// so that a stolen session isn't enough
+#[instrument(
+    name = "Delete Account",
+    skip(id, auth, args, user_db, throttle_options),
+    fields(user_id = auth.user.id)
+)]
+pub async fn delete_account(
+    id: Identity,
+    auth: AuthenticatedUser,
+    client: ClientInfo,
+    args: web::Json<DeleteAccountArgs>,
+    user_db: web::Data<UserDb>,
+    throttle_options: web::Data<ThrottleOptions>,
+) -> HttpResponse {
+    let outcome = delete_account_core(&auth, &user_db, &throttle_options, &client, &args).await;
+
+    let failure = match &outcome {
+        Ok(DeleteAccountOutcome::Deleted) => None,
+        Ok(DeleteAccountOutcome::InvalidPassword) => Some("Invalid password"),
+        Ok(DeleteAccountOutcome::LastAdmin) => Some("Last administrator"),
+        Ok(DeleteAccountOutcome::Locked(_)) => Some("Locked"),
+        Err(_) => Some("Internal error"),
+    };
+    // Once deleted, nothing identifies the user anymore
+    let entry = match failure {
+        Some(_) => AuditEntry {
+            actor_id: Some(auth.user.id),
+            actor_login: Some(auth.user.login.clone()),
+            ..AuditEntry::new(AuditEvent::DeleteAccount, &client, failure)
+        },
+        None => AuditEntry {
+            ip: None,
+            user_agent: None,
+            ..AuditEntry::new(AuditEvent::DeleteAccount, &client, failure)
+        },
+    };
+    record_audit(&user_db, entry).await;
+
+    match outcome {
+        Ok(DeleteAccountOutcome::Deleted) => {
+            info!("Deleted account");
+            id.forget();
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Ok(DeleteAccountOutcome::InvalidPassword) => {
+            HttpResponse::BadRequest().json(LoginResponse::error_display("Invalid password"))
+        }
+        Ok(DeleteAccountOutcome::LastAdmin) => HttpResponse::BadRequest().json(
+            LoginResponse::error_display("The last administrator can't delete their account"),
+        ),
+        Ok(DeleteAccountOutcome::Locked(locked_until)) => {
+            let retry_after = (locked_until - Utc::now()).num_seconds().max(1);
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(LoginResponse::error_display(
+                    "Too many failed attempts, try again later",
+                ))
+        }
+        Err(err) => {
+            error!(?err, "Failed to delete account");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedAccount {
+    id: i32,
+    login: String,
+    role: Role,
+    disabled: bool,
+}
+
+#[derive(Serialize)]
+struct ExportedSession {
+    id: String,
+    created_at: Option<DateTime<Utc>>,
+    last_seen_at: Option<DateTime<Utc>>,
+    valid_until: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportedUsage {
+    created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// ZIP archive of the JSON files describing everything stored about the user
+async fn export_account_core(auth: &AuthenticatedUser, user_db: &UserDb) -> AnyResult<Vec<u8>> {
+    let user_id = auth.user.id;
+    let account = ExportedAccount {
+        id: user_id,
+        login: auth.user.login.clone(),
+        role: auth.user.role,
+        disabled: auth.user.disabled,
+    };
+
+    let sessions = user_db
+        .list_sessions(user_id)
+        .await?
+        .into_iter()
+        .map(|session| ExportedSession {
+            id: session_public_id(&session.id),
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            valid_until: session.valid_until,
+            user_agent: session.user_agent,
+            ip: session.ip,
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = user_db
+        .list_api_tokens(user_id)
+        .await?
+        .into_iter()
+        .map(ApiTokenResponse::from)
+        .collect::<Vec<_>>();
+
+    let usage = user_db
+        .list_usage(user_id)
+        .await?
+        .into_iter()
+        .map(|record| ExportedUsage {
+            created_at: record.created_at,
+            usage: record.usage,
+        })
+        .collect::<Vec<_>>();
+
+    let mut audit = Vec::new();
+    let mut before_id = None;
+    loop {
+        let page = user_db
+            .list_audit_entries(AuditFilter {
+                actor_id: Some(user_id),
+                event: None,
+                outcome: None,
+                since: None,
+                until: None,
+                before_id,
+                limit: EXPORT_AUDIT_PAGE,
+            })
+            .await?;
+        let last_page = page.len() < EXPORT_AUDIT_PAGE as usize;
+        before_id = page.last().map(|record| record.id);
+        audit.extend(page.into_iter().map(AuditEntryResponse::from));
+        if last_page {
+            break;
+        }
+    }
+
+    let files = [
+        ("account.json", serde_json::to_vec_pretty(&account)?),
+        ("sessions.json", serde_json::to_vec_pretty(&sessions)?),
+        ("api_tokens.json", serde_json::to_vec_pretty(&tokens)?),
+        ("activity.json", serde_json::to_vec_pretty(&audit)?),
+        ("usage.json", serde_json::to_vec_pretty(&usage)?),
+    ];
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, bytes) in files {
+        archive.start_file(name, FileOptions::default())?;
+        archive.write_all(&bytes)?;
+    }
+
+    Ok(archive.finish()?.into_inner())
+}
+
+#[instrument(name = "Export Account", skip(auth, user_db), fields(user_id = auth.user.id))]
+pub async fn export_account(auth: AuthenticatedUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    match export_account_core(&auth, &user_db).await {
+        Ok(bytes) => HttpResponse::build(StatusCode::OK)
+            .content_type("application/zip")
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"mirnet-account.zip\"",
+            ))
+            .body(bytes),
+        Err(err) => {
+            error!(?err, "Failed to export account");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use anyhow::{anyhow, bail, Result as AnyResult};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument};
+
+use super::user_db::{AuditEntry, AuditEvent, LockoutInfo, Role, UserDb, UserInfo};
+use super::{
+    generate_token, hash_password, issue_password_reset, normalize_login, record_audit, AdminUser,
+    ClientInfo, HashingOptions, LoginResponse, ValidationOptions,
+};
+
+/// Number of lockouts returned for review
+const LOCKOUTS_LIMIT: u32 = 200;
+
+/// Give the admin role to a user, creating the account if needed.
+///
+/// Returns a password reset token when the account was created, so that its owner can choose
+/// their password.
+#[instrument(name = "Create Admin", skip(user_db, validation, hashing))]
+pub async fn create_admin(
+    user_db: &UserDb,
+    validation: &ValidationOptions,
+    hashing: &HashingOptions,
+    login: &str,
+) -> AnyResult<Option<String>> {
+    let login = normalize_login(login);
+    let (user, created) = match user_db.get_user_by_login(&login).await? {
+        Some(user) => (user, false),
+        None => {
+            let mut errors = Vec::new();
+            validation.validate_login(&login, &mut errors);
+            if let Some(error) = errors.first() {
+                bail!(error.message.clone());
+            }
+
+            // Nobody knows this password, it has to be reset before logging in
+            let password_hash =
+                hash_password(&generate_token(""), hashing).map_err(|e| anyhow!(e))?;
+            if !user_db.register(&login, &password_hash).await? {
+                bail!("Can't create user '{}'", login);
+            }
+            match user_db.get_user_by_login(&login).await? {
+                Some(user) => (user, true),
+                None => bail!("User '{}' disappeared after its creation", login),
+            }
+        }
+    };
+
+    user_db.set_user_role(user.id, Role::Admin).await?;
+    info!(user_id = user.id, created, "Granted admin role");
+
+    if created {
+        Ok(Some(issue_password_reset(user_db, &login).await?))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserResponse {
+    id: i32,
+    login: String,
+    role: Role,
+    disabled: bool,
+    invited_by: Option<i32>,
+}
+
+impl From<UserInfo> for UserResponse {
+    fn from(info: UserInfo) -> Self {
+        Self {
+            id: info.id,
+            login: info.login,
+            role: info.role,
+            disabled: info.disabled,
+            invited_by: info.invited_by,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LockoutResponse {
+    id: i64,
+    scope: String,
+    key: String,
+    failures: u32,
+    created_at: DateTime<Utc>,
+    locked_until: DateTime<Utc>,
+}
+
+impl From<LockoutInfo> for LockoutResponse {
+    fn from(info: LockoutInfo) -> Self {
+        Self {
+            id: info.id,
+            scope: info.scope,
+            key: info.key,
+            failures: info.failures,
+            created_at: info.created_at,
+            locked_until: info.locked_until,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleArgs {
+    role: Role,
+}
+
+/// Admins can't lock themselves out by mistake
+fn check_not_self(admin: &AdminUser, user_id: i32) -> Result<(), HttpResponse> {
+    if admin.0.user.id == user_id {
+        Err(
+            HttpResponse::BadRequest().json(LoginResponse::error_display(
+                "Administrators can't modify their own account",
+            )),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+/// Action of an administrator on another account, recorded in the audit log
+struct AdminAction<'a> {
+    admin: &'a AdminUser,
+    client: &'a ClientInfo,
+    event: AuditEvent,
+    /// Account and change, kept in the details of the entry
+    target: String,
+}
+
+impl AdminAction<'_> {
+    async fn record(&self, user_db: &UserDb, failure: Option<&str>) {
+        let details = match failure {
+            Some(failure) => format!("{}: {}", self.target, failure),
+            None => self.target.clone(),
+        };
+        record_audit(
+            user_db,
+            AuditEntry {
+                actor_id: Some(self.admin.0.user.id),
+                actor_login: Some(self.admin.0.user.login.clone()),
+                details: Some(details),
+                ..AuditEntry::new(self.event, self.client, failure)
+            },
+        )
+        .await;
+    }
+}
+
+/// Reason of the failure of an update of the account, for the audit log
+fn update_failure<E>(result: &Result<bool, E>) -> Option<&'static str> {
+    match result {
+        Ok(true) => None,
+        Ok(false) => Some("Unknown user"),
+        Err(_) => Some("Internal error"),
+    }
+}
+
+pub fn unknown_user() -> HttpResponse {
+    HttpResponse::NotFound().json(LoginResponse::error_display("Unknown user"))
+}
+
+#[instrument(name = "Admin List Users", skip(admin, user_db), fields(admin_id = admin.0.user.id))]
+pub async fn list_users(admin: AdminUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    match user_db.list_users().await {
+        Ok(users) => HttpResponse::Ok().json(
+            users
+                .into_iter()
+                .map(UserResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Admin Set Role",
+    skip(admin, client, args, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn set_role(
+    admin: AdminUser,
+    client: ClientInfo,
+    user_id: web::Path<i32>,
+    args: web::Json<SetRoleArgs>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    let action = AdminAction {
+        admin: &admin,
+        client: &client,
+        event: AuditEvent::ChangeRole,
+        target: format!("user {} role {}", user_id, args.role.as_str()),
+    };
+    if let Err(response) = check_not_self(&admin, user_id) {
+        action.record(&user_db, Some("Own account")).await;
+        return response;
+    }
+
+    let result = user_db.set_user_role(user_id, args.role).await;
+    action.record(&user_db, update_failure(&result)).await;
+    match result {
+        Ok(true) => {
+            info!(user_id, role = args.role.as_str(), "Changed user role");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Ok(false) => unknown_user(),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+async fn set_disabled(
+    admin: &AdminUser,
+    client: &ClientInfo,
+    user_id: i32,
+    disabled: bool,
+    user_db: &UserDb,
+) -> HttpResponse {
+    let action = AdminAction {
+        admin,
+        client,
+        event: if disabled {
+            AuditEvent::DisableUser
+        } else {
+            AuditEvent::EnableUser
+        },
+        target: format!("user {}", user_id),
+    };
+    if let Err(response) = check_not_self(admin, user_id) {
+        action.record(user_db, Some("Own account")).await;
+        return response;
+    }
+
+    let result = match user_db.set_user_disabled(user_id, disabled).await {
+        Ok(true) if disabled => user_db
+            .remove_user_sessions(user_id, None)
+            .await
+            .map(|_| true),
+        result => result,
+    };
+    action.record(user_db, update_failure(&result)).await;
+    match result {
+        Ok(true) => {
+            info!(user_id, disabled, "Changed user status");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Ok(false) => unknown_user(),
+        Err(err) => {
+            error!(?err, "Failed to change user status");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
+
+#[instrument(
+    name = "Admin Disable User",
+    skip(admin, client, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn disable_user(
+    admin: AdminUser,
+    client: ClientInfo,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    set_disabled(&admin, &client, user_id.into_inner(), true, &user_db).await
+}
+
+#[instrument(
+    name = "Admin Enable User",
+    skip(admin, client, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn enable_user(
+    admin: AdminUser,
+    client: ClientInfo,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    set_disabled(&admin, &client, user_id.into_inner(), false, &user_db).await
+}
+
+#[instrument(
+    name = "Admin Delete User",
+    skip(admin, client, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn delete_user(
+    admin: AdminUser,
+    client: ClientInfo,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    let action = AdminAction {
+        admin: &admin,
+        client: &client,
+        event: AuditEvent::DeleteUser,
+        target: format!("user {}", user_id),
+    };
+    if let Err(response) = check_not_self(&admin, user_id) {
+        action.record(&user_db, Some("Own account")).await;
+        return response;
+    }
+
+    let result = user_db.delete_user(user_id).await;
+    action.record(&user_db, update_failure(&result)).await;
+    match result {
+        Ok(true) => {
+            info!(user_id, "Deleted user");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Ok(false) => unknown_user(),
+        Err(err) => {
+            error!(?err, "Failed to delete user");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
+
+#[instrument(name = "Admin Logout User", skip(admin, user_db), fields(admin_id = admin.0.user.id))]
+pub async fn logout_user(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    match user_db.get_user_by_id(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unknown_user(),
+        Err(err) => return HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+
+    match user_db.remove_user_sessions(user_id, None).await {
+        Ok(removed) => {
+            info!(user_id, removed, "Forced logout");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Admin List Lockouts",
+    skip(admin, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn list_lockouts(admin: AdminUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    match user_db.list_lockouts(LOCKOUTS_LIMIT).await {
+        Ok(lockouts) => HttpResponse::Ok().json(
+            lockouts
+                .into_iter()
+                .map(LockoutResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
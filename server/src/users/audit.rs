@@ -0,0 +1,234 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tracing::{error, info, instrument};
+
+use super::user_db::{AuditEntry, AuditEvent, AuditFilter, AuditOutcome, AuditRecord, UserDb};
+use super::{AdminUser, ClientInfo, LoginResponse};
+
+/// Interval between two purges of the audit entries older than the retention
+const PRUNING_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// Number of audit entries returned when the admin doesn't choose one
+const DEFAULT_AUDIT_LIMIT: u32 = 100;
+
+const MAX_AUDIT_LIMIT: u32 = 1000;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct AuditOptions {
+    /// Days during which the audit log entries are kept, 0 keeps them forever
+    #[structopt(long, env = "MIRNET_AUDIT_RETENTION_DAYS", default_value = "90")]
+    pub audit_retention_days: u32,
+}
+
+impl AuditEntry {
+    /// Event caused by `client` now, `failure` is the reason of the failure of the event
+    pub fn new(event: AuditEvent, client: &ClientInfo, failure: Option<&str>) -> Self {
+        Self {
+            created_at: Utc::now(),
+            event,
+            actor_id: None,
+            actor_login: None,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            outcome: match failure {
+                Some(_) => AuditOutcome::Failure,
+                None => AuditOutcome::Success,
+            },
+            details: failure.map(str::to_owned),
+        }
+    }
+}
+
+/// Add an entry to the audit log, a failure is logged but doesn't fail the audited request
+pub async fn record_audit(user_db: &UserDb, entry: AuditEntry) {
+    let event = entry.event;
+    if let Err(err) = user_db.add_audit_entry(entry).await {
+        error!(?err, ?event, "Failed to record audit entry");
+    }
+}
+
+/// Periodically remove the audit entries older than the retention
+pub fn spawn_audit_pruning(user_db: UserDb, options: &AuditOptions) {
+    if options.audit_retention_days == 0 {
+        return;
+    }
+    let retention = Duration::days(options.audit_retention_days.into());
+
+    actix_web::rt::spawn(async move {
+        let period = std::time::Duration::from_secs(PRUNING_INTERVAL_SECONDS);
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            match user_db
+                .remove_audit_entries_before(Utc::now() - retention)
+                .await
+            {
+                Ok(removed) => info!(removed, "Pruned audit log"),
+                Err(err) => error!(?err, "Failed to prune audit log"),
+            }
+        }
+    });
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    actor_id: Option<i32>,
+    event: Option<AuditEvent>,
+    outcome: Option<AuditOutcome>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// `next_before_id` of the previous page
+    before_id: Option<i64>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct AuditEntryResponse {
+    id: i64,
+    created_at: DateTime<Utc>,
+    event: AuditEvent,
+    actor_id: Option<i32>,
+    actor_login: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    outcome: AuditOutcome,
+    details: Option<String>,
+}
+
+impl From<AuditRecord> for AuditEntryResponse {
+    fn from(record: AuditRecord) -> Self {
+        let entry = record.entry;
+        Self {
+            id: record.id,
+            created_at: entry.created_at,
+            event: entry.event,
+            actor_id: entry.actor_id,
+            actor_login: entry.actor_login,
+            ip: entry.ip,
+            user_agent: entry.user_agent,
+            outcome: entry.outcome,
+            details: entry.details,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditPageResponse {
+    entries: Vec<AuditEntryResponse>,
+    /// Value of `before_id` to get the next page, `None` on the last page
+    next_before_id: Option<i64>,
+}
+
+#[instrument(name = "Admin List Audit", skip(admin, user_db), fields(admin_id = admin.0.user.id))]
+pub async fn list_audit(
+    admin: AdminUser,
+    query: web::Query<AuditQuery>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    let filter = AuditFilter {
+        actor_id: query.actor_id,
+        event: query.event,
+        outcome: query.outcome,
+        since: query.since,
+        until: query.until,
+        before_id: query.before_id,
+        limit,
+    };
+
+    match user_db.list_audit_entries(filter).await {
+        Ok(records) => {
+            let next_before_id = match records.last() {
+                Some(last) if records.len() == limit as usize => Some(last.id),
+                _ => None,
+            };
+            HttpResponse::Ok().json(AuditPageResponse {
+                entries: records.into_iter().map(AuditEntryResponse::from).collect(),
+                next_before_id,
+            })
+        }
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{MemoryUserDb, TemporaryDb};
+    use std::sync::Arc;
+
+    async fn check_deleted_user_anonymized(user_db: &UserDb) {
+        let client = ClientInfo {
+            ip: Some("127.0.0.1".into()),
+            user_agent: Some("test".into()),
+        };
+        for login in ["alice", "bob"] {
+            assert!(user_db.register(login, "hash").await.unwrap());
+            let user = user_db.get_user_by_login(login).await.unwrap().unwrap();
+            // Registrations only know the login, logins know the user
+            for (event, actor_id) in [
+                (AuditEvent::Register, None),
+                (AuditEvent::Login, Some(user.id)),
+            ] {
+                user_db
+                    .add_audit_entry(AuditEntry {
+                        actor_id,
+                        actor_login: Some(login.into()),
+                        ..AuditEntry::new(event, &client, None)
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        let alice = user_db.get_user_by_login("alice").await.unwrap().unwrap();
+
+        assert!(user_db.delete_user(alice.id).await.unwrap());
+
+        let filter = AuditFilter {
+            actor_id: None,
+            event: None,
+            outcome: None,
+            since: None,
+            until: None,
+            before_id: None,
+            limit: 10,
+        };
+        let entries: Vec<_> = user_db
+            .list_audit_entries(filter)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|record| record.entry)
+            .collect();
+        assert_eq!(entries.len(), 4);
+        // The events of alice stay, without anything identifying the user
+        for entry in entries {
+            if entry.actor_login.as_deref() == Some("bob") {
+                assert_eq!(entry.ip, client.ip);
+            } else {
+                assert_eq!(entry.actor_id, None);
+                assert_eq!(entry.actor_login, None);
+                assert_eq!((entry.ip, entry.user_agent), (None, None));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn deleted_user_anonymized_memory() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        check_deleted_user_anonymized(&user_db).await;
+    }
+
+    #[actix_web::test]
+    async fn deleted_user_anonymized_sqlite() {
+        let db = TemporaryDb::new().await;
+        check_deleted_user_anonymized(&db.user_db).await;
+    }
+}
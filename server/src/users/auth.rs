@@ -1,20 +1,50 @@
-use actix_identity::Identity;
+use actix_identity::RequestIdentity;
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
 use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use thiserror::Error;
 use tracing::{error, info, instrument};
 
-use super::{user_db::SessionInfo, UserDb};
+use super::user_db::{ApiTokenInfo, DbError, Role, SessionInfo, UserInfo};
+use super::{hash_token, LoginResponse, SessionOptions, UserDb};
 
-#[instrument(name = "get_session_from_identity", skip(id, user_db))]
-pub async fn get_session_from_identity(id: &Identity, user_db: &UserDb) -> Option<SessionInfo> {
-    match id.identity() {
+#[instrument(name = "get_session_from_identity", skip(id, user_db, options))]
+pub async fn get_session_from_identity(
+    id: Option<String>,
+    user_db: &UserDb,
+    options: &SessionOptions,
+) -> Option<SessionInfo> {
+    match id {
         Some(id) => match user_db.get_session_by_id(&id).await {
-            Ok(info) => {
-                if Utc::now() > info.valid_until {
+            Ok(mut info) => {
+                let now = Utc::now();
+                if now > info.valid_until {
                     info!(%info.valid_until, %id, "Session expired");
-                    None
-                } else {
-                    Some(info)
+                    return None;
                 }
+
+                if options.needs_renewal(&info, now) {
+                    // Sessions created before their creation time was recorded are never extended
+                    let valid_until = match info.created_at {
+                        Some(created_at) => options.valid_until(created_at, now),
+                        None => info.valid_until,
+                    };
+                    match user_db.touch_session(&id, now, valid_until).await {
+                        Ok(()) => {
+                            info.last_seen_at = Some(now);
+                            info.valid_until = valid_until;
+                        }
+                        Err(err) => error!(?err, %id, "Failed to renew session"),
+                    }
+                }
+
+                Some(info)
+            }
+            Err(DbError::NotFound) => {
+                info!(%id, "Unknown session");
+                None
             }
             Err(err) => {
                 error!(?err, %id, "Failed to get session");
@@ -28,13 +58,177 @@ pub async fn get_session_from_identity(id: &Identity, user_db: &UserDb) -> Optio
     }
 }
 
-#[macro_export]
-macro_rules! authenticate {
-    ( $id:expr, $user_db:expr ) => {{
-        let result = $crate::users::get_session_from_identity($id, $user_db).await;
-        match result {
-            Some(session_info) => session_info,
-            None => return Ok(HttpResponse::Forbidden().finish()),
+/// Find the API token sent as `Authorization: Bearer <token>`, `None` if it's unknown or expired
+#[instrument(name = "get_api_token", skip(token, user_db))]
+pub async fn get_api_token(token: &str, user_db: &UserDb) -> Option<ApiTokenInfo> {
+    match user_db.get_api_token_by_hash(&hash_token(token)).await {
+        Ok(Some(info)) => {
+            let now = Utc::now();
+            if matches!(info.expires_at, Some(expires_at) if now > expires_at) {
+                info!(token_id = info.id, "API token expired");
+                return None;
+            }
+            if let Err(err) = user_db.set_api_token_last_used(info.id, now).await {
+                error!(
+                    ?err,
+                    token_id = info.id,
+                    "Failed to update API token last use"
+                );
+            }
+            Some(info)
+        }
+        Ok(None) => {
+            info!("Unknown API token");
+            None
+        }
+        Err(err) => {
+            error!(?err, "Failed to get API token");
+            None
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Authentication required")]
+    Unauthorized,
+
+    #[error("Invalid authorization header")]
+    InvalidHeader,
+
+    #[error("Insufficient rights")]
+    Forbidden,
+
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthorized | AuthError::InvalidHeader => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(LoginResponse::error_display(self))
+    }
+}
+
+/// How the user of a request was authenticated
+pub enum Credentials {
+    Session(SessionInfo),
+    ApiToken(ApiTokenInfo),
+}
+
+/// Extractor for the user of the current request, authenticated by an `Authorization: Bearer`
+/// API token or by the session cookie. Requests without valid credentials are rejected with a 401
+/// status
+pub struct AuthenticatedUser {
+    pub user: UserInfo,
+    pub credentials: Credentials,
+}
+
+/// The token of an `Authorization: Bearer <token>` header, `Ok(None)` if there is no header
+fn bearer_token(req: &HttpRequest) -> Result<Option<String>, AuthError> {
+    match req.headers().get(header::AUTHORIZATION) {
+        Some(value) => {
+            let value = value.to_str().map_err(|_| AuthError::InvalidHeader)?;
+            match value.strip_prefix("Bearer ") {
+                Some(token) => Ok(Some(token.trim().to_string())),
+                None => Err(AuthError::InvalidHeader),
+            }
         }
-    }};
+        None => Ok(None),
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let bearer_token = bearer_token(req);
+        let identity = req.get_identity();
+        let user_db = req.app_data::<web::Data<UserDb>>().cloned();
+        let session_options = req.app_data::<web::Data<SessionOptions>>().cloned();
+
+        Box::pin(async move {
+            let user_db = user_db
+                .ok_or_else(|| AuthError::Internal("User database not configured".into()))?;
+            let session_options = session_options
+                .ok_or_else(|| AuthError::Internal("Sessions not configured".into()))?;
+
+            // An explicit token takes precedence over the cookie and never falls back to it
+            let credentials = match bearer_token? {
+                Some(token) => get_api_token(&token, &user_db)
+                    .await
+                    .map(Credentials::ApiToken),
+                None => get_session_from_identity(identity, &user_db, &session_options)
+                    .await
+                    .map(Credentials::Session),
+            }
+            .ok_or(AuthError::Unauthorized)?;
+
+            let user_id = match &credentials {
+                Credentials::Session(session) => session.user_id,
+                Credentials::ApiToken(token) => token.user_id,
+            };
+            let user = user_db
+                .get_user_by_id(user_id)
+                .await
+                .map_err(|e| AuthError::Internal(format!("{:?}", e)))?
+                .ok_or(AuthError::Unauthorized)?;
+            if user.disabled {
+                info!(user_id, "Rejecting disabled user");
+                return Err(AuthError::Unauthorized);
+            }
+
+            Ok(AuthenticatedUser { user, credentials })
+        })
+    }
+}
+
+/// Extractor for an authenticated user with the admin role, others are rejected with a 403 status
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl FromRequest for AdminUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let authenticated = AuthenticatedUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let auth = authenticated.await?;
+            match auth.user.role {
+                Role::Admin => Ok(AdminUser(auth)),
+                Role::User => Err(AuthError::Forbidden),
+            }
+        })
+    }
+}
+
+/// Extractor for a user authenticated by the session cookie, requests made with an API token are
+/// rejected with a 403 status so that a leaked token can't be used to manage the tokens and the
+/// sessions of the user
+pub struct SessionUser(pub AuthenticatedUser);
+
+impl FromRequest for SessionUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let authenticated = AuthenticatedUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let auth = authenticated.await?;
+            match auth.credentials {
+                Credentials::Session(_) => Ok(SessionUser(auth)),
+                Credentials::ApiToken(_) => Err(AuthError::Forbidden),
+            }
+        })
+    }
 }
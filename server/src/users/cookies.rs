@@ -0,0 +1,167 @@
+use actix_identity::CookieIdentityPolicy;
+use actix_web::cookie::SameSite;
+use anyhow::{bail, Context, Result as AnyResult};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing::{info, warn};
+
+/// Size of generated keys, the cookie policy needs at least 32 bytes
+const GENERATED_KEY_LENGTH: usize = 64;
+const MIN_KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CookieSameSite(pub SameSite);
+
+impl FromStr for CookieSameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "strict" => Ok(CookieSameSite(SameSite::Strict)),
+            "lax" => Ok(CookieSameSite(SameSite::Lax)),
+            "none" => Ok(CookieSameSite(SameSite::None)),
+            _ => Err(format!(
+                "invalid SameSite value '{}', expected 'strict', 'lax' or 'none'",
+                s
+            )),
+        }
+    }
+}
+
+/// Configuration of the authentication cookie
+#[derive(Debug, Clone, StructOpt)]
+pub struct CookieOptions {
+    /// Hex encoded key used to sign the authentication cookie (at least 32 bytes)
+    #[structopt(long, env = "MIRNET_COOKIE_KEY", hide_env_values = true)]
+    pub cookie_key: Option<String>,
+
+    /// File containing the hex encoded cookie key, generated on first start if missing outside of
+    /// production mode
+    #[structopt(
+        long,
+        env = "MIRNET_COOKIE_KEY_FILE",
+        parse(from_os_str),
+        default_value = "cookie.key"
+    )]
+    pub cookie_key_file: PathBuf,
+
+    /// Refuse to start without a configured cookie key
+    #[structopt(long)]
+    pub production: bool,
+
+    /// Only send the authentication cookie over HTTPS
+    #[structopt(long)]
+    pub cookie_secure: bool,
+
+    /// SameSite attribute of the authentication cookie: "strict", "lax" or "none"
+    #[structopt(long, env = "MIRNET_COOKIE_SAME_SITE", default_value = "lax")]
+    pub cookie_same_site: CookieSameSite,
+
+    /// Domain attribute of the authentication cookie
+    #[structopt(long, env = "MIRNET_COOKIE_DOMAIN")]
+    pub cookie_domain: Option<String>,
+
+    /// Path attribute of the authentication cookie
+    #[structopt(long, env = "MIRNET_COOKIE_PATH", default_value = "/")]
+    pub cookie_path: String,
+
+    /// Max-Age of the authentication cookie in seconds, a session cookie is used if not set
+    #[structopt(long, env = "MIRNET_COOKIE_MAX_AGE")]
+    pub cookie_max_age: Option<i64>,
+}
+
+fn decode_key(hex_key: &str, source: &str) -> AnyResult<Vec<u8>> {
+    let key = hex::decode(hex_key.trim()).with_context(|| format!("Invalid {}", source))?;
+    if key.len() < MIN_KEY_LENGTH {
+        bail!(
+            "The {} is {} bytes long, at least {} are required",
+            source,
+            key.len(),
+            MIN_KEY_LENGTH
+        );
+    }
+
+    Ok(key)
+}
+
+fn generate_key(path: &Path) -> AnyResult<Vec<u8>> {
+    let mut key = vec![0u8; GENERATED_KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+
+    // Created with restricted permissions from the start, and never replacing an existing key
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(hex::encode(&key).as_bytes()))
+        .with_context(|| format!("Failed to write cookie key to {:?}", path))?;
+
+    warn!(?path, "Generated a new cookie key");
+
+    Ok(key)
+}
+
+impl CookieOptions {
+    /// Load the cookie key from the options, the key file or generate and persist a new one
+    pub fn load_key(&self) -> AnyResult<Vec<u8>> {
+        if let Some(key) = &self.cookie_key {
+            info!("Using cookie key from the command line or environment");
+            return decode_key(key, "cookie key");
+        }
+
+        let path = &self.cookie_key_file;
+        if path.exists() {
+            info!(?path, "Using cookie key file");
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read cookie key file {:?}", path))?;
+            return decode_key(&content, "cookie key file");
+        }
+
+        if self.production {
+            bail!(
+                "No cookie key configured, set MIRNET_COOKIE_KEY or create {:?} in production mode",
+                path
+            );
+        }
+
+        generate_key(path)
+    }
+
+    /// Check the options for problems that should prevent the server from starting
+    pub fn validate(&self) -> AnyResult<()> {
+        if self.production && !self.cookie_secure {
+            warn!("Authentication cookies will be sent over plain HTTP, use --cookie-secure");
+        }
+        if matches!(self.cookie_same_site.0, SameSite::None) && !self.cookie_secure {
+            bail!("SameSite=None cookies must be secure, use --cookie-secure");
+        }
+
+        Ok(())
+    }
+
+    pub fn identity_policy(&self, key: &[u8]) -> CookieIdentityPolicy {
+        let mut policy = CookieIdentityPolicy::new(key)
+            .name("auth-cookie")
+            .path(self.cookie_path.clone())
+            .secure(self.cookie_secure)
+            .http_only(true)
+            .same_site(self.cookie_same_site.0);
+
+        if let Some(domain) = &self.cookie_domain {
+            policy = policy.domain(domain.clone());
+        }
+        if let Some(max_age) = self.cookie_max_age {
+            policy = policy.max_age_secs(max_age);
+        }
+
+        policy
+    }
+}
@@ -1,19 +1,23 @@
 use std::fmt::{Debug, Display};
 
 use crate::users::user_db::UserDb;
-use crate::users::{hash_password, verify_password};
+use crate::users::{hash_password_blocking, needs_rehash, verify_password_blocking};
 use actix_identity::Identity;
+use actix_web::http::header;
 use actix_web::web;
 use actix_web::HttpResponse;
-use anyhow::anyhow;
 use anyhow::Result as AnyResult;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, instrument};
 use uuid::Uuid;
 
-use super::get_session_from_identity;
-use super::user_db::SessionInfo;
+use super::user_db::{AuditEntry, AuditEvent, InviteRegistration};
+use super::{
+    hash_token, normalize_login, record_audit, ClientInfo, FieldError, HashingOptions,
+    LoginThrottle, RegistrationMode, RegistrationOptions, SessionOptions, ThrottleOptions,
+    ValidationOptions,
+};
 
 #[derive(Deserialize)]
 pub struct LoginArgs {
@@ -25,6 +29,8 @@ pub struct LoginArgs {
 pub struct LoginResponse {
     success: bool,
     error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
 }
 
 impl LoginResponse {
@@ -32,6 +38,7 @@ impl LoginResponse {
         LoginResponse {
             success: true,
             error: None,
+            fields: Vec::new(),
         }
     }
 
@@ -39,6 +46,7 @@ impl LoginResponse {
         LoginResponse {
             success: false,
             error: Some(format!("{:?}", error)),
+            fields: Vec::new(),
         }
     }
 
@@ -46,129 +54,827 @@ impl LoginResponse {
         LoginResponse {
             success: false,
             error: Some(format!("{}", error)),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn invalid_fields(fields: Vec<FieldError>) -> LoginResponse {
+        LoginResponse {
+            success: false,
+            error: Some("Invalid fields".into()),
+            fields,
         }
     }
 }
 
-async fn register_account_core(user_db: &UserDb, login: &str, password: &str) -> AnyResult<bool> {
-    let password: String = password.into();
-    let password_hash =
-        web::block(move || hash_password(&password).map_err(|e| anyhow!(e))).await??;
+#[derive(Deserialize)]
+pub struct RegisterArgs {
+    login: String,
+    password: String,
+    /// Code required when registration is invite-only
+    invite: Option<String>,
+}
+
+enum RegisterOutcome {
+    Registered,
+    Invalid(Vec<FieldError>),
+    AlreadyExists,
+    Closed,
+    InvalidInvite,
+}
+
+async fn register_account_core(
+    user_db: &UserDb,
+    options: &RegistrationOptions,
+    validation: &ValidationOptions,
+    hashing: &HashingOptions,
+    login: &str,
+    password: &str,
+    invite: Option<&str>,
+) -> AnyResult<RegisterOutcome> {
+    let login = normalize_login(login);
+    let mut errors = Vec::new();
+    validation.validate_login(&login, &mut errors);
+    validation.validate_password("password", password, Some(&login), &mut errors);
+
+    let invite_hash = match (options.registration, invite) {
+        (RegistrationMode::Closed, _) => return Ok(RegisterOutcome::Closed),
+        (RegistrationMode::Invite, None) => return Ok(RegisterOutcome::InvalidInvite),
+        (RegistrationMode::Invite, Some(invite)) => Some(hash_token(invite.trim())),
+        (RegistrationMode::Open, _) => None,
+    };
+    if !errors.is_empty() {
+        return Ok(RegisterOutcome::Invalid(errors));
+    }
+
+    let password_hash = hash_password_blocking(password.into(), hashing.clone()).await?;
 
     debug!(%password_hash, "Generated password hash");
 
-    Ok(user_db.register(login, &password_hash).await?)
+    match invite_hash {
+        Some(invite_hash) => Ok(
+            match user_db
+                .register_with_invite(&login, &password_hash, &invite_hash, Utc::now())
+                .await?
+            {
+                InviteRegistration::Registered => RegisterOutcome::Registered,
+                InviteRegistration::AlreadyExists => RegisterOutcome::AlreadyExists,
+                InviteRegistration::InvalidInvite => RegisterOutcome::InvalidInvite,
+            },
+        ),
+        None => Ok(if user_db.register(&login, &password_hash).await? {
+            RegisterOutcome::Registered
+        } else {
+            RegisterOutcome::AlreadyExists
+        }),
+    }
 }
 
 #[instrument(
     name = "User Register",
-    skip(args, user_db),
+    skip(args, user_db, options, validation, hashing),
     fields(
         login = %args.login,
     )
     )]
-pub async fn register(args: web::Json<LoginArgs>, user_db: web::Data<UserDb>) -> HttpResponse {
-    match register_account_core(&user_db, &args.login, &args.password).await {
-        Ok(success) => {
-            if success {
-                HttpResponse::Ok().json(LoginResponse::ok())
-            } else {
-                HttpResponse::BadRequest()
-                    .json(LoginResponse::error_display("Account already exists"))
-            }
+pub async fn register(
+    client: ClientInfo,
+    args: web::Json<RegisterArgs>,
+    user_db: web::Data<UserDb>,
+    options: web::Data<RegistrationOptions>,
+    validation: web::Data<ValidationOptions>,
+    hashing: web::Data<HashingOptions>,
+) -> HttpResponse {
+    let outcome = register_account_core(
+        &user_db,
+        &options,
+        &validation,
+        &hashing,
+        &args.login,
+        &args.password,
+        args.invite.as_deref(),
+    )
+    .await;
+
+    let failure = match &outcome {
+        Ok(RegisterOutcome::Registered) => None,
+        Ok(RegisterOutcome::Invalid(_)) => Some("Invalid fields"),
+        Ok(RegisterOutcome::AlreadyExists) => Some("Account already exists"),
+        Ok(RegisterOutcome::Closed) => Some("Registration is closed"),
+        Ok(RegisterOutcome::InvalidInvite) => Some("Invalid invite"),
+        Err(_) => Some("Internal error"),
+    };
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_login: Some(normalize_login(&args.login)),
+            ..AuditEntry::new(AuditEvent::Register, &client, failure)
+        },
+    )
+    .await;
+
+    match outcome {
+        Ok(RegisterOutcome::Registered) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(RegisterOutcome::Invalid(errors)) => {
+            HttpResponse::BadRequest().json(LoginResponse::invalid_fields(errors))
         }
+        Ok(RegisterOutcome::AlreadyExists) => {
+            HttpResponse::BadRequest().json(LoginResponse::error_display("Account already exists"))
+        }
+        Ok(RegisterOutcome::Closed) => {
+            HttpResponse::Forbidden().json(LoginResponse::error_display("Registration is closed"))
+        }
+        Ok(RegisterOutcome::InvalidInvite) => HttpResponse::Forbidden().json(
+            LoginResponse::error_display("A valid invite code is required"),
+        ),
         Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
     }
 }
 
+/// Message of all the login failures, doesn't tell whether the account exists
+const INVALID_CREDENTIALS: &str = "Invalid login or password";
+
+enum LoginOutcome {
+    LoggedIn(i32),
+    InvalidCredentials,
+    Disabled(i32),
+    Locked(DateTime<Utc>),
+}
+
 async fn login_core(
     id: Identity,
     user_db: &UserDb,
+    session_options: &SessionOptions,
+    throttle_options: &ThrottleOptions,
+    hashing: &HashingOptions,
+    client: &ClientInfo,
     login: &str,
     password: &str,
-) -> AnyResult<LoginResponse> {
-    let user_info = user_db.get_user_by_login(login).await?;
+) -> AnyResult<LoginOutcome> {
+    let now = Utc::now();
+    let login = normalize_login(login);
+    let throttle = LoginThrottle::new(user_db, throttle_options, &login, client.ip.as_deref());
+    if let Some(locked_until) = throttle.locked_until(now).await? {
+        info!(%locked_until, "Login attempt while locked");
+        return Ok(LoginOutcome::Locked(locked_until));
+    }
+
+    let user_info = user_db.get_user_by_login(&login).await?;
+    let valid_password = match &user_info {
+        Some(info) => {
+            verify_password_blocking(password.to_owned(), info.password_hash.clone()).await?
+        }
+        None => {
+            // Spend as much time as a real verification so that logins can't be enumerated
+            hash_password_blocking(password.to_owned(), hashing.clone()).await?;
+            false
+        }
+    };
     let user_info = match user_info {
-        Some(info) => info,
-        None => return Ok(LoginResponse::error_display("Unknown user")),
+        Some(info) if valid_password => info,
+        _ => {
+            throttle.record_failure(now).await?;
+            return Ok(LoginOutcome::InvalidCredentials);
+        }
     };
+    throttle.record_success().await?;
+    if user_info.disabled {
+        info!(user_id = user_info.id, "Login of a disabled account");
+        return Ok(LoginOutcome::Disabled(user_info.id));
+    }
 
-    let valid_password =
-        verify_password(password, &user_info.password_hash).map_err(|e| anyhow!(e))?;
-    if !valid_password {
-        return Ok(LoginResponse::error_display("Invalid password"));
+    // The password is only known now, upgrade hashes made with an older policy
+    if needs_rehash(&user_info.password_hash, hashing) {
+        match hash_password_blocking(password.to_owned(), hashing.clone()).await {
+            Ok(password_hash) => {
+                user_db
+                    .set_password_hash(user_info.id, &password_hash)
+                    .await?;
+                info!(user_id = user_info.id, "Upgraded password hash");
+            }
+            Err(err) => error!(?err, "Failed to upgrade password hash"),
+        }
     }
 
     let session_id = Uuid::new_v4().to_string();
-    let valid_until = Utc::now() + Duration::days(2);
+    let valid_until = session_options.valid_until(now, now);
     user_db
-        .create_session(&session_id, user_info.id, valid_until)
+        .create_session(
+            &session_id,
+            user_info.id,
+            valid_until,
+            now,
+            client.user_agent.as_deref(),
+            client.ip.as_deref(),
+        )
         .await?;
 
     id.remember(session_id);
 
-    Ok(LoginResponse::ok())
+    Ok(LoginOutcome::LoggedIn(user_info.id))
 }
 
 #[instrument(
     name = "User Login",
-    skip(id, args, user_db),
+    skip(id, args, user_db, session_options, throttle_options, hashing),
     fields(
         login = %args.login,
     )
     )]
 pub async fn login(
     id: Identity,
+    client: ClientInfo,
     args: web::Json<LoginArgs>,
     user_db: web::Data<UserDb>,
+    session_options: web::Data<SessionOptions>,
+    throttle_options: web::Data<ThrottleOptions>,
+    hashing: web::Data<HashingOptions>,
 ) -> HttpResponse {
-    match login_core(id, &user_db, &args.login, &args.password).await {
-        Ok(response) => HttpResponse::Ok().json(response),
+    let outcome = login_core(
+        id,
+        &user_db,
+        &session_options,
+        &throttle_options,
+        &hashing,
+        &client,
+        &args.login,
+        &args.password,
+    )
+    .await;
+
+    let (actor_id, failure) = match &outcome {
+        Ok(LoginOutcome::LoggedIn(user_id)) => (Some(*user_id), None),
+        Ok(LoginOutcome::InvalidCredentials) => (None, Some("Invalid credentials")),
+        Ok(LoginOutcome::Disabled(user_id)) => (Some(*user_id), Some("Account disabled")),
+        Ok(LoginOutcome::Locked(_)) => (None, Some("Locked")),
+        Err(_) => (None, Some("Internal error")),
+    };
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_id,
+            actor_login: Some(normalize_login(&args.login)),
+            ..AuditEntry::new(AuditEvent::Login, &client, failure)
+        },
+    )
+    .await;
+
+    match outcome {
+        Ok(LoginOutcome::LoggedIn(_)) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(LoginOutcome::InvalidCredentials) => {
+            HttpResponse::Ok().json(LoginResponse::error_display(INVALID_CREDENTIALS))
+        }
+        Ok(LoginOutcome::Disabled(_)) => {
+            HttpResponse::Forbidden().json(LoginResponse::error_display("Account disabled"))
+        }
+        Ok(LoginOutcome::Locked(locked_until)) => {
+            let retry_after = (locked_until - Utc::now()).num_seconds().max(1);
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(LoginResponse::error_display(
+                    "Too many failed logins, try again later",
+                ))
+        }
         Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
     }
 }
 
 #[instrument(name = "User Logout", skip(id, user_db))]
-pub async fn logout(id: Identity, user_db: web::Data<UserDb>) -> HttpResponse {
+pub async fn logout(id: Identity, client: ClientInfo, user_db: web::Data<UserDb>) -> HttpResponse {
     if let Some(id) = id.identity() {
+        let actor_id = user_db.get_session_by_id(&id).await.ok().map(|s| s.user_id);
+
         info!(%id, "Removing session");
-        if let Err(error) = user_db.remove_session(&id).await {
+        let result = user_db.remove_session(&id).await;
+        if let Err(error) = &result {
             error!(?error, "Failed to remove session");
         }
+
+        let failure = result.err().map(|_| "Failed to remove session");
+        record_audit(
+            &user_db,
+            AuditEntry {
+                actor_id,
+                ..AuditEntry::new(AuditEvent::Logout, &client, failure)
+            },
+        )
+        .await;
     }
     id.forget();
 
     HttpResponse::Ok().finish()
 }
 
-#[derive(Serialize)]
-pub struct SessionInfoResponse {
-    pub user_id: i32,
-    pub valid_until: DateTime<Utc>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::sessions::session_public_id;
+    use crate::users::user_db::AuditFilter;
+    use crate::users::{
+        change_password, delete_user, disable_user, list_sessions, revoke_session, set_role,
+        AuditEntry, AuditEvent, AuditOutcome, DbError, MemoryUserDb, Role, UserStore,
+    };
+    use actix_identity::{CookieIdentityPolicy, IdentityService};
+    use actix_web::cookie::Cookie;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use structopt::StructOpt;
+
+    const PASSWORD: &str = "correct horse 42";
 
-impl From<SessionInfo> for SessionInfoResponse {
-    fn from(session_info: SessionInfo) -> Self {
-        Self {
-            user_id: session_info.user_id,
-            valid_until: session_info.valid_until,
+    /// Options with their default values
+    fn options<T: StructOpt>() -> web::Data<T> {
+        web::Data::new(T::from_iter(&["test"]))
+    }
+
+    /// The authentication endpoints on top of `user_db`
+    macro_rules! test_app {
+        ($user_db:expr) => {
+            test::init_service(
+                App::new()
+                    .wrap(IdentityService::new(
+                        CookieIdentityPolicy::new(&[0; 32])
+                            .name("auth-cookie")
+                            .secure(false),
+                    ))
+                    .route("/api/register", web::post().to(register))
+                    .route("/api/login", web::post().to(login))
+                    .route("/api/logout", web::post().to(logout))
+                    .route("/api/me/sessions", web::get().to(list_sessions))
+                    .route("/api/me/sessions/{id}", web::delete().to(revoke_session))
+                    .route("/api/me/password", web::post().to(change_password))
+                    .route("/api/admin/users/{id}", web::delete().to(delete_user))
+                    .route("/api/admin/users/{id}/role", web::post().to(set_role))
+                    .route(
+                        "/api/admin/users/{id}/disable",
+                        web::post().to(disable_user),
+                    )
+                    .app_data(web::Data::new($user_db.clone()))
+                    .app_data(options::<SessionOptions>())
+                    .app_data(options::<ThrottleOptions>())
+                    .app_data(options::<RegistrationOptions>())
+                    .app_data(options::<ValidationOptions>())
+                    .app_data(options::<HashingOptions>()),
+            )
+            .await
+        };
+    }
+
+    fn post_json(uri: &str, body: Value) -> test::TestRequest {
+        test::TestRequest::post().uri(uri).set_json(&body)
+    }
+
+    /// Audit entries of `event`, the newest first
+    async fn audit_entries(user_db: &UserDb, event: AuditEvent) -> Vec<AuditEntry> {
+        let filter = AuditFilter {
+            actor_id: None,
+            event: Some(event),
+            outcome: None,
+            since: None,
+            until: None,
+            before_id: None,
+            limit: 100,
+        };
+        user_db
+            .list_audit_entries(filter)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|record| record.entry)
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn register_login_and_logout() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "Alice", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let user = user_db.get_user_by_login("alice").await.unwrap().unwrap();
+
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/login",
+                json!({ "login": "ALICE", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(Cookie::into_owned)
+            .expect("No session cookie");
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["success"], true);
+
+        let sessions = user_db.list_sessions(user.id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = user_db.get_session_by_id(&sessions[0].id).await.unwrap();
+        assert_eq!(session.user_id, user.id);
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/me/sessions")
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body[0]["current"], true);
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/api/logout")
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(user_db.list_sessions(user.id).await.unwrap().is_empty());
+        assert!(matches!(
+            user_db.get_session_by_id(&session.id).await,
+            Err(DbError::NotFound)
+        ));
+
+        // The cookie of the closed session isn't accepted anymore
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/me/sessions")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn register_existing_login() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+
+        for (login, status) in [("bob", StatusCode::OK), ("Bob", StatusCode::BAD_REQUEST)] {
+            let response = test::call_service(
+                &app,
+                post_json(
+                    "/api/register",
+                    json!({ "login": login, "password": PASSWORD }),
+                )
+                .to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), status);
         }
     }
-}
 
-#[derive(Serialize)]
-pub struct GetMeResponse {
-    session: Option<SessionInfoResponse>,
-}
+    #[actix_web::test]
+    async fn register_invalid_fields() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
 
-#[instrument(name = "User Logout", skip(id, user_db))]
-pub async fn get_me(id: Identity, user_db: web::Data<UserDb>) -> HttpResponse {
-    let session = get_session_from_identity(&id, &user_db).await;
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "c", "password": "short" }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert!(!body["fields"].as_array().unwrap().is_empty());
+        assert!(user_db.get_user_by_login("c").await.unwrap().is_none());
+    }
 
-    match session {
-        Some(session) => HttpResponse::Ok().json(GetMeResponse {
-            session: Some(session.into()),
-        }),
-        None => HttpResponse::Ok().json(GetMeResponse { session: None }),
+    #[actix_web::test]
+    async fn login_with_invalid_credentials() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+
+        test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "carol", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let user = user_db.get_user_by_login("carol").await.unwrap().unwrap();
+
+        for (login, password) in [("carol", "wrong password 1"), ("dave", PASSWORD)] {
+            let response = test::call_service(
+                &app,
+                post_json(
+                    "/api/login",
+                    json!({ "login": login, "password": password }),
+                )
+                .to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.response().cookies().next().is_none());
+            let body: Value = test::read_body_json(response).await;
+            assert_eq!(body["success"], false);
+            assert_eq!(body["error"], INVALID_CREDENTIALS);
+        }
+
+        assert!(user_db.list_sessions(user.id).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn sessions_require_cookie() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+
+        test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "erin", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/login",
+                json!({ "login": "erin", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(Cookie::into_owned)
+            .expect("No session cookie");
+        let user = user_db.get_user_by_login("erin").await.unwrap().unwrap();
+        let sessions = user_db.list_sessions(user.id).await.unwrap();
+        let public_id = session_public_id(&sessions[0].id);
+
+        let token = "mirnet_test";
+        user_db
+            .create_api_token(user.id, "script", &hash_token(token), Utc::now(), None)
+            .await
+            .unwrap();
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", token));
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/me/sessions")
+                .insert_header(bearer.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri(&format!("/api/me/sessions/{}", public_id))
+                .insert_header(bearer)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(user_db.list_sessions(user.id).await.unwrap().len(), 1);
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri(&format!("/api/me/sessions/{}", public_id))
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(user_db.list_sessions(user.id).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn change_password_locked_out() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+        let max_failures = ThrottleOptions::from_iter(&["test"]).login_max_failures;
+
+        test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "frank", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/login",
+                json!({ "login": "frank", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(Cookie::into_owned)
+            .expect("No session cookie");
+        let user = user_db.get_user_by_login("frank").await.unwrap().unwrap();
+        let change = |current_password: &str| {
+            post_json(
+                "/api/me/password",
+                json!({
+                    "current_password": current_password,
+                    "new_password": "another horse 43",
+                }),
+            )
+            .cookie(cookie.clone())
+            .to_request()
+        };
+
+        for _ in 0..max_failures {
+            let response = test::call_service(&app, change("wrong password 1")).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Locked, even with the right password
+        let response = test::call_service(&app, change(PASSWORD)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let unchanged = user_db.get_user_by_id(user.id).await.unwrap().unwrap();
+        assert_eq!(unchanged.password_hash, user.password_hash);
+    }
+
+    #[actix_web::test]
+    async fn change_password_resets_failures() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+        let max_failures = ThrottleOptions::from_iter(&["test"]).login_max_failures;
+
+        test::call_service(
+            &app,
+            post_json(
+                "/api/register",
+                json!({ "login": "grace", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/login",
+                json!({ "login": "grace", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(Cookie::into_owned)
+            .expect("No session cookie");
+        let change = |current_password: &str, new_password: &str| {
+            post_json(
+                "/api/me/password",
+                json!({
+                    "current_password": current_password,
+                    "new_password": new_password,
+                }),
+            )
+            .cookie(cookie.clone())
+            .to_request()
+        };
+
+        for _ in 1..max_failures {
+            let response = test::call_service(&app, change("wrong password 1", PASSWORD)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = test::call_service(&app, change(PASSWORD, "another horse 43")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The failures before the success are forgotten
+        let response =
+            test::call_service(&app, change("wrong password 1", "another horse 44")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&app, change("another horse 43", PASSWORD)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let user = user_db.get_user_by_login("grace").await.unwrap().unwrap();
+        let entries = audit_entries(&user_db, AuditEvent::ChangePassword).await;
+        assert_eq!(entries.len(), max_failures as usize + 2);
+        assert!(entries.iter().all(|entry| entry.actor_id == Some(user.id)));
+        let outcomes: Vec<_> = entries.iter().take(2).map(|entry| entry.outcome).collect();
+        assert_eq!(outcomes, [AuditOutcome::Success, AuditOutcome::Failure]);
+    }
+
+    #[actix_web::test]
+    async fn admin_actions_audited() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let app = test_app!(user_db);
+        for login in ["admin", "henry"] {
+            test::call_service(
+                &app,
+                post_json(
+                    "/api/register",
+                    json!({ "login": login, "password": PASSWORD }),
+                )
+                .to_request(),
+            )
+            .await;
+        }
+        let admin = user_db.get_user_by_login("admin").await.unwrap().unwrap();
+        let user = user_db.get_user_by_login("henry").await.unwrap().unwrap();
+        user_db.set_user_role(admin.id, Role::Admin).await.unwrap();
+        let response = test::call_service(
+            &app,
+            post_json(
+                "/api/login",
+                json!({ "login": "admin", "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(Cookie::into_owned)
+            .expect("No session cookie");
+
+        let requests = [
+            post_json(
+                &format!("/api/admin/users/{}/role", user.id),
+                json!({ "role": "admin" }),
+            ),
+            post_json(&format!("/api/admin/users/{}/disable", user.id), json!({})),
+            test::TestRequest::delete().uri(&format!("/api/admin/users/{}", user.id)),
+            // Failures are recorded too
+            test::TestRequest::delete().uri(&format!("/api/admin/users/{}", admin.id)),
+        ];
+        for request in requests {
+            test::call_service(&app, request.cookie(cookie.clone()).to_request()).await;
+        }
+
+        let role = audit_entries(&user_db, AuditEvent::ChangeRole).await;
+        assert_eq!(role.len(), 1);
+        assert_eq!(role[0].actor_id, Some(admin.id));
+        assert_eq!(role[0].outcome, AuditOutcome::Success);
+        assert_eq!(
+            role[0].details.as_deref(),
+            Some(format!("user {} role admin", user.id).as_str())
+        );
+        let disable = audit_entries(&user_db, AuditEvent::DisableUser).await;
+        assert_eq!(disable.len(), 1);
+        assert_eq!(disable[0].actor_id, Some(admin.id));
+        let delete = audit_entries(&user_db, AuditEvent::DeleteUser).await;
+        let outcomes: Vec<_> = delete
+            .iter()
+            .map(|entry| (entry.actor_id, entry.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (Some(admin.id), AuditOutcome::Failure),
+                (Some(admin.id), AuditOutcome::Success)
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn unknown_session() {
+        let user_db = MemoryUserDb::default();
+
+        assert!(matches!(
+            user_db.get_session_by_id("unknown").await,
+            Err(DbError::NotFound)
+        ));
     }
 }
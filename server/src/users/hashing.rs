@@ -1,19 +1,67 @@
+use actix_web::web;
+use anyhow::{anyhow, Result as AnyResult};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
+use structopt::StructOpt;
+
+/// Cost of the argon2id hashes of new passwords, older hashes are upgraded on login
+#[derive(Debug, Clone, StructOpt)]
+pub struct HashingOptions {
+    /// Memory used by argon2 to hash a password, in KiB
+    #[structopt(long, env = "MIRNET_ARGON2_MEMORY_KIB", default_value = "4096")]
+    pub argon2_memory_kib: u32,
+
+    /// Number of passes of argon2 over the memory
+    #[structopt(long, env = "MIRNET_ARGON2_ITERATIONS", default_value = "3")]
+    pub argon2_iterations: u32,
+
+    /// Number of lanes of argon2
+    #[structopt(long, env = "MIRNET_ARGON2_PARALLELISM", default_value = "1")]
+    pub argon2_parallelism: u32,
+}
+
+impl HashingOptions {
+    fn params(&self) -> argon2::Result<Params> {
+        Params::new(
+            self.argon2_memory_kib,
+            self.argon2_iterations,
+            self.argon2_parallelism,
+            None,
+        )
+    }
+
+    /// Fail early if argon2 doesn't accept the parameters
+    pub fn validate(&self) -> AnyResult<()> {
+        self.params()
+            .map(|_| ())
+            .map_err(|e| anyhow!("Invalid argon2 parameters: {}", e))
+    }
+}
 
 /// Hash a password to a PHC string using argon2
-pub fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+pub fn hash_password(
+    password: &str,
+    options: &HashingOptions,
+) -> argon2::password_hash::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, options.params()?);
 
     Ok(argon2
         .hash_password(password.as_bytes(), &salt)?
         .to_string())
 }
 
-/// Hash a password to a PHC string using argon2
+/// Hash a password outside of the async executor, argon2 is slow on purpose
+pub async fn hash_password_blocking(
+    password: String,
+    options: HashingOptions,
+) -> AnyResult<String> {
+    web::block(move || hash_password(&password, &options).map_err(|e| anyhow!(e))).await?
+}
+
+/// Verify a password against a PHC string, using the parameters stored in it
 pub fn verify_password(password: &str, password_hash: &str) -> argon2::password_hash::Result<bool> {
     let argon2 = Argon2::default();
 
@@ -23,3 +71,30 @@ pub fn verify_password(password: &str, password_hash: &str) -> argon2::password_
         Err(_) => Ok(false),
     }
 }
+
+/// Verify a password outside of the async executor
+pub async fn verify_password_blocking(password: String, password_hash: String) -> AnyResult<bool> {
+    web::block(move || verify_password(&password, &password_hash).map_err(|e| anyhow!(e))).await?
+}
+
+/// Whether a stored hash is weaker than what `hash_password` would produce now
+pub fn needs_rehash(password_hash: &str, options: &HashingOptions) -> bool {
+    let password_hash = match PasswordHash::new(password_hash) {
+        Ok(password_hash) => password_hash,
+        Err(_) => return true,
+    };
+    if password_hash.algorithm != Algorithm::Argon2id.ident()
+        || password_hash.version != Some(Version::V0x13 as u32)
+    {
+        return true;
+    }
+
+    match Params::try_from(&password_hash) {
+        Ok(params) => {
+            params.m_cost() < options.argon2_memory_kib
+                || params.t_cost() < options.argon2_iterations
+                || params.p_cost() < options.argon2_parallelism
+        }
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,677 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use anyhow::{bail, Result as AnyResult};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::quotas::QuotaLimits;
+use super::user_db::{
+    ApiTokenInfo, AuditEntry, AuditFilter, AuditRecord, DbError, InviteInfo, InviteRegistration,
+    LockoutInfo, LoginFailures, QuotaOverride, Role, SessionInfo, Usage, UsageRecord,
+    UsageReservation, UserInfo, UserStore,
+};
+
+#[derive(Default)]
+struct MemoryState {
+    last_user_id: i32,
+    last_id: i64,
+    users: Vec<UserInfo>,
+    sessions: HashMap<String, SessionInfo>,
+    /// Tokens with their hash
+    api_tokens: Vec<(String, ApiTokenInfo)>,
+    /// User and expiration of the password reset tokens, by hash
+    password_resets: HashMap<String, (i32, DateTime<Utc>)>,
+    login_failures: HashMap<(String, String), LoginFailures>,
+    lockouts: Vec<LockoutInfo>,
+    /// Invites with the hash of their code
+    invites: Vec<(String, InviteInfo)>,
+    audit_log: Vec<AuditRecord>,
+    usage: Vec<UsageRecord>,
+    quota_overrides: HashMap<i32, QuotaOverride>,
+}
+
+impl MemoryState {
+    fn next_id(&mut self) -> i64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn user_mut(&mut self, id: i32) -> Option<&mut UserInfo> {
+        self.users.iter_mut().find(|user| user.id == id)
+    }
+
+    fn insert_user(
+        &mut self,
+        login: &str,
+        password_hash: &str,
+        created_at: DateTime<Utc>,
+    ) -> &mut UserInfo {
+        self.last_user_id += 1;
+        self.users.push(UserInfo {
+            id: self.last_user_id,
+            login: login.into(),
+            password_hash: password_hash.into(),
+            role: Role::User,
+            disabled: false,
+            created_at: Some(created_at),
+            invited_by: None,
+        });
+
+        self.users.last_mut().unwrap()
+    }
+
+    fn user_exists(&self, login: &str) -> bool {
+        self.users.iter().any(|user| user.login == login)
+    }
+
+    fn sum_usage(&self, user_id: i32, since: DateTime<Utc>) -> Usage {
+        let mut total = Usage::default();
+        for record in &self.usage {
+            if record.user_id == user_id && record.created_at >= since {
+                total += record.usage;
+            }
+        }
+
+        total
+    }
+}
+
+/// Storage kept in memory, for tests and ephemeral deployments
+#[derive(Default)]
+pub struct MemoryUserDb {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryUserDb {
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // No operation can panic halfway through a change, a poisoned state is still consistent
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserDb {
+    async fn initialize(&self) -> AnyResult<()> {
+        Ok(())
+    }
+
+    async fn backup(&self, _destination: PathBuf) -> AnyResult<()> {
+        bail!("The memory storage isn't kept anywhere, there is nothing to back up")
+    }
+
+    async fn register(&self, login: &str, password_hash: &str) -> Result<bool, DbError> {
+        let mut state = self.state();
+        if state.user_exists(login) {
+            return Ok(false);
+        }
+        state.insert_user(login, password_hash, Utc::now());
+
+        Ok(true)
+    }
+
+    async fn import_user(
+        &self,
+        login: &str,
+        password_hash: &str,
+        role: Role,
+        disabled: bool,
+        invited_by: Option<i32>,
+    ) -> Result<bool, DbError> {
+        let mut state = self.state();
+        if state.user_exists(login) {
+            return Ok(false);
+        }
+        let user = state.insert_user(login, password_hash, Utc::now());
+        user.role = role;
+        user.disabled = disabled;
+        user.invited_by = invited_by;
+
+        Ok(true)
+    }
+
+    async fn register_with_invite(
+        &self,
+        login: &str,
+        password_hash: &str,
+        code_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<InviteRegistration, DbError> {
+        let mut state = self.state();
+        let invite = state.invites.iter().position(|(hash, invite)| {
+            hash == code_hash && invite.expires_at >= now && invite.uses < invite.max_uses
+        });
+        let invite = match invite {
+            Some(invite) => invite,
+            None => return Ok(InviteRegistration::InvalidInvite),
+        };
+        if state.user_exists(login) {
+            return Ok(InviteRegistration::AlreadyExists);
+        }
+
+        let invited_by = state.invites[invite].1.created_by;
+        state.insert_user(login, password_hash, now).invited_by = Some(invited_by);
+        state.invites[invite].1.uses += 1;
+
+        Ok(InviteRegistration::Registered)
+    }
+
+    async fn get_user_by_login(&self, login: &str) -> Result<Option<UserInfo>, DbError> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.login == login)
+            .cloned())
+    }
+
+    async fn get_user_by_id(&self, id: i32) -> Result<Option<UserInfo>, DbError> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.id == id)
+            .cloned())
+    }
+
+    async fn list_users(&self) -> Result<Vec<UserInfo>, DbError> {
+        Ok(self.state().users.clone())
+    }
+
+    async fn set_user_role(&self, id: i32, role: Role) -> Result<bool, DbError> {
+        Ok(self
+            .state()
+            .user_mut(id)
+            .map(|user| user.role = role)
+            .is_some())
+    }
+
+    async fn set_user_disabled(&self, id: i32, disabled: bool) -> Result<bool, DbError> {
+        Ok(self
+            .state()
+            .user_mut(id)
+            .map(|user| user.disabled = disabled)
+            .is_some())
+    }
+
+    async fn delete_user(&self, id: i32) -> Result<bool, DbError> {
+        let mut state = self.state();
+        let login = state
+            .users
+            .iter()
+            .find(|user| user.id == id)
+            .map(|user| user.login.clone());
+        for record in &mut state.audit_log {
+            let entry = &mut record.entry;
+            if entry.actor_id == Some(id) || (login.is_some() && entry.actor_login == login) {
+                entry.actor_id = None;
+                entry.actor_login = None;
+                entry.ip = None;
+                entry.user_agent = None;
+            }
+        }
+        state.sessions.retain(|_, session| session.user_id != id);
+        state.api_tokens.retain(|(_, token)| token.user_id != id);
+        state
+            .password_resets
+            .retain(|_, (user_id, _)| *user_id != id);
+        state.usage.retain(|record| record.user_id != id);
+        state.quota_overrides.remove(&id);
+
+        let count = state.users.len();
+        state.users.retain(|user| user.id != id);
+
+        Ok(state.users.len() < count)
+    }
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), DbError> {
+        if let Some(user) = self.state().user_mut(user_id) {
+            user.password_hash = password_hash.into();
+        }
+
+        Ok(())
+    }
+
+    async fn create_session(
+        &self,
+        id: &str,
+        user_id: i32,
+        valid_until: DateTime<Utc>,
+        created_at: DateTime<Utc>,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<(), DbError> {
+        self.state().sessions.insert(
+            id.into(),
+            SessionInfo {
+                id: id.into(),
+                user_id,
+                valid_until,
+                created_at: Some(created_at),
+                last_seen_at: Some(created_at),
+                user_agent: user_agent.map(str::to_owned),
+                ip: ip.map(str::to_owned),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn touch_session(
+        &self,
+        id: &str,
+        last_seen_at: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        if let Some(session) = self.state().sessions.get_mut(id) {
+            session.last_seen_at = Some(last_seen_at);
+            session.valid_until = valid_until;
+        }
+
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>, DbError> {
+        let mut sessions = self
+            .state()
+            .sessions
+            .values()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by(|a, b| b.last_seen_at.cmp(&a.last_seen_at));
+
+        Ok(sessions)
+    }
+
+    async fn remove_expired_sessions(&self, now: DateTime<Utc>) -> Result<usize, DbError> {
+        let mut state = self.state();
+        let count = state.sessions.len();
+        state
+            .sessions
+            .retain(|_, session| session.valid_until >= now);
+
+        Ok(count - state.sessions.len())
+    }
+
+    async fn remove_session(&self, id: &str) -> Result<(), DbError> {
+        self.state().sessions.remove(id);
+
+        Ok(())
+    }
+
+    async fn remove_user_sessions(
+        &self,
+        user_id: i32,
+        except: Option<&str>,
+    ) -> Result<usize, DbError> {
+        let mut state = self.state();
+        let count = state.sessions.len();
+        state
+            .sessions
+            .retain(|id, session| session.user_id != user_id || Some(id.as_str()) == except);
+
+        Ok(count - state.sessions.len())
+    }
+
+    async fn get_session_by_id(&self, id: &str) -> Result<SessionInfo, DbError> {
+        self.state()
+            .sessions
+            .get(id)
+            .cloned()
+            .ok_or(DbError::NotFound)
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiTokenInfo, DbError> {
+        let mut state = self.state();
+        let info = ApiTokenInfo {
+            id: state.next_id(),
+            user_id,
+            name: name.into(),
+            created_at,
+            expires_at,
+            last_used_at: None,
+        };
+        state.api_tokens.push((token_hash.into(), info.clone()));
+
+        Ok(info)
+    }
+
+    async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiTokenInfo>, DbError> {
+        Ok(self
+            .state()
+            .api_tokens
+            .iter()
+            .filter(|(_, token)| token.user_id == user_id)
+            .map(|(_, token)| token.clone())
+            .collect())
+    }
+
+    async fn remove_api_token(&self, user_id: i32, id: i64) -> Result<bool, DbError> {
+        let mut state = self.state();
+        let count = state.api_tokens.len();
+        state
+            .api_tokens
+            .retain(|(_, token)| token.id != id || token.user_id != user_id);
+
+        Ok(state.api_tokens.len() < count)
+    }
+
+    async fn get_api_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiTokenInfo>, DbError> {
+        Ok(self
+            .state()
+            .api_tokens
+            .iter()
+            .find(|(hash, _)| hash == token_hash)
+            .map(|(_, token)| token.clone()))
+    }
+
+    async fn set_api_token_last_used(
+        &self,
+        id: i64,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let mut state = self.state();
+        if let Some((_, token)) = state
+            .api_tokens
+            .iter_mut()
+            .find(|(_, token)| token.id == id)
+        {
+            token.last_used_at = Some(last_used_at);
+        }
+
+        Ok(())
+    }
+
+    async fn create_password_reset(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        _created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let mut state = self.state();
+        state.password_resets.retain(|_, (id, _)| *id != user_id);
+        state
+            .password_resets
+            .insert(token_hash.into(), (user_id, expires_at));
+
+        Ok(())
+    }
+
+    async fn consume_password_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i32>, DbError> {
+        Ok(match self.state().password_resets.remove(token_hash) {
+            Some((user_id, expires_at)) if now <= expires_at => Some(user_id),
+            _ => None,
+        })
+    }
+
+    async fn get_login_failures(
+        &self,
+        scope: &str,
+        key: &str,
+    ) -> Result<Option<LoginFailures>, DbError> {
+        Ok(self
+            .state()
+            .login_failures
+            .get(&(scope.to_owned(), key.to_owned()))
+            .cloned())
+    }
+
+    async fn add_login_failure(
+        &self,
+        scope: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        forget_before: DateTime<Utc>,
+    ) -> Result<u32, DbError> {
+        let mut state = self.state();
+        let failures = state
+            .login_failures
+            .entry((scope.into(), key.into()))
+            .or_insert(LoginFailures {
+                failures: 0,
+                last_failure_at: now,
+                locked_until: None,
+            });
+        if failures.last_failure_at < forget_before {
+            failures.failures = 0;
+            failures.locked_until = None;
+        }
+        failures.failures += 1;
+        failures.last_failure_at = now;
+
+        Ok(failures.failures)
+    }
+
+    async fn lock_login(
+        &self,
+        scope: &str,
+        key: &str,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        if let Some(failures) = self
+            .state()
+            .login_failures
+            .get_mut(&(scope.to_owned(), key.to_owned()))
+        {
+            failures.locked_until = failures.locked_until.max(Some(locked_until));
+        }
+
+        Ok(())
+    }
+
+    async fn clear_login_failures(&self, scope: &str, key: &str) -> Result<(), DbError> {
+        self.state()
+            .login_failures
+            .remove(&(scope.to_owned(), key.to_owned()));
+
+        Ok(())
+    }
+
+    async fn remove_expired_login_failures(
+        &self,
+        forget_before: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<usize, DbError> {
+        let mut state = self.state();
+        let count = state.login_failures.len();
+        state.login_failures.retain(|_, failures| {
+            failures.last_failure_at >= forget_before
+                || matches!(failures.locked_until, Some(locked_until) if locked_until > now)
+        });
+
+        Ok(count - state.login_failures.len())
+    }
+
+    async fn record_lockout(
+        &self,
+        scope: &str,
+        key: &str,
+        failures: u32,
+        created_at: DateTime<Utc>,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.lockouts.push(LockoutInfo {
+            id,
+            scope: scope.into(),
+            key: key.into(),
+            failures,
+            created_at,
+            locked_until,
+        });
+
+        Ok(())
+    }
+
+    async fn list_lockouts(&self, limit: u32) -> Result<Vec<LockoutInfo>, DbError> {
+        Ok(self
+            .state()
+            .lockouts
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_old_lockouts(&self, keep: u32) -> Result<usize, DbError> {
+        let mut state = self.state();
+        let removed = state.lockouts.len().saturating_sub(keep as usize);
+        state.lockouts.drain(..removed);
+
+        Ok(removed)
+    }
+
+    async fn create_invite(
+        &self,
+        code_hash: &str,
+        created_by: i32,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        max_uses: u32,
+    ) -> Result<InviteInfo, DbError> {
+        let mut state = self.state();
+        let info = InviteInfo {
+            id: state.next_id(),
+            created_by,
+            created_at,
+            expires_at,
+            max_uses,
+            uses: 0,
+        };
+        state.invites.push((code_hash.into(), info.clone()));
+
+        Ok(info)
+    }
+
+    async fn list_invites(&self) -> Result<Vec<InviteInfo>, DbError> {
+        Ok(self
+            .state()
+            .invites
+            .iter()
+            .map(|(_, invite)| invite.clone())
+            .collect())
+    }
+
+    async fn remove_invite(&self, id: i64) -> Result<bool, DbError> {
+        let mut state = self.state();
+        let count = state.invites.len();
+        state.invites.retain(|(_, invite)| invite.id != id);
+
+        Ok(state.invites.len() < count)
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<(), DbError> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.audit_log.push(AuditRecord { id, entry });
+
+        Ok(())
+    }
+
+    async fn list_audit_entries(&self, filter: AuditFilter) -> Result<Vec<AuditRecord>, DbError> {
+        Ok(self
+            .state()
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|record| {
+                let entry = &record.entry;
+                (filter.actor_id.is_none() || entry.actor_id == filter.actor_id)
+                    && (filter.event.is_none() || Some(entry.event) == filter.event)
+                    && (filter.outcome.is_none() || Some(entry.outcome) == filter.outcome)
+                    && !matches!(filter.since, Some(since) if entry.created_at < since)
+                    && !matches!(filter.until, Some(until) if entry.created_at >= until)
+                    && !matches!(filter.before_id, Some(id) if record.id >= id)
+            })
+            .take(filter.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_audit_entries_before(&self, before: DateTime<Utc>) -> Result<usize, DbError> {
+        let mut state = self.state();
+        let count = state.audit_log.len();
+        state
+            .audit_log
+            .retain(|record| record.entry.created_at >= before);
+
+        Ok(count - state.audit_log.len())
+    }
+
+    async fn add_usage(&self, record: UsageRecord) -> Result<(), DbError> {
+        self.state().usage.push(record);
+
+        Ok(())
+    }
+
+    async fn reserve_usage(
+        &self,
+        record: UsageRecord,
+        quotas: Vec<(DateTime<Utc>, QuotaLimits)>,
+    ) -> Result<UsageReservation, DbError> {
+        let mut state = self.state();
+        for (quota, (since, limits)) in quotas.iter().enumerate() {
+            if let Some(resource) = limits.exhausted(&state.sum_usage(record.user_id, *since)) {
+                return Ok(UsageReservation::Exhausted { quota, resource });
+            }
+        }
+        state.usage.push(record);
+
+        Ok(UsageReservation::Reserved)
+    }
+
+    async fn sum_usage(&self, user_id: i32, since: DateTime<Utc>) -> Result<Usage, DbError> {
+        Ok(self.state().sum_usage(user_id, since))
+    }
+
+    async fn list_usage(&self, user_id: i32) -> Result<Vec<UsageRecord>, DbError> {
+        Ok(self
+            .state()
+            .usage
+            .iter()
+            .filter(|record| record.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_quota_override(&self, user_id: i32) -> Result<Option<QuotaOverride>, DbError> {
+        Ok(self.state().quota_overrides.get(&user_id).cloned())
+    }
+
+    async fn set_quota_override(
+        &self,
+        user_id: i32,
+        quotas: QuotaOverride,
+    ) -> Result<bool, DbError> {
+        let mut state = self.state();
+        if state.user_mut(user_id).is_none() {
+            return Ok(false);
+        }
+        state.quota_overrides.insert(user_id, quotas);
+
+        Ok(true)
+    }
+
+    async fn remove_quota_override(&self, user_id: i32) -> Result<bool, DbError> {
+        Ok(self.state().quota_overrides.remove(&user_id).is_some())
+    }
+}
@@ -0,0 +1,609 @@
+use anyhow::{bail, Result as AnyResult};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use tracing::{info, warn};
+
+/// A change of the schema, applied once to each database in the order of the versions
+struct Migration {
+    version: u32,
+    name: &'static str,
+    up: fn(&Connection) -> Result<(), rusqlite::Error>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        name: "sessions_text_id",
+        up: sessions_text_id,
+    },
+    Migration {
+        version: 3,
+        name: "normalize_logins",
+        up: normalize_logins,
+    },
+    Migration {
+        version: 4,
+        name: "user_id_indices",
+        up: user_id_indices,
+    },
+    Migration {
+        version: 5,
+        name: "integer_timestamps",
+        up: integer_timestamps,
+    },
+    Migration {
+        version: 6,
+        name: "audit_log",
+        up: audit_log,
+    },
+    Migration {
+        version: 7,
+        name: "user_created_at",
+        up: user_created_at,
+    },
+    Migration {
+        version: 8,
+        name: "usage_quotas",
+        up: usage_quotas,
+    },
+    Migration {
+        version: 9,
+        name: "audit_log_anonymization",
+        up: audit_log_anonymization,
+    },
+];
+
+/// Columns holding times, as 64-bit seconds since the epoch
+const TIME_COLUMNS: &[(&str, &str)] = &[
+    ("sessions", "valid_until"),
+    ("sessions", "created_at"),
+    ("sessions", "last_seen_at"),
+    ("api_tokens", "created_at"),
+    ("api_tokens", "expires_at"),
+    ("api_tokens", "last_used_at"),
+    ("login_failures", "last_failure_at"),
+    ("login_failures", "locked_until"),
+    ("lockouts", "created_at"),
+    ("lockouts", "locked_until"),
+    ("invites", "created_at"),
+    ("invites", "expires_at"),
+    ("password_resets", "created_at"),
+    ("password_resets", "expires_at"),
+];
+
+/// Add a column to an existing table, databases created before the column existed are upgraded
+fn add_column_if_missing(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = statement
+        .query_map([], |r| r.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        info!(table, column, "Adding missing column");
+        connection.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The schema as it was created before the migrations, databases of that time already have some of
+/// these tables and columns
+fn initial_schema(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "create table if not exists users (
+             id integer primary key,
+             login text not null unique,
+             password_hash text not null
+         )",
+        [],
+    )?;
+    add_column_if_missing(connection, "users", "role", "text not null default 'user'")?;
+    add_column_if_missing(
+        connection,
+        "users",
+        "disabled",
+        "integer not null default 0",
+    )?;
+    add_column_if_missing(connection, "users", "invited_by", "integer")?;
+
+    connection.execute(
+        "create table if not exists sessions (
+             id string primary key,
+             user_id integer not null,
+             valid_until integer not null
+         )",
+        [],
+    )?;
+    add_column_if_missing(connection, "sessions", "created_at", "integer")?;
+    add_column_if_missing(connection, "sessions", "last_seen_at", "integer")?;
+    add_column_if_missing(connection, "sessions", "user_agent", "text")?;
+    add_column_if_missing(connection, "sessions", "ip", "text")?;
+
+    connection.execute(
+        "create table if not exists api_tokens (
+             id integer primary key,
+             user_id integer not null,
+             name text not null,
+             token_hash text not null unique,
+             created_at integer not null,
+             expires_at integer,
+             last_used_at integer
+         )",
+        [],
+    )?;
+
+    connection.execute(
+        "create table if not exists login_failures (
+             scope text not null,
+             key text not null,
+             failures integer not null,
+             last_failure_at integer not null,
+             locked_until integer,
+             primary key (scope, key)
+         )",
+        [],
+    )?;
+
+    connection.execute(
+        "create table if not exists lockouts (
+             id integer primary key,
+             scope text not null,
+             key text not null,
+             failures integer not null,
+             created_at integer not null,
+             locked_until integer not null
+         )",
+        [],
+    )?;
+
+    connection.execute(
+        "create table if not exists invites (
+             id integer primary key,
+             code_hash text not null unique,
+             created_by integer not null,
+             created_at integer not null,
+             expires_at integer not null,
+             max_uses integer not null,
+             uses integer not null default 0
+         )",
+        [],
+    )?;
+
+    connection.execute(
+        "create table if not exists password_resets (
+             token_hash text primary key,
+             user_id integer not null,
+             created_at integer not null,
+             expires_at integer not null
+         )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// `string` isn't an SQLite type, it gives the column a numeric affinity. SQLite can't change the
+/// type of a column so the table is rebuilt
+fn sessions_text_id(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
+        "create table sessions_new (
+             id text not null primary key,
+             user_id integer not null,
+             valid_until integer not null,
+             created_at integer,
+             last_seen_at integer,
+             user_agent text,
+             ip text
+         );
+         insert into sessions_new
+             select cast(id as text), user_id, valid_until, created_at, last_seen_at,
+                    user_agent, ip
+             from sessions;
+         drop table sessions;
+         alter table sessions_new rename to sessions;",
+    )
+}
+
+/// Store the logins created before they were normalized in lowercase, unless two accounts only
+/// differ by their case
+fn normalize_logins(connection: &Connection) -> Result<(), rusqlite::Error> {
+    let updated = connection.execute(
+        "UPDATE users SET login=lower(trim(login))
+         WHERE login<>lower(trim(login))
+         AND NOT EXISTS (SELECT 1 FROM users other WHERE other.login=lower(trim(users.login)))",
+        [],
+    )?;
+    if updated > 0 {
+        info!(updated, "Normalized logins");
+    }
+
+    let conflicts: i64 = connection.query_row(
+        "SELECT COUNT(*) FROM users WHERE login<>lower(trim(login))",
+        [],
+        |r| r.get(0),
+    )?;
+    if conflicts > 0 {
+        warn!(
+            conflicts,
+            "Some logins only differ by their case and can't be normalized"
+        );
+    }
+
+    Ok(())
+}
+
+fn user_id_indices(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
+        "create index if not exists sessions_user_id on sessions(user_id);
+         create index if not exists api_tokens_user_id on api_tokens(user_id);
+         create index if not exists password_resets_user_id on password_resets(user_id);",
+    )
+}
+
+/// Whether a column of an existing table was declared `not null`
+fn is_not_null(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+) -> Result<bool, rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let not_null = statement
+        .query_map([], |r| Ok((r.get::<_, String>(1)?, r.get::<_, bool>(3)?)))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .any(|(name, not_null)| name == column && not_null);
+
+    Ok(not_null)
+}
+
+/// Times used to be read as 32-bit values, make sure that all of them are stored as integers so
+/// that they read back the same whatever their range. Text values, written by hand, are parsed as
+/// numbers of seconds or as ISO-8601 dates. Those that can't be parsed become NULL, or the epoch in
+/// `not null` columns so that the sessions and tokens they belong to are expired
+fn integer_timestamps(connection: &Connection) -> Result<(), rusqlite::Error> {
+    for (table, column) in TIME_COLUMNS {
+        let fallback = if is_not_null(connection, table, column)? {
+            "0"
+        } else {
+            "NULL"
+        };
+        let is_integer = format!(
+            "({column} GLOB '[0-9]*' OR {column} GLOB '-[0-9]*')
+             AND substr({column}, 2) NOT GLOB '*[^0-9]*'",
+            column = column
+        );
+
+        let invalid: u32 = connection.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {table}
+                 WHERE typeof({column}) = 'text' AND NOT ({is_integer})
+                     AND strftime('%s', {column}) IS NULL",
+                table = table,
+                column = column,
+                is_integer = is_integer
+            ),
+            [],
+            |r| r.get(0),
+        )?;
+        if invalid > 0 {
+            warn!(table, column, invalid, fallback, "Unreadable times");
+        }
+
+        let updated = connection.execute(
+            &format!(
+                "UPDATE {table} SET {column} = CASE
+                     WHEN typeof({column}) = 'text' AND ({is_integer})
+                         THEN CAST({column} AS INTEGER)
+                     WHEN typeof({column}) = 'text'
+                         THEN COALESCE(CAST(strftime('%s', {column}) AS INTEGER), {fallback})
+                     ELSE CAST({column} AS INTEGER)
+                 END
+                 WHERE typeof({column}) NOT IN ('integer', 'null')",
+                table = table,
+                column = column,
+                is_integer = is_integer,
+                fallback = fallback
+            ),
+            [],
+        )?;
+        if updated > 0 {
+            info!(table, column, updated, "Converted times to integers");
+        }
+    }
+
+    Ok(())
+}
+
+/// Security relevant events, entries are only removed once older than the retention
+fn audit_log(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
+        "create table audit_log (
+             id integer primary key,
+             created_at integer not null,
+             event text not null,
+             actor_id integer,
+             actor_login text,
+             ip text,
+             user_agent text,
+             outcome text not null,
+             details text
+         );
+         create index audit_log_created_at on audit_log(created_at);
+         create index audit_log_actor_id on audit_log(actor_id);
+         create trigger audit_log_append_only before update on audit_log
+         begin
+             select raise(abort, 'audit_log is append-only');
+         end;",
+    )
+}
+
+/// Creation time of the accounts, unknown for the ones created before this migration
+fn user_created_at(connection: &Connection) -> Result<(), rusqlite::Error> {
+    add_column_if_missing(connection, "users", "created_at", "integer")
+}
+
+/// Resources used by each processing request, and the quotas set by administrators for some users
+fn usage_quotas(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
+        "create table usage (
+             id integer primary key,
+             user_id integer not null,
+             created_at integer not null,
+             requests integer not null,
+             megapixels real not null,
+             compute_seconds real not null
+         );
+         create index usage_user_id_created_at on usage(user_id, created_at);
+         create table user_quotas (
+             user_id integer primary key,
+             daily_requests integer,
+             daily_megapixels real,
+             daily_compute_seconds real,
+             monthly_requests integer,
+             monthly_megapixels real,
+             monthly_compute_seconds real
+         );",
+    )
+}
+
+/// The entries of a deleted user lose the fields identifying them, any other update still fails
+fn audit_log_anonymization(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
+        "drop trigger audit_log_append_only;
+         create trigger audit_log_append_only before update on audit_log
+         when not (new.actor_id is null and new.actor_login is null and new.ip is null
+             and new.user_agent is null and new.id = old.id and new.created_at = old.created_at
+             and new.event = old.event and new.outcome = old.outcome
+             and new.details is old.details)
+         begin
+             select raise(abort, 'audit_log is append-only');
+         end;",
+    )
+}
+
+fn create_version_table(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "create table if not exists schema_migrations (
+             version integer primary key,
+             name text not null,
+             applied_at integer not null
+         )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Migrations that weren't applied to the database yet
+fn pending_migrations(connection: &Connection) -> AnyResult<&'static [Migration]> {
+    let version: u32 = connection.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |r| r.get(0),
+    )?;
+
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if version > latest {
+        bail!(
+            "Database schema version {} is newer than the latest known version {}",
+            version,
+            latest
+        );
+    }
+
+    let applied = MIGRATIONS
+        .iter()
+        .take_while(|m| m.version <= version)
+        .count();
+    Ok(&MIGRATIONS[applied..])
+}
+
+fn apply_migration(connection: &Connection, migration: &Migration) -> AnyResult<()> {
+    (migration.up)(connection)?;
+    connection.execute(
+        "INSERT INTO schema_migrations(version, name, applied_at) VALUES (?1, ?2, ?3)",
+        params![migration.version, migration.name, Utc::now().timestamp()],
+    )?;
+
+    Ok(())
+}
+
+/// Apply the pending migrations, each one in its own transaction
+pub fn run_migrations(connection: &mut Connection) -> AnyResult<()> {
+    create_version_table(connection)?;
+
+    for migration in pending_migrations(connection)? {
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "Applying migration"
+        );
+        let transaction = connection.transaction()?;
+        apply_migration(&transaction, migration)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Apply the pending migrations in a transaction that is rolled back, and return their names
+pub fn check_migrations(connection: &mut Connection) -> AnyResult<Vec<String>> {
+    let transaction = connection.transaction()?;
+    create_version_table(&transaction)?;
+
+    let pending = pending_migrations(&transaction)?;
+    for migration in pending {
+        apply_migration(&transaction, migration)?;
+    }
+    transaction.rollback()?;
+
+    Ok(pending
+        .iter()
+        .map(|m| format!("{:04}_{}", m.version, m.name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rusqlite::ToSql;
+
+    /// A database with the schema of the versions before `integer_timestamps`
+    fn database_before_integer_timestamps() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        create_version_table(&connection).unwrap();
+        for migration in MIGRATIONS
+            .iter()
+            .take_while(|m| m.name != "integer_timestamps")
+        {
+            apply_migration(&connection, migration).unwrap();
+        }
+
+        connection
+    }
+
+    /// Insert a session with the given times and return them once all the migrations are applied
+    fn migrate_session_times(
+        valid_until: &dyn ToSql,
+        created_at: &dyn ToSql,
+    ) -> (Option<i64>, Option<i64>) {
+        let mut connection = database_before_integer_timestamps();
+        connection
+            .execute(
+                "INSERT INTO sessions(id, user_id, valid_until, created_at)
+                 VALUES ('s', 1, ?1, ?2)",
+                params![valid_until, created_at],
+            )
+            .unwrap();
+
+        run_migrations(&mut connection).unwrap();
+
+        connection
+            .query_row(
+                "SELECT valid_until, created_at FROM sessions WHERE id='s'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn integer_times_are_kept() {
+        let far_future = Utc.ymd(9999, 12, 31).and_hms(23, 59, 59).timestamp();
+        let pre_epoch = Utc.ymd(1901, 1, 1).and_hms(0, 0, 0).timestamp();
+
+        assert_eq!(
+            migrate_session_times(&far_future, &pre_epoch),
+            (Some(far_future), Some(pre_epoch))
+        );
+    }
+
+    #[test]
+    fn text_times_are_converted() {
+        let far_future = Utc.ymd(9999, 12, 31).and_hms(23, 59, 59).timestamp();
+        let pre_epoch = Utc.ymd(1901, 1, 1).and_hms(0, 0, 0).timestamp();
+
+        assert_eq!(
+            migrate_session_times(&far_future.to_string(), &pre_epoch.to_string()),
+            (Some(far_future), Some(pre_epoch))
+        );
+        assert_eq!(
+            migrate_session_times(&"9999-12-31 23:59:59", &"1901-01-01T00:00:00Z"),
+            (Some(far_future), Some(pre_epoch))
+        );
+    }
+
+    #[test]
+    fn real_times_are_converted() {
+        let far_future = Utc.ymd(9999, 12, 31).and_hms(23, 59, 59).timestamp();
+
+        assert_eq!(
+            migrate_session_times(&(far_future as f64), &Option::<i64>::None),
+            (Some(far_future), None)
+        );
+    }
+
+    #[test]
+    fn unreadable_times_are_replaced() {
+        assert_eq!(
+            migrate_session_times(&"tomorrow", &"yesterday"),
+            (Some(0), None)
+        );
+    }
+
+    #[test]
+    fn audit_log_only_anonymized() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        connection
+            .execute(
+                "INSERT INTO audit_log(created_at, event, actor_id, actor_login, ip, user_agent,
+                     outcome)
+                 VALUES (1, 'login', 1, 'alice', '127.0.0.1', 'test', 'success')",
+                [],
+            )
+            .unwrap();
+
+        for update in [
+            "UPDATE audit_log SET outcome='failure'",
+            "UPDATE audit_log SET actor_id=2",
+            // Anonymizing can't hide another change
+            "UPDATE audit_log SET actor_id=NULL, actor_login=NULL, ip=NULL, user_agent=NULL,
+                 event='logout'",
+        ] {
+            assert!(connection.execute(update, []).is_err());
+        }
+        connection
+            .execute(
+                "UPDATE audit_log SET actor_id=NULL, actor_login=NULL, ip=NULL, user_agent=NULL",
+                [],
+            )
+            .unwrap();
+        let row = connection
+            .query_row("SELECT event, actor_login, ip FROM audit_log", [], |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, Option<String>>(1)?,
+                    r.get::<_, Option<String>>(2)?,
+                ))
+            })
+            .unwrap();
+        assert_eq!(row, ("login".to_owned(), None, None));
+    }
+}
@@ -0,0 +1,274 @@
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpResponse;
+use anyhow::{bail, Result as AnyResult};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use tracing::{error, info, instrument};
+
+use super::user_db::{AuditEntry, AuditEvent, UserDb};
+use super::{
+    generate_token, hash_password_blocking, hash_token, normalize_login, record_audit,
+    verify_password_blocking, AuthenticatedUser, ClientInfo, Credentials, FieldError,
+    HashingOptions, LoginResponse, LoginThrottle, ThrottleOptions, ValidationOptions,
+};
+
+/// Prefix of password reset tokens, distinguishes them from API tokens
+const RESET_TOKEN_PREFIX: &str = "mirnet_reset_";
+
+/// Time during which a password reset token can be redeemed
+const RESET_TOKEN_LIFETIME_HOURS: i64 = 24;
+
+#[derive(Deserialize)]
+pub struct ChangePasswordArgs {
+    current_password: String,
+    new_password: String,
+}
+
+enum ChangePasswordOutcome {
+    Changed,
+    InvalidPassword,
+    Invalid(Vec<FieldError>),
+    Locked(DateTime<Utc>),
+}
+
+async fn change_password_core(
+    auth: &AuthenticatedUser,
+    user_db: &UserDb,
+    validation: &ValidationOptions,
+    hashing: &HashingOptions,
+    throttle_options: &ThrottleOptions,
+    client: &ClientInfo,
+    args: &ChangePasswordArgs,
+) -> AnyResult<ChangePasswordOutcome> {
+    // A stolen session mustn't allow guessing the password, it's throttled like the login
+    let now = Utc::now();
+    let throttle = LoginThrottle::new(
+        user_db,
+        throttle_options,
+        &auth.user.login,
+        client.ip.as_deref(),
+    );
+    if let Some(locked_until) = throttle.locked_until(now).await? {
+        info!(%locked_until, "Password change while locked");
+        return Ok(ChangePasswordOutcome::Locked(locked_until));
+    }
+
+    let valid_password = verify_password_blocking(
+        args.current_password.clone(),
+        auth.user.password_hash.clone(),
+    )
+    .await?;
+    if !valid_password {
+        throttle.record_failure(now).await?;
+        return Ok(ChangePasswordOutcome::InvalidPassword);
+    }
+    throttle.record_success().await?;
+
+    let mut errors = Vec::new();
+    validation.validate_password(
+        "new_password",
+        &args.new_password,
+        Some(&auth.user.login),
+        &mut errors,
+    );
+    if !errors.is_empty() {
+        return Ok(ChangePasswordOutcome::Invalid(errors));
+    }
+
+    let password_hash = hash_password_blocking(args.new_password.clone(), hashing.clone()).await?;
+    user_db
+        .set_password_hash(auth.user.id, &password_hash)
+        .await?;
+
+    // Keep the session used for the change, all the others were opened with the old password
+    let current_session = match &auth.credentials {
+        Credentials::Session(session) => Some(session.id.as_str()),
+        Credentials::ApiToken(_) => None,
+    };
+    let removed = user_db
+        .remove_user_sessions(auth.user.id, current_session)
+        .await?;
+    info!(removed, "Removed other sessions after password change");
+
+    Ok(ChangePasswordOutcome::Changed)
+}
+
+#[instrument(
+    name = "Change Password",
+    skip(auth, client, args, user_db, validation, hashing, throttle_options),
+    fields(user_id = auth.user.id)
+)]
+pub async fn change_password(
+    auth: AuthenticatedUser,
+    client: ClientInfo,
+    args: web::Json<ChangePasswordArgs>,
+    user_db: web::Data<UserDb>,
+    validation: web::Data<ValidationOptions>,
+    hashing: web::Data<HashingOptions>,
+    throttle_options: web::Data<ThrottleOptions>,
+) -> HttpResponse {
+    let outcome = change_password_core(
+        &auth,
+        &user_db,
+        &validation,
+        &hashing,
+        &throttle_options,
+        &client,
+        &args,
+    )
+    .await;
+
+    let failure = match &outcome {
+        Ok(ChangePasswordOutcome::Changed) => None,
+        Ok(ChangePasswordOutcome::InvalidPassword) => Some("Invalid password"),
+        Ok(ChangePasswordOutcome::Invalid(_)) => Some("Invalid new password"),
+        Ok(ChangePasswordOutcome::Locked(_)) => Some("Locked"),
+        Err(_) => Some("Internal error"),
+    };
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_id: Some(auth.user.id),
+            actor_login: Some(auth.user.login.clone()),
+            ..AuditEntry::new(AuditEvent::ChangePassword, &client, failure)
+        },
+    )
+    .await;
+
+    match outcome {
+        Ok(ChangePasswordOutcome::Changed) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(ChangePasswordOutcome::InvalidPassword) => {
+            HttpResponse::BadRequest().json(LoginResponse::error_display("Invalid password"))
+        }
+        Ok(ChangePasswordOutcome::Invalid(errors)) => {
+            HttpResponse::BadRequest().json(LoginResponse::invalid_fields(errors))
+        }
+        Ok(ChangePasswordOutcome::Locked(locked_until)) => {
+            let retry_after = (locked_until - Utc::now()).num_seconds().max(1);
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(LoginResponse::error_display(
+                    "Too many failed attempts, try again later",
+                ))
+        }
+        Err(err) => {
+            error!(?err, "Failed to change password");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
+
+/// Create a one-time password reset token for a user, to be given to them out of band. It's only
+/// issued from the command line, the audit entry has neither actor nor client
+#[instrument(name = "Issue Password Reset", skip(user_db))]
+pub async fn issue_password_reset(user_db: &UserDb, login: &str) -> AnyResult<String> {
+    let user = match user_db.get_user_by_login(&normalize_login(login)).await? {
+        Some(user) => user,
+        None => bail!("Unknown user '{}'", login),
+    };
+
+    let token = generate_token(RESET_TOKEN_PREFIX);
+    let created_at = Utc::now();
+    let expires_at = created_at + Duration::hours(RESET_TOKEN_LIFETIME_HOURS);
+    user_db
+        .create_password_reset(user.id, &hash_token(&token), created_at, expires_at)
+        .await?;
+    info!(user_id = user.id, %expires_at, "Issued password reset token");
+    record_audit(
+        user_db,
+        AuditEntry {
+            details: Some(format!("user {}", user.id)),
+            ..AuditEntry::new(AuditEvent::IssuePasswordReset, &ClientInfo::default(), None)
+        },
+    )
+    .await;
+
+    Ok(token)
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordArgs {
+    token: String,
+    new_password: String,
+}
+
+enum ResetPasswordOutcome {
+    /// Password of the user with this id reset
+    Reset(i32),
+    InvalidToken,
+    Invalid(Vec<FieldError>),
+}
+
+async fn reset_password_core(
+    user_db: &UserDb,
+    validation: &ValidationOptions,
+    hashing: &HashingOptions,
+    args: &ResetPasswordArgs,
+) -> AnyResult<ResetPasswordOutcome> {
+    // Checked before redeeming the token so that it can be used again with a better password
+    let mut errors = Vec::new();
+    validation.validate_password("new_password", &args.new_password, None, &mut errors);
+    if !errors.is_empty() {
+        return Ok(ResetPasswordOutcome::Invalid(errors));
+    }
+
+    let user_id = match user_db
+        .consume_password_reset(&hash_token(args.token.trim()), Utc::now())
+        .await?
+    {
+        Some(user_id) => user_id,
+        None => return Ok(ResetPasswordOutcome::InvalidToken),
+    };
+
+    let password_hash = hash_password_blocking(args.new_password.clone(), hashing.clone()).await?;
+    user_db.set_password_hash(user_id, &password_hash).await?;
+
+    let removed = user_db.remove_user_sessions(user_id, None).await?;
+    info!(user_id, removed, "Password reset, removed all sessions");
+
+    Ok(ResetPasswordOutcome::Reset(user_id))
+}
+
+#[instrument(
+    name = "Reset Password",
+    skip(client, args, user_db, validation, hashing)
+)]
+pub async fn reset_password(
+    client: ClientInfo,
+    args: web::Json<ResetPasswordArgs>,
+    user_db: web::Data<UserDb>,
+    validation: web::Data<ValidationOptions>,
+    hashing: web::Data<HashingOptions>,
+) -> HttpResponse {
+    let outcome = reset_password_core(&user_db, &validation, &hashing, &args).await;
+
+    let (actor_id, failure) = match &outcome {
+        Ok(ResetPasswordOutcome::Reset(user_id)) => (Some(*user_id), None),
+        Ok(ResetPasswordOutcome::InvalidToken) => (None, Some("Invalid token")),
+        Ok(ResetPasswordOutcome::Invalid(_)) => (None, Some("Invalid new password")),
+        Err(_) => (None, Some("Internal error")),
+    };
+    record_audit(
+        &user_db,
+        AuditEntry {
+            actor_id,
+            ..AuditEntry::new(AuditEvent::ResetPassword, &client, failure)
+        },
+    )
+    .await;
+
+    match outcome {
+        Ok(ResetPasswordOutcome::Reset(_)) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(ResetPasswordOutcome::InvalidToken) => HttpResponse::BadRequest().json(
+            LoginResponse::error_display("Invalid or expired reset token"),
+        ),
+        Ok(ResetPasswordOutcome::Invalid(errors)) => {
+            HttpResponse::BadRequest().json(LoginResponse::invalid_fields(errors))
+        }
+        Err(err) => {
+            error!(?err, "Failed to reset password");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
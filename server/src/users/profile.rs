@@ -0,0 +1,89 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{error, instrument};
+use ts_rs::TS;
+
+use super::quotas::usage_report;
+use super::user_db::{Role, UserDb};
+use super::{AuthenticatedUser, Credentials, LoginResponse, QuotaOptions, UsageReport};
+
+/// Version of the `/api/me` response, incremented when a field is removed or changes meaning.
+/// Adding a field doesn't change it
+const ME_RESPONSE_VERSION: u32 = 1;
+
+/// How the request was authenticated
+#[derive(Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub enum AuthMethod {
+    Session,
+    ApiToken,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct UserProfile {
+    id: i32,
+    login: String,
+    role: Role,
+    /// Unknown for the accounts created before it was recorded
+    created_at: Option<DateTime<Utc>>,
+    auth: AuthMethod,
+    /// Expiration of the session or API token used for the request, `None` if it never expires
+    expires_at: Option<DateTime<Utc>>,
+    usage: UsageReport,
+}
+
+impl UserProfile {
+    fn new(auth: AuthenticatedUser, usage: UsageReport) -> Self {
+        let (method, expires_at) = match auth.credentials {
+            Credentials::Session(session) => (AuthMethod::Session, Some(session.valid_until)),
+            Credentials::ApiToken(token) => (AuthMethod::ApiToken, token.expires_at),
+        };
+
+        Self {
+            id: auth.user.id,
+            login: auth.user.login,
+            role: auth.user.role,
+            created_at: auth.user.created_at,
+            auth: method,
+            expires_at,
+            usage,
+        }
+    }
+}
+
+/// Response of `/api/me`, its TypeScript definition is generated in `client/src/bindings` by
+/// `cargo test`
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct MeResponse {
+    version: u32,
+    /// `None` when the request isn't authenticated
+    profile: Option<UserProfile>,
+}
+
+#[instrument(name = "User Me", skip(auth, user_db, quotas))]
+pub async fn get_me(
+    auth: Option<AuthenticatedUser>,
+    user_db: web::Data<UserDb>,
+    quotas: web::Data<QuotaOptions>,
+) -> HttpResponse {
+    let profile = match auth {
+        Some(auth) => match usage_report(&user_db, &quotas, auth.user.id).await {
+            Ok(usage) => Some(UserProfile::new(auth, usage)),
+            Err(err) => {
+                error!(?err, "Failed to read usage");
+                return HttpResponse::InternalServerError().json(LoginResponse::error(err));
+            }
+        },
+        None => None,
+    };
+
+    HttpResponse::Ok().json(MeResponse {
+        version: ME_RESPONSE_VERSION,
+        profile,
+    })
+}
@@ -0,0 +1,512 @@
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpResponse;
+use anyhow::{bail, Result as AnyResult};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{error, info, instrument};
+use ts_rs::TS;
+
+use super::admin::unknown_user;
+use super::user_db::{QuotaOverride, Usage, UsageRecord, UsageReservation, UserDb};
+use super::{AdminUser, AuthenticatedUser, LoginResponse};
+
+/// Default quotas of the users, 0 removes a limit. Days and months start at midnight UTC
+#[derive(Debug, Clone, StructOpt)]
+pub struct QuotaOptions {
+    /// Image processing requests a user can make per day
+    #[structopt(long, env = "MIRNET_QUOTA_DAILY_REQUESTS", default_value = "0")]
+    pub quota_daily_requests: u32,
+
+    /// Megapixels of input images a user can process per day
+    #[structopt(long, env = "MIRNET_QUOTA_DAILY_MEGAPIXELS", default_value = "0")]
+    pub quota_daily_megapixels: f64,
+
+    /// Seconds of image processing a user can use per day
+    #[structopt(long, env = "MIRNET_QUOTA_DAILY_COMPUTE_SECONDS", default_value = "0")]
+    pub quota_daily_compute_seconds: f64,
+
+    /// Image processing requests a user can make per month
+    #[structopt(long, env = "MIRNET_QUOTA_MONTHLY_REQUESTS", default_value = "0")]
+    pub quota_monthly_requests: u32,
+
+    /// Megapixels of input images a user can process per month
+    #[structopt(long, env = "MIRNET_QUOTA_MONTHLY_MEGAPIXELS", default_value = "0")]
+    pub quota_monthly_megapixels: f64,
+
+    /// Seconds of image processing a user can use per month
+    #[structopt(
+        long,
+        env = "MIRNET_QUOTA_MONTHLY_COMPUTE_SECONDS",
+        default_value = "0"
+    )]
+    pub quota_monthly_compute_seconds: f64,
+}
+
+impl QuotaOptions {
+    pub fn validate(&self) -> AnyResult<()> {
+        let limits = [
+            self.quota_daily_megapixels,
+            self.quota_daily_compute_seconds,
+            self.quota_monthly_megapixels,
+            self.quota_monthly_compute_seconds,
+        ];
+        if !limits.iter().all(|limit| valid_limit(*limit)) {
+            bail!("Quotas must be positive numbers, or 0 for no limit");
+        }
+
+        Ok(())
+    }
+}
+
+fn valid_limit(limit: f64) -> bool {
+    limit.is_finite() && limit >= 0.0
+}
+
+impl Usage {
+    /// Usage of a request before any of its images is processed
+    pub fn one_request() -> Self {
+        Self {
+            requests: 1,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    fn label(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Day => "Daily",
+            QuotaPeriod::Month => "Monthly",
+        }
+    }
+
+    /// Start of the current period and of the next one
+    fn bounds(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date();
+        match self {
+            QuotaPeriod::Day => (
+                today.and_hms(0, 0, 0),
+                (today + Duration::days(1)).and_hms(0, 0, 0),
+            ),
+            QuotaPeriod::Month => {
+                let next = match now.month() {
+                    12 => Utc.ymd(now.year() + 1, 1, 1),
+                    month => Utc.ymd(now.year(), month + 1, 1),
+                };
+                (
+                    Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0),
+                    next.and_hms(0, 0, 0),
+                )
+            }
+        }
+    }
+}
+
+/// Limits of a period, `None` when there is no limit
+#[derive(Debug, Clone, Copy, Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct QuotaLimits {
+    requests: Option<u32>,
+    megapixels: Option<f64>,
+    compute_seconds: Option<f64>,
+}
+
+/// The limit of the user if an admin chose one, otherwise the default one
+fn limit<T: PartialEq + Default>(custom: Option<T>, default: T) -> Option<T> {
+    let limit = custom.unwrap_or(default);
+    if limit == T::default() {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+impl QuotaLimits {
+    fn new(period: QuotaPeriod, options: &QuotaOptions, custom: &QuotaOverride) -> Self {
+        match period {
+            QuotaPeriod::Day => Self {
+                requests: limit(custom.daily_requests, options.quota_daily_requests),
+                megapixels: limit(custom.daily_megapixels, options.quota_daily_megapixels),
+                compute_seconds: limit(
+                    custom.daily_compute_seconds,
+                    options.quota_daily_compute_seconds,
+                ),
+            },
+            QuotaPeriod::Month => Self {
+                requests: limit(custom.monthly_requests, options.quota_monthly_requests),
+                megapixels: limit(custom.monthly_megapixels, options.quota_monthly_megapixels),
+                compute_seconds: limit(
+                    custom.monthly_compute_seconds,
+                    options.quota_monthly_compute_seconds,
+                ),
+            },
+        }
+    }
+
+    /// Name of the first exhausted resource. The size and duration of a request are only known
+    /// once it's done, so a request is refused once a limit is reached rather than exceeded
+    pub fn exhausted(&self, used: &Usage) -> Option<&'static str> {
+        if matches!(self.requests, Some(limit) if used.requests >= limit) {
+            Some("request")
+        } else if matches!(self.megapixels, Some(limit) if used.megapixels >= limit) {
+            Some("megapixel")
+        } else if matches!(self.compute_seconds, Some(limit) if used.compute_seconds >= limit) {
+            Some("compute time")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct PeriodUsage {
+    used: Usage,
+    limits: QuotaLimits,
+    resets_at: DateTime<Utc>,
+}
+
+/// Usage of a user in the current day and month, with their quotas
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct UsageReport {
+    daily: PeriodUsage,
+    monthly: PeriodUsage,
+}
+
+async fn period_usage(
+    user_db: &UserDb,
+    options: &QuotaOptions,
+    custom: &QuotaOverride,
+    user_id: i32,
+    period: QuotaPeriod,
+) -> AnyResult<PeriodUsage> {
+    let (start, resets_at) = period.bounds(Utc::now());
+    Ok(PeriodUsage {
+        used: user_db.sum_usage(user_id, start).await?,
+        limits: QuotaLimits::new(period, options, custom),
+        resets_at,
+    })
+}
+
+/// Usage of the user in the current day and month, compared to their quotas
+pub async fn usage_report(
+    user_db: &UserDb,
+    options: &QuotaOptions,
+    user_id: i32,
+) -> AnyResult<UsageReport> {
+    let custom = user_db
+        .get_quota_override(user_id)
+        .await?
+        .unwrap_or_default();
+
+    Ok(UsageReport {
+        daily: period_usage(user_db, options, &custom, user_id, QuotaPeriod::Day).await?,
+        monthly: period_usage(user_db, options, &custom, user_id, QuotaPeriod::Month).await?,
+    })
+}
+
+/// A quota reached by a user, their requests are refused until it resets
+pub struct QuotaExceeded {
+    pub message: String,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl QuotaExceeded {
+    fn new(period: QuotaPeriod, resource: &str, resets_at: DateTime<Utc>) -> Self {
+        Self {
+            message: format!(
+                "{} {} quota exceeded, it resets at {}",
+                period.label(),
+                resource,
+                resets_at.to_rfc3339()
+            ),
+            resets_at,
+        }
+    }
+}
+
+/// The first quota reached by the user, `None` while they are under all of them. The `reserved`
+/// requests are already counted in the usage and being processed, they don't use the request
+/// quotas again
+pub async fn exceeded_quota(
+    user_db: &UserDb,
+    options: &QuotaOptions,
+    user_id: i32,
+    reserved: u32,
+) -> AnyResult<Option<QuotaExceeded>> {
+    let report = usage_report(user_db, options, user_id).await?;
+
+    for (period, usage) in [
+        (QuotaPeriod::Day, &report.daily),
+        (QuotaPeriod::Month, &report.monthly),
+    ] {
+        let used = Usage {
+            requests: usage.used.requests.saturating_sub(reserved),
+            ..usage.used
+        };
+        if let Some(resource) = usage.limits.exhausted(&used) {
+            info!(user_id, ?period, resource, "Quota exceeded");
+            return Ok(Some(QuotaExceeded::new(period, resource, usage.resets_at)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Count a request of the user unless they reached one of their quotas
+async fn try_reserve_request(
+    user_db: &UserDb,
+    options: &QuotaOptions,
+    user_id: i32,
+) -> AnyResult<Option<QuotaExceeded>> {
+    let custom = user_db
+        .get_quota_override(user_id)
+        .await?
+        .unwrap_or_default();
+    let now = Utc::now();
+    let periods = [QuotaPeriod::Day, QuotaPeriod::Month];
+    let quotas = periods
+        .iter()
+        .map(|period| {
+            let (start, _) = period.bounds(now);
+            (start, QuotaLimits::new(*period, options, &custom))
+        })
+        .collect();
+    let record = UsageRecord {
+        user_id,
+        created_at: now,
+        usage: Usage::one_request(),
+    };
+
+    match user_db.reserve_usage(record, quotas).await? {
+        UsageReservation::Reserved => Ok(None),
+        UsageReservation::Exhausted { quota, resource } => {
+            let period = periods[quota];
+            info!(user_id, ?period, resource, "Quota exceeded");
+            let (_, resets_at) = period.bounds(now);
+            Ok(Some(QuotaExceeded::new(period, resource, resets_at)))
+        }
+    }
+}
+
+/// Refuse the request with a 429 response when the user reached one of their quotas. Otherwise
+/// the request is counted right away, before it's processed, so that the concurrent requests of
+/// the user see it
+pub async fn reserve_request(
+    user_db: &UserDb,
+    options: &QuotaOptions,
+    user_id: i32,
+) -> Result<(), HttpResponse> {
+    match try_reserve_request(user_db, options, user_id).await {
+        Ok(None) => Ok(()),
+        Ok(Some(exceeded)) => {
+            let retry_after = (exceeded.resets_at - Utc::now()).num_seconds().max(1);
+            Err(HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(LoginResponse::error_display(exceeded.message)))
+        }
+        Err(err) => {
+            error!(?err, "Failed to reserve request");
+            Err(HttpResponse::InternalServerError().json(LoginResponse::error(err)))
+        }
+    }
+}
+
+/// Add the usage of a request, a failure is logged but doesn't fail the request
+pub async fn record_usage(user_db: &UserDb, user_id: i32, usage: Usage) {
+    let record = UsageRecord {
+        user_id,
+        created_at: Utc::now(),
+        usage,
+    };
+    if let Err(err) = user_db.add_usage(record).await {
+        error!(?err, user_id, "Failed to record usage");
+    }
+}
+
+#[instrument(name = "User Usage", skip(auth, user_db, quotas), fields(user_id = auth.user.id))]
+pub async fn get_usage(
+    auth: AuthenticatedUser,
+    user_db: web::Data<UserDb>,
+    quotas: web::Data<QuotaOptions>,
+) -> HttpResponse {
+    match usage_report(&user_db, &quotas, auth.user.id).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserQuotasResponse {
+    /// Quotas chosen for this user, `None` when they have the default ones
+    custom: Option<QuotaOverride>,
+    usage: UsageReport,
+}
+
+#[instrument(
+    name = "Admin Get Quotas",
+    skip(admin, user_db, quotas),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn get_user_quotas(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+    quotas: web::Data<QuotaOptions>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    match user_db.get_user_by_id(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unknown_user(),
+        Err(err) => return HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+
+    let result = match user_db.get_quota_override(user_id).await {
+        Ok(custom) => usage_report(&user_db, &quotas, user_id)
+            .await
+            .map(|usage| UserQuotasResponse { custom, usage }),
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Admin Set Quotas",
+    skip(admin, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn set_user_quotas(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    args: web::Json<QuotaOverride>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    let custom = args.into_inner();
+    let limits = [
+        custom.daily_megapixels,
+        custom.daily_compute_seconds,
+        custom.monthly_megapixels,
+        custom.monthly_compute_seconds,
+    ];
+    if !limits.iter().flatten().all(|limit| valid_limit(*limit)) {
+        return HttpResponse::BadRequest().json(LoginResponse::error_display(
+            "Quotas must be positive numbers, or 0 for no limit",
+        ));
+    }
+
+    match user_db.set_quota_override(user_id, custom).await {
+        Ok(true) => {
+            info!(user_id, "Changed user quotas");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Ok(false) => unknown_user(),
+        Err(err) => {
+            error!(?err, "Failed to change user quotas");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
+
+#[instrument(
+    name = "Admin Reset Quotas",
+    skip(admin, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn reset_user_quotas(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    match user_db.remove_quota_override(user_id).await {
+        Ok(removed) => {
+            info!(user_id, removed, "Reset user quotas");
+            HttpResponse::Ok().json(LoginResponse::ok())
+        }
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{MemoryUserDb, TemporaryDb};
+    use actix_web::http::StatusCode;
+    use futures::future::join_all;
+    use std::sync::Arc;
+
+    const LIMIT: u32 = 5;
+
+    /// One more request than the daily quota at the same time, exactly one is refused
+    async fn check_concurrent_reservations(user_db: &UserDb) {
+        let mut options = QuotaOptions::from_iter(&["test"]);
+        options.quota_daily_requests = LIMIT;
+        assert!(user_db.register("user", "hash").await.unwrap());
+        let user = user_db.get_user_by_login("user").await.unwrap().unwrap();
+
+        let results =
+            join_all((0..=LIMIT).map(|_| reserve_request(user_db, &options, user.id))).await;
+
+        let refused: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0].status(), StatusCode::TOO_MANY_REQUESTS);
+        let (start, _) = QuotaPeriod::Day.bounds(Utc::now());
+        let used = user_db.sum_usage(user.id, start).await.unwrap();
+        assert_eq!(used.requests, LIMIT);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_reservations_memory() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        check_concurrent_reservations(&user_db).await;
+    }
+
+    #[actix_web::test]
+    async fn concurrent_reservations_sqlite() {
+        let db = TemporaryDb::new().await;
+        check_concurrent_reservations(&db.user_db).await;
+    }
+
+    #[actix_web::test]
+    async fn reservation_reports_quota() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        let now = Utc::now();
+        let (day, _) = QuotaPeriod::Day.bounds(now);
+        let (month, _) = QuotaPeriod::Month.bounds(now);
+        let limits = |requests| QuotaLimits {
+            requests,
+            megapixels: None,
+            compute_seconds: None,
+        };
+        let record = UsageRecord {
+            user_id: 1,
+            created_at: now,
+            usage: Usage::one_request(),
+        };
+
+        let quotas = vec![(day, limits(Some(2))), (month, limits(Some(1)))];
+        let reservation = user_db.reserve_usage(record.clone(), quotas.clone()).await;
+        assert_eq!(reservation.unwrap(), UsageReservation::Reserved);
+        let reservation = user_db.reserve_usage(record, quotas).await;
+        assert_eq!(
+            reservation.unwrap(),
+            UsageReservation::Exhausted {
+                quota: 1,
+                resource: "request"
+            }
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::str::FromStr;
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tracing::{error, info, instrument};
+
+use super::user_db::{InviteInfo, UserDb};
+use super::{generate_token, hash_token, AdminUser, LoginResponse};
+
+/// Prefix of invite codes
+const INVITE_PREFIX: &str = "mirnet_invite_";
+
+/// Validity of an invite when the admin doesn't choose one
+const DEFAULT_INVITE_DAYS: u32 = 7;
+
+/// Who can create an account through `/api/register`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Anyone who can reach the server
+    Open,
+    /// Only the holders of an invite code created by an admin
+    Invite,
+    /// Nobody, accounts are created with the command line
+    Closed,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "open" => Ok(RegistrationMode::Open),
+            "invite" => Ok(RegistrationMode::Invite),
+            "closed" => Ok(RegistrationMode::Closed),
+            _ => Err(format!(
+                "invalid registration mode '{}', expected 'open', 'invite' or 'closed'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RegistrationOptions {
+    /// Who can create an account: "open", "invite" or "closed"
+    #[structopt(long, env = "MIRNET_REGISTRATION", default_value = "open")]
+    pub registration: RegistrationMode,
+}
+
+#[derive(Serialize)]
+pub struct InviteResponse {
+    id: i64,
+    created_by: i32,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    max_uses: u32,
+    uses: u32,
+}
+
+impl From<InviteInfo> for InviteResponse {
+    fn from(info: InviteInfo) -> Self {
+        Self {
+            id: info.id,
+            created_by: info.created_by,
+            created_at: info.created_at,
+            expires_at: info.expires_at,
+            max_uses: info.max_uses,
+            uses: info.uses,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteArgs {
+    max_uses: Option<u32>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct CreateInviteResponse {
+    /// The code itself, only returned once at creation
+    code: String,
+    info: InviteResponse,
+}
+
+#[instrument(
+    name = "Admin Create Invite",
+    skip(admin, args, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn create_invite(
+    admin: AdminUser,
+    args: web::Json<CreateInviteArgs>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let max_uses = args.max_uses.unwrap_or(1);
+    if max_uses == 0 {
+        return HttpResponse::BadRequest()
+            .json(LoginResponse::error_display("Invalid number of uses"));
+    }
+
+    let created_at = Utc::now();
+    let days = args.expires_in_days.unwrap_or(DEFAULT_INVITE_DAYS);
+    let expires_at = match created_at.checked_add_signed(Duration::days(days.into())) {
+        Some(expires_at) => expires_at,
+        None => {
+            return HttpResponse::BadRequest()
+                .json(LoginResponse::error_display("Invalid expiration"))
+        }
+    };
+    let code = generate_token(INVITE_PREFIX);
+
+    match user_db
+        .create_invite(
+            &hash_token(&code),
+            admin.0.user.id,
+            created_at,
+            expires_at,
+            max_uses,
+        )
+        .await
+    {
+        Ok(info) => {
+            info!(invite_id = info.id, max_uses, %expires_at, "Created invite");
+            HttpResponse::Ok().json(CreateInviteResponse {
+                code,
+                info: info.into(),
+            })
+        }
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(name = "Admin List Invites", skip(admin, user_db), fields(admin_id = admin.0.user.id))]
+pub async fn list_invites(admin: AdminUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    match user_db.list_invites().await {
+        Ok(invites) => HttpResponse::Ok().json(
+            invites
+                .into_iter()
+                .map(InviteResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Admin Revoke Invite",
+    skip(admin, user_db),
+    fields(admin_id = admin.0.user.id)
+)]
+pub async fn revoke_invite(
+    admin: AdminUser,
+    invite_id: web::Path<i64>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    match user_db.remove_invite(invite_id.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(false) => HttpResponse::NotFound().json(LoginResponse::error_display("Unknown invite")),
+        Err(err) => {
+            error!(?err, "Failed to revoke invite");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
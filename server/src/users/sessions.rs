@@ -0,0 +1,165 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{error, info, instrument};
+
+use super::user_db::{SessionInfo, UserDb};
+use super::{
+    hash_token, purge_login_failures, AuthenticatedUser, Credentials, LoginResponse, SessionUser,
+    ThrottleOptions,
+};
+
+/// Minimum time between two renewals of the same session, avoids a database write per request
+const RENEWAL_INTERVAL_SECONDS: i64 = 60;
+
+/// Configuration of the login sessions
+#[derive(Debug, Clone, StructOpt)]
+pub struct SessionOptions {
+    /// Hours of inactivity after which a session expires, renewed on each use
+    #[structopt(long, env = "MIRNET_SESSION_LIFETIME_HOURS", default_value = "48")]
+    pub session_lifetime_hours: u32,
+
+    /// Maximum age of a session in days, whatever the activity
+    #[structopt(long, env = "MIRNET_SESSION_MAX_AGE_DAYS", default_value = "30")]
+    pub session_max_age_days: u32,
+
+    /// Interval in minutes between two purges of the expired sessions and login failures
+    #[structopt(long, env = "MIRNET_SESSION_CLEANUP_MINUTES", default_value = "60")]
+    pub session_cleanup_minutes: u32,
+}
+
+impl SessionOptions {
+    pub fn lifetime(&self) -> Duration {
+        Duration::hours(self.session_lifetime_hours.into())
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::days(self.session_max_age_days.into())
+    }
+
+    /// Expiration of a session created at `created_at` and last used at `now`
+    pub fn valid_until(&self, created_at: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+        (now + self.lifetime()).min(created_at + self.max_age())
+    }
+
+    /// Whether the validity of the session should be extended following activity at `now`
+    pub fn needs_renewal(&self, session: &SessionInfo, now: DateTime<Utc>) -> bool {
+        match session.last_seen_at {
+            Some(last_seen_at) => now - last_seen_at >= Duration::seconds(RENEWAL_INTERVAL_SECONDS),
+            None => true,
+        }
+    }
+}
+
+/// Identifier of a session that can be shown to the user, the real one is the cookie content
+pub fn session_public_id(session_id: &str) -> String {
+    hash_token(session_id)[..16].to_string()
+}
+
+/// Periodically remove the expired sessions and login failures from the database
+pub fn spawn_session_cleanup(
+    user_db: UserDb,
+    options: &SessionOptions,
+    throttle_options: &ThrottleOptions,
+) {
+    let period = std::time::Duration::from_secs(u64::from(options.session_cleanup_minutes) * 60);
+    if period.is_zero() {
+        return;
+    }
+    let throttle_options = throttle_options.clone();
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            match user_db.remove_expired_sessions(Utc::now()).await {
+                Ok(removed) => info!(removed, "Purged expired sessions"),
+                Err(err) => error!(?err, "Failed to purge expired sessions"),
+            }
+            match purge_login_failures(&user_db, &throttle_options, Utc::now()).await {
+                Ok((failures, lockouts)) => info!(failures, lockouts, "Purged login failures"),
+                Err(err) => error!(?err, "Failed to purge login failures"),
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    id: String,
+    current: bool,
+    created_at: Option<DateTime<Utc>>,
+    last_seen_at: Option<DateTime<Utc>>,
+    valid_until: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+fn current_session_id(auth: &AuthenticatedUser) -> Option<&str> {
+    match &auth.credentials {
+        Credentials::Session(session) => Some(&session.id),
+        Credentials::ApiToken(_) => None,
+    }
+}
+
+#[instrument(
+    name = "List Sessions",
+    skip(session, user_db),
+    fields(user_id = session.0.user.id)
+)]
+pub async fn list_sessions(session: SessionUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    let auth = &session.0;
+    let current = current_session_id(auth);
+    let now = Utc::now();
+
+    match user_db.list_sessions(auth.user.id).await {
+        Ok(sessions) => HttpResponse::Ok().json(
+            sessions
+                .into_iter()
+                .filter(|session| session.valid_until > now)
+                .map(|session| SessionResponse {
+                    id: session_public_id(&session.id),
+                    current: Some(session.id.as_str()) == current,
+                    created_at: session.created_at,
+                    last_seen_at: session.last_seen_at,
+                    valid_until: session.valid_until,
+                    user_agent: session.user_agent,
+                    ip: session.ip,
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Revoke Session",
+    skip(session, user_db),
+    fields(user_id = session.0.user.id)
+)]
+pub async fn revoke_session(
+    session: SessionUser,
+    public_id: web::Path<String>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let sessions = match user_db.list_sessions(session.0.user.id).await {
+        Ok(sessions) => sessions,
+        Err(err) => return HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    };
+
+    let session = sessions
+        .into_iter()
+        .find(|session| session_public_id(&session.id) == *public_id);
+    match session {
+        Some(session) => match user_db.remove_session(&session.id).await {
+            Ok(()) => {
+                info!(session = %public_id, "Revoked session");
+                HttpResponse::Ok().json(LoginResponse::ok())
+            }
+            Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+        },
+        None => HttpResponse::NotFound().json(LoginResponse::error_display("Unknown session")),
+    }
+}
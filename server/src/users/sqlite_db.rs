@@ -0,0 +1,1320 @@
+use actix_web::web;
+use anyhow::{bail, Result as AnyResult};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, DatabaseName, TransactionBehavior};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+
+use super::migrations::{check_migrations, run_migrations};
+use super::quotas::QuotaLimits;
+use super::user_db::{
+    ApiTokenInfo, AuditEntry, AuditFilter, AuditRecord, DatabaseOptions, DbError, InviteInfo,
+    InviteRegistration, LockoutInfo, LoginFailures, QuotaOverride, Role, SessionInfo, Usage,
+    UsageRecord, UsageReservation, UserInfo, UserStore,
+};
+
+/// Time a query waits for a lock held by another connection before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default storage, a SQLite database file
+#[derive(Clone)]
+pub struct SqliteUserDb {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+/// Read a time column, stored as 64-bit seconds since the epoch. Values out of the range of
+/// `DateTime` are reported as errors instead of panicking
+fn get_timestamp(r: &rusqlite::Row, index: usize) -> Result<DateTime<Utc>, rusqlite::Error> {
+    let seconds: i64 = r.get(index)?;
+    Utc.timestamp_opt(seconds, 0)
+        .single()
+        .ok_or(rusqlite::Error::IntegralValueOutOfRange(index, seconds))
+}
+
+fn get_optional_timestamp(
+    r: &rusqlite::Row,
+    index: usize,
+) -> Result<Option<DateTime<Utc>>, rusqlite::Error> {
+    match r.get::<_, Option<i64>>(index)? {
+        Some(_) => get_timestamp(r, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn user_from_row(r: &rusqlite::Row) -> Result<UserInfo, rusqlite::Error> {
+    Ok(UserInfo {
+        id: r.get(0)?,
+        login: r.get(1)?,
+        password_hash: r.get(2)?,
+        role: r.get(3)?,
+        disabled: r.get(4)?,
+        created_at: get_optional_timestamp(r, 5)?,
+        invited_by: r.get(6)?,
+    })
+}
+
+fn session_from_row(r: &rusqlite::Row) -> Result<SessionInfo, rusqlite::Error> {
+    Ok(SessionInfo {
+        id: r.get(0)?,
+        user_id: r.get(1)?,
+        valid_until: get_timestamp(r, 2)?,
+        created_at: get_optional_timestamp(r, 3)?,
+        last_seen_at: get_optional_timestamp(r, 4)?,
+        user_agent: r.get(5)?,
+        ip: r.get(6)?,
+    })
+}
+
+fn invite_from_row(r: &rusqlite::Row) -> Result<InviteInfo, rusqlite::Error> {
+    Ok(InviteInfo {
+        id: r.get(0)?,
+        created_by: r.get(1)?,
+        created_at: get_timestamp(r, 2)?,
+        expires_at: get_timestamp(r, 3)?,
+        max_uses: r.get(4)?,
+        uses: r.get(5)?,
+    })
+}
+
+fn api_token_from_row(r: &rusqlite::Row) -> Result<ApiTokenInfo, rusqlite::Error> {
+    Ok(ApiTokenInfo {
+        id: r.get(0)?,
+        user_id: r.get(1)?,
+        name: r.get(2)?,
+        created_at: get_timestamp(r, 3)?,
+        expires_at: get_optional_timestamp(r, 4)?,
+        last_used_at: get_optional_timestamp(r, 5)?,
+    })
+}
+
+fn audit_record_from_row(r: &rusqlite::Row) -> Result<AuditRecord, rusqlite::Error> {
+    Ok(AuditRecord {
+        id: r.get(0)?,
+        entry: AuditEntry {
+            created_at: get_timestamp(r, 1)?,
+            event: r.get(2)?,
+            actor_id: r.get(3)?,
+            actor_login: r.get(4)?,
+            ip: r.get(5)?,
+            user_agent: r.get(6)?,
+            outcome: r.get(7)?,
+            details: r.get(8)?,
+        },
+    })
+}
+
+fn usage_record_from_row(r: &rusqlite::Row) -> Result<UsageRecord, rusqlite::Error> {
+    Ok(UsageRecord {
+        user_id: r.get(0)?,
+        created_at: get_timestamp(r, 1)?,
+        usage: Usage {
+            requests: r.get(2)?,
+            megapixels: r.get(3)?,
+            compute_seconds: r.get(4)?,
+        },
+    })
+}
+
+fn insert_usage(connection: &Connection, record: &UsageRecord) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO usage(user_id, created_at, requests, megapixels, compute_seconds)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            record.user_id,
+            record.created_at.timestamp(),
+            record.usage.requests,
+            record.usage.megapixels,
+            record.usage.compute_seconds
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn sum_usage_since(
+    connection: &Connection,
+    user_id: i32,
+    since: DateTime<Utc>,
+) -> Result<Usage, rusqlite::Error> {
+    connection.query_row(
+        "SELECT COALESCE(SUM(requests), 0), COALESCE(SUM(megapixels), 0.0),
+             COALESCE(SUM(compute_seconds), 0.0)
+         FROM usage WHERE user_id=?1 AND created_at>=?2",
+        params![user_id, since.timestamp()],
+        |r| {
+            Ok(Usage {
+                requests: r.get(0)?,
+                megapixels: r.get(1)?,
+                compute_seconds: r.get(2)?,
+            })
+        },
+    )
+}
+
+fn quota_override_from_row(r: &rusqlite::Row) -> Result<QuotaOverride, rusqlite::Error> {
+    Ok(QuotaOverride {
+        daily_requests: r.get(0)?,
+        daily_megapixels: r.get(1)?,
+        daily_compute_seconds: r.get(2)?,
+        monthly_requests: r.get(3)?,
+        monthly_megapixels: r.get(4)?,
+        monthly_compute_seconds: r.get(5)?,
+    })
+}
+
+impl SqliteUserDb {
+    /// Open a pool of connections to the database. WAL mode lets readers proceed while a writer
+    /// holds the database
+    pub fn open(options: &DatabaseOptions) -> AnyResult<Self> {
+        if !options.db_path.exists() {
+            warn!(path = ?options.db_path, "Database not found, creating an empty one");
+        }
+
+        let manager = SqliteConnectionManager::file(&options.db_path).with_init(|connection| {
+            connection.busy_timeout(BUSY_TIMEOUT)?;
+            connection.execute_batch("PRAGMA journal_mode=WAL;")
+        });
+        let pool = Pool::builder()
+            .max_size(options.db_pool_size)
+            .build(manager)?;
+
+        Ok(Self { pool })
+    }
+
+    /// Run `f` with a connection of the pool on the blocking thread pool, sqlite calls block. A
+    /// query returning no rows is reported as `DbError::NotFound`
+    async fn run<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            let mut connection = pool.get()?;
+            f(&mut connection).map_err(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => DbError::NotFound,
+                err => err.into(),
+            })
+        })
+        .await?
+    }
+
+    /// Apply the pending migrations without committing them and return their names
+    #[instrument(name = "UserDb::check_migrations", skip(self))]
+    pub async fn check_migrations(&self) -> AnyResult<Vec<String>> {
+        let pool = self.pool.clone();
+        web::block(move || check_migrations(&mut *pool.get()?)).await?
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteUserDb {
+    #[instrument(name = "UserDb::initialize", skip(self))]
+    async fn initialize(&self) -> AnyResult<()> {
+        info!("Initializing database");
+
+        let pool = self.pool.clone();
+        web::block(move || run_migrations(&mut *pool.get()?)).await?
+    }
+
+    /// Uses SQLite's online backup, so writers aren't blocked for the whole copy
+    #[instrument(name = "UserDb::backup", skip(self))]
+    async fn backup(&self, destination: PathBuf) -> AnyResult<()> {
+        if destination.exists() {
+            bail!("{:?} already exists", destination);
+        }
+
+        let pool = self.pool.clone();
+        web::block(move || {
+            pool.get()?.backup(DatabaseName::Main, &destination, None)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    #[instrument(name = "UserDb::register", skip(self, password_hash))]
+    async fn register(&self, login: &str, password_hash: &str) -> Result<bool, DbError> {
+        let login = login.to_owned();
+        let password_hash = password_hash.to_owned();
+        let created_at = Utc::now();
+
+        self.run(move |connection| {
+            let result = connection.execute(
+                "INSERT INTO users(login, password_hash, created_at) VALUES (?1, ?2, ?3)",
+                params![login, password_hash, created_at.timestamp()],
+            );
+
+            if let Err(error) = result {
+                match error {
+                    rusqlite::Error::SqliteFailure(sql_error, _) => {
+                        if sql_error.code == rusqlite::ErrorCode::ConstraintViolation {
+                            Ok(false)
+                        } else {
+                            Err(error)
+                        }
+                    }
+                    _ => Err(error),
+                }
+            } else {
+                Ok(true)
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::import_user", skip(self, password_hash))]
+    async fn import_user(
+        &self,
+        login: &str,
+        password_hash: &str,
+        role: Role,
+        disabled: bool,
+        invited_by: Option<i32>,
+    ) -> Result<bool, DbError> {
+        let login = login.to_owned();
+        let password_hash = password_hash.to_owned();
+        let created_at = Utc::now();
+
+        self.run(move |connection| {
+            let result = connection.execute(
+                "INSERT INTO users(login, password_hash, role, disabled, invited_by, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    login,
+                    password_hash,
+                    role,
+                    disabled,
+                    invited_by,
+                    created_at.timestamp()
+                ],
+            );
+
+            match result {
+                Ok(_) => Ok(true),
+                Err(rusqlite::Error::SqliteFailure(sql_error, _))
+                    if sql_error.code == rusqlite::ErrorCode::ConstraintViolation =>
+                {
+                    Ok(false)
+                }
+                Err(error) => Err(error),
+            }
+        })
+        .await
+    }
+
+    #[instrument(
+        name = "UserDb::register_with_invite",
+        skip(self, password_hash, code_hash)
+    )]
+    async fn register_with_invite(
+        &self,
+        login: &str,
+        password_hash: &str,
+        code_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<InviteRegistration, DbError> {
+        let login = login.to_owned();
+        let password_hash = password_hash.to_owned();
+        let code_hash = code_hash.to_owned();
+
+        self.run(move |connection| {
+            // Take the write lock before reading the invite, so that two registrations can't both
+            // take its last use
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let invite = transaction.query_row(
+                "SELECT id, created_by FROM invites
+                 WHERE code_hash=?1 AND expires_at>=?2 AND uses<max_uses",
+                params![code_hash, now.timestamp()],
+                |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i32>(1)?)),
+            );
+            let (invite_id, invited_by) = match invite {
+                Ok(invite) => invite,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    return Ok(InviteRegistration::InvalidInvite)
+                }
+                Err(err) => return Err(err),
+            };
+
+            let exists = transaction.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE login=?1)",
+                [&login],
+                |r| r.get::<_, bool>(0),
+            )?;
+            if exists {
+                return Ok(InviteRegistration::AlreadyExists);
+            }
+
+            transaction.execute(
+                "INSERT INTO users(login, password_hash, invited_by, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![login, password_hash, invited_by, now.timestamp()],
+            )?;
+            transaction.execute(
+                "UPDATE invites SET uses=uses+1 WHERE id=?1",
+                params![invite_id],
+            )?;
+            transaction.commit()?;
+
+            Ok(InviteRegistration::Registered)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_user_by_login", skip(self))]
+    async fn get_user_by_login(&self, login: &str) -> Result<Option<UserInfo>, DbError> {
+        let login = login.to_owned();
+
+        self.run(move |connection| {
+            let result = connection.query_row(
+                "SELECT id, login, password_hash, role, disabled, created_at, invited_by
+                 FROM users WHERE login=?1",
+                [login],
+                user_from_row,
+            );
+
+            match result {
+                Ok(info) => Ok(Some(info)),
+                Err(err) => match err {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    _ => Err(err),
+                },
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_user_by_id", skip(self))]
+    async fn get_user_by_id(&self, id: i32) -> Result<Option<UserInfo>, DbError> {
+        self.run(move |connection| {
+            let result = connection.query_row(
+                "SELECT id, login, password_hash, role, disabled, created_at, invited_by
+                 FROM users WHERE id=?1",
+                [id],
+                user_from_row,
+            );
+
+            match result {
+                Ok(info) => Ok(Some(info)),
+                Err(err) => match err {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    _ => Err(err),
+                },
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_users", skip(self))]
+    async fn list_users(&self) -> Result<Vec<UserInfo>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, login, password_hash, role, disabled, created_at, invited_by
+                 FROM users ORDER BY id",
+            )?;
+            let users = statement
+                .query_map([], user_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(users)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::set_user_role", skip(self))]
+    async fn set_user_role(&self, id: i32, role: Role) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let updated =
+                connection.execute("UPDATE users SET role=?1 WHERE id=?2", params![role, id])?;
+
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::set_user_disabled", skip(self))]
+    async fn set_user_disabled(&self, id: i32, disabled: bool) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let updated = connection.execute(
+                "UPDATE users SET disabled=?1 WHERE id=?2",
+                params![disabled, id],
+            )?;
+
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::delete_user", skip(self))]
+    async fn delete_user(&self, id: i32) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            // The events stay in the audit log, without who caused them
+            transaction.execute(
+                "UPDATE audit_log SET actor_id=NULL, actor_login=NULL, ip=NULL, user_agent=NULL
+                 WHERE actor_id=?1
+                    OR actor_login=(SELECT login FROM users WHERE id=?1)",
+                [id],
+            )?;
+            transaction.execute("DELETE FROM sessions WHERE user_id=?1", [id])?;
+            transaction.execute("DELETE FROM api_tokens WHERE user_id=?1", [id])?;
+            transaction.execute("DELETE FROM password_resets WHERE user_id=?1", [id])?;
+            transaction.execute("DELETE FROM usage WHERE user_id=?1", [id])?;
+            transaction.execute("DELETE FROM user_quotas WHERE user_id=?1", [id])?;
+            let removed = transaction.execute("DELETE FROM users WHERE id=?1", [id])?;
+            transaction.commit()?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::set_password_hash", skip(self, password_hash))]
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), DbError> {
+        let password_hash = password_hash.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE users SET password_hash=?1 WHERE id=?2",
+                params![password_hash, user_id],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::create_session", skip(self, id))]
+    async fn create_session(
+        &self,
+        id: &str,
+        user_id: i32,
+        valid_until: DateTime<Utc>,
+        created_at: DateTime<Utc>,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<(), DbError> {
+        let id = id.to_owned();
+        let user_agent = user_agent.map(str::to_owned);
+        let ip = ip.map(str::to_owned);
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO sessions(id, user_id, valid_until, created_at, last_seen_at, user_agent, ip)
+                 VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6)",
+                params![
+                    id,
+                    user_id,
+                    valid_until.timestamp(),
+                    created_at.timestamp(),
+                    user_agent,
+                    ip
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::touch_session", skip(self, id))]
+    async fn touch_session(
+        &self,
+        id: &str,
+        last_seen_at: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let id = id.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE sessions SET last_seen_at=?1, valid_until=?2 WHERE id=?3",
+                params![last_seen_at.timestamp(), valid_until.timestamp(), id],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_sessions", skip(self))]
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, user_id, valid_until, created_at, last_seen_at, user_agent, ip
+                 FROM sessions WHERE user_id=?1 ORDER BY last_seen_at DESC",
+            )?;
+            let sessions = statement
+                .query_map([user_id], session_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(sessions)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_expired_sessions", skip(self))]
+    async fn remove_expired_sessions(&self, now: DateTime<Utc>) -> Result<usize, DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE valid_until < ?1",
+                params![now.timestamp()],
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_session", skip(self))]
+    async fn remove_session(&self, id: &str) -> Result<(), DbError> {
+        let id = id.to_owned();
+
+        self.run(move |connection| {
+            connection.execute("DELETE FROM sessions WHERE id=?1", params![id])?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_user_sessions", skip(self, except))]
+    async fn remove_user_sessions(
+        &self,
+        user_id: i32,
+        except: Option<&str>,
+    ) -> Result<usize, DbError> {
+        let except = except.map(str::to_owned);
+
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE user_id=?1 AND id IS NOT ?2",
+                params![user_id, except],
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_session_by_id", skip(self))]
+    async fn get_session_by_id(&self, id: &str) -> Result<SessionInfo, DbError> {
+        let id = id.to_owned();
+
+        self.run(move |connection| {
+            connection.query_row(
+                "SELECT id, user_id, valid_until, created_at, last_seen_at, user_agent, ip
+                 FROM sessions WHERE id=?1",
+                [id],
+                session_from_row,
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::create_api_token", skip(self, token_hash))]
+    async fn create_api_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiTokenInfo, DbError> {
+        let name = name.to_owned();
+        let token_hash = token_hash.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO api_tokens(user_id, name, token_hash, created_at, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    user_id,
+                    name,
+                    token_hash,
+                    created_at.timestamp(),
+                    expires_at.map(|e| e.timestamp())
+                ],
+            )?;
+
+            Ok(ApiTokenInfo {
+                id: connection.last_insert_rowid(),
+                user_id,
+                name,
+                created_at,
+                expires_at,
+                last_used_at: None,
+            })
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_api_tokens", skip(self))]
+    async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiTokenInfo>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, user_id, name, created_at, expires_at, last_used_at
+                 FROM api_tokens WHERE user_id=?1 ORDER BY id",
+            )?;
+            let tokens = statement
+                .query_map([user_id], api_token_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(tokens)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_api_token", skip(self))]
+    async fn remove_api_token(&self, user_id: i32, id: i64) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let removed = connection.execute(
+                "DELETE FROM api_tokens WHERE id=?1 AND user_id=?2",
+                params![id, user_id],
+            )?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_api_token_by_hash", skip(self, token_hash))]
+    async fn get_api_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiTokenInfo>, DbError> {
+        let token_hash = token_hash.to_owned();
+
+        self.run(move |connection| {
+            let result = connection.query_row(
+                "SELECT id, user_id, name, created_at, expires_at, last_used_at
+                 FROM api_tokens WHERE token_hash=?1",
+                [token_hash],
+                api_token_from_row,
+            );
+
+            match result {
+                Ok(info) => Ok(Some(info)),
+                Err(err) => match err {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    _ => Err(err),
+                },
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::set_api_token_last_used", skip(self))]
+    async fn set_api_token_last_used(
+        &self,
+        id: i64,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE api_tokens SET last_used_at=?1 WHERE id=?2",
+                params![last_used_at.timestamp(), id],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::create_password_reset", skip(self, token_hash))]
+    async fn create_password_reset(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let token_hash = token_hash.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM password_resets WHERE user_id=?1",
+                params![user_id],
+            )?;
+            connection.execute(
+                "INSERT INTO password_resets(token_hash, user_id, created_at, expires_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    token_hash,
+                    user_id,
+                    created_at.timestamp(),
+                    expires_at.timestamp()
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::consume_password_reset", skip(self, token_hash))]
+    async fn consume_password_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i32>, DbError> {
+        let token_hash = token_hash.to_owned();
+
+        self.run(move |connection| {
+            // Deleted and read in one statement so that a token can only be redeemed once
+            let result = connection.query_row(
+                "DELETE FROM password_resets WHERE token_hash=?1 RETURNING user_id, expires_at",
+                [&token_hash],
+                |r| Ok((r.get::<_, i32>(0)?, r.get::<_, i64>(1)?)),
+            );
+            let (user_id, expires_at) = match result {
+                Ok(reset) => reset,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(err) => return Err(err),
+            };
+
+            if now.timestamp() > expires_at {
+                Ok(None)
+            } else {
+                Ok(Some(user_id))
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_login_failures", skip(self))]
+    async fn get_login_failures(
+        &self,
+        scope: &str,
+        key: &str,
+    ) -> Result<Option<LoginFailures>, DbError> {
+        let scope = scope.to_owned();
+        let key = key.to_owned();
+
+        self.run(move |connection| {
+            let result = connection.query_row(
+                "SELECT failures, last_failure_at, locked_until FROM login_failures
+                 WHERE scope=?1 AND key=?2",
+                params![scope, key],
+                |r| {
+                    Ok(LoginFailures {
+                        failures: r.get(0)?,
+                        last_failure_at: get_timestamp(r, 1)?,
+                        locked_until: get_optional_timestamp(r, 2)?,
+                    })
+                },
+            );
+
+            match result {
+                Ok(failures) => Ok(Some(failures)),
+                Err(err) => match err {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    _ => Err(err),
+                },
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::add_login_failure", skip(self))]
+    async fn add_login_failure(
+        &self,
+        scope: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        forget_before: DateTime<Utc>,
+    ) -> Result<u32, DbError> {
+        let scope = scope.to_owned();
+        let key = key.to_owned();
+
+        // A single statement so that concurrent failures are all counted
+        self.run(move |connection| {
+            connection.query_row(
+                "INSERT INTO login_failures(scope, key, failures, last_failure_at, locked_until)
+                 VALUES (?1, ?2, 1, ?3, NULL)
+                 ON CONFLICT(scope, key) DO UPDATE SET
+                     failures = CASE WHEN last_failure_at < ?4 THEN 1 ELSE failures + 1 END,
+                     locked_until = CASE WHEN last_failure_at < ?4 THEN NULL ELSE locked_until END,
+                     last_failure_at = ?3
+                 RETURNING failures",
+                params![scope, key, now.timestamp(), forget_before.timestamp()],
+                |r| r.get(0),
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::lock_login", skip(self))]
+    async fn lock_login(
+        &self,
+        scope: &str,
+        key: &str,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let scope = scope.to_owned();
+        let key = key.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE login_failures SET locked_until = max(coalesce(locked_until, 0), ?3)
+                 WHERE scope=?1 AND key=?2",
+                params![scope, key, locked_until.timestamp()],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::clear_login_failures", skip(self))]
+    async fn clear_login_failures(&self, scope: &str, key: &str) -> Result<(), DbError> {
+        let scope = scope.to_owned();
+        let key = key.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM login_failures WHERE scope=?1 AND key=?2",
+                params![scope, key],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_expired_login_failures", skip(self))]
+    async fn remove_expired_login_failures(
+        &self,
+        forget_before: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<usize, DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM login_failures
+                 WHERE last_failure_at < ?1 AND (locked_until IS NULL OR locked_until <= ?2)",
+                params![forget_before.timestamp(), now.timestamp()],
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::record_lockout", skip(self))]
+    async fn record_lockout(
+        &self,
+        scope: &str,
+        key: &str,
+        failures: u32,
+        created_at: DateTime<Utc>,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let scope = scope.to_owned();
+        let key = key.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO lockouts(scope, key, failures, created_at, locked_until)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    scope,
+                    key,
+                    failures,
+                    created_at.timestamp(),
+                    locked_until.timestamp()
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_lockouts", skip(self))]
+    async fn list_lockouts(&self, limit: u32) -> Result<Vec<LockoutInfo>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, scope, key, failures, created_at, locked_until
+                 FROM lockouts ORDER BY id DESC LIMIT ?1",
+            )?;
+            let lockouts = statement
+                .query_map([limit], |r| {
+                    Ok(LockoutInfo {
+                        id: r.get(0)?,
+                        scope: r.get(1)?,
+                        key: r.get(2)?,
+                        failures: r.get(3)?,
+                        created_at: get_timestamp(r, 4)?,
+                        locked_until: get_timestamp(r, 5)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(lockouts)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_old_lockouts", skip(self))]
+    async fn remove_old_lockouts(&self, keep: u32) -> Result<usize, DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM lockouts
+                 WHERE id NOT IN (SELECT id FROM lockouts ORDER BY id DESC LIMIT ?1)",
+                [keep],
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::create_invite", skip(self, code_hash))]
+    async fn create_invite(
+        &self,
+        code_hash: &str,
+        created_by: i32,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        max_uses: u32,
+    ) -> Result<InviteInfo, DbError> {
+        let code_hash = code_hash.to_owned();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO invites(code_hash, created_by, created_at, expires_at, max_uses)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    code_hash,
+                    created_by,
+                    created_at.timestamp(),
+                    expires_at.timestamp(),
+                    max_uses
+                ],
+            )?;
+
+            Ok(InviteInfo {
+                id: connection.last_insert_rowid(),
+                created_by,
+                created_at,
+                expires_at,
+                max_uses,
+                uses: 0,
+            })
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_invites", skip(self))]
+    async fn list_invites(&self) -> Result<Vec<InviteInfo>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, created_by, created_at, expires_at, max_uses, uses
+                 FROM invites ORDER BY id",
+            )?;
+            let invites = statement
+                .query_map([], invite_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(invites)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_invite", skip(self))]
+    async fn remove_invite(&self, id: i64) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let removed = connection.execute("DELETE FROM invites WHERE id=?1", params![id])?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::add_audit_entry", skip(self, entry))]
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<(), DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO audit_log(created_at, event, actor_id, actor_login, ip, user_agent,
+                     outcome, details)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    entry.created_at.timestamp(),
+                    entry.event,
+                    entry.actor_id,
+                    entry.actor_login,
+                    entry.ip,
+                    entry.user_agent,
+                    entry.outcome,
+                    entry.details
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::list_audit_entries", skip(self))]
+    async fn list_audit_entries(&self, filter: AuditFilter) -> Result<Vec<AuditRecord>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, created_at, event, actor_id, actor_login, ip, user_agent, outcome,
+                     details
+                 FROM audit_log
+                 WHERE (?1 IS NULL OR actor_id=?1)
+                 AND (?2 IS NULL OR event=?2)
+                 AND (?3 IS NULL OR outcome=?3)
+                 AND (?4 IS NULL OR created_at>=?4)
+                 AND (?5 IS NULL OR created_at<?5)
+                 AND (?6 IS NULL OR id<?6)
+                 ORDER BY id DESC LIMIT ?7",
+            )?;
+            let entries = statement
+                .query_map(
+                    params![
+                        filter.actor_id,
+                        filter.event,
+                        filter.outcome,
+                        filter.since.map(|s| s.timestamp()),
+                        filter.until.map(|u| u.timestamp()),
+                        filter.before_id,
+                        filter.limit
+                    ],
+                    audit_record_from_row,
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(entries)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_audit_entries_before", skip(self))]
+    async fn remove_audit_entries_before(&self, before: DateTime<Utc>) -> Result<usize, DbError> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM audit_log WHERE created_at < ?1",
+                params![before.timestamp()],
+            )
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::add_usage", skip(self))]
+    async fn add_usage(&self, record: UsageRecord) -> Result<(), DbError> {
+        self.run(move |connection| insert_usage(connection, &record))
+            .await
+    }
+
+    #[instrument(name = "UserDb::reserve_usage", skip(self, record), fields(user_id = record.user_id))]
+    async fn reserve_usage(
+        &self,
+        record: UsageRecord,
+        quotas: Vec<(DateTime<Utc>, QuotaLimits)>,
+    ) -> Result<UsageReservation, DbError> {
+        self.run(move |connection| {
+            // Take the write lock before summing the usage, so that concurrent reservations are
+            // checked one after the other
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            for (quota, (since, limits)) in quotas.iter().enumerate() {
+                let used = sum_usage_since(&transaction, record.user_id, *since)?;
+                if let Some(resource) = limits.exhausted(&used) {
+                    return Ok(UsageReservation::Exhausted { quota, resource });
+                }
+            }
+            insert_usage(&transaction, &record)?;
+            transaction.commit()?;
+
+            Ok(UsageReservation::Reserved)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::sum_usage", skip(self))]
+    async fn sum_usage(&self, user_id: i32, since: DateTime<Utc>) -> Result<Usage, DbError> {
+        self.run(move |connection| sum_usage_since(connection, user_id, since))
+            .await
+    }
+
+    #[instrument(name = "UserDb::list_usage", skip(self))]
+    async fn list_usage(&self, user_id: i32) -> Result<Vec<UsageRecord>, DbError> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT user_id, created_at, requests, megapixels, compute_seconds
+                 FROM usage WHERE user_id=?1 ORDER BY id",
+            )?;
+            let records = statement
+                .query_map([user_id], usage_record_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(records)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::get_quota_override", skip(self))]
+    async fn get_quota_override(&self, user_id: i32) -> Result<Option<QuotaOverride>, DbError> {
+        self.run(move |connection| {
+            let result = connection.query_row(
+                "SELECT daily_requests, daily_megapixels, daily_compute_seconds, monthly_requests,
+                     monthly_megapixels, monthly_compute_seconds
+                 FROM user_quotas WHERE user_id=?1",
+                [user_id],
+                quota_override_from_row,
+            );
+
+            match result {
+                Ok(quotas) => Ok(Some(quotas)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::set_quota_override", skip(self))]
+    async fn set_quota_override(
+        &self,
+        user_id: i32,
+        quotas: QuotaOverride,
+    ) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let changed = connection.execute(
+                "INSERT INTO user_quotas(user_id, daily_requests, daily_megapixels,
+                     daily_compute_seconds, monthly_requests, monthly_megapixels,
+                     monthly_compute_seconds)
+                 SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7 WHERE EXISTS(SELECT 1 FROM users WHERE id=?1)
+                 ON CONFLICT(user_id) DO UPDATE SET
+                     daily_requests=excluded.daily_requests,
+                     daily_megapixels=excluded.daily_megapixels,
+                     daily_compute_seconds=excluded.daily_compute_seconds,
+                     monthly_requests=excluded.monthly_requests,
+                     monthly_megapixels=excluded.monthly_megapixels,
+                     monthly_compute_seconds=excluded.monthly_compute_seconds",
+                params![
+                    user_id,
+                    quotas.daily_requests,
+                    quotas.daily_megapixels,
+                    quotas.daily_compute_seconds,
+                    quotas.monthly_requests,
+                    quotas.monthly_megapixels,
+                    quotas.monthly_compute_seconds
+                ],
+            )?;
+
+            Ok(changed > 0)
+        })
+        .await
+    }
+
+    #[instrument(name = "UserDb::remove_quota_override", skip(self))]
+    async fn remove_quota_override(&self, user_id: i32) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let removed =
+                connection.execute("DELETE FROM user_quotas WHERE user_id=?1", [user_id])?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+}
+
+/// Initialized database in a temporary file for the tests of the stores, removed when dropped
+#[cfg(test)]
+pub struct TemporaryDb {
+    pub user_db: super::UserDb,
+    path: PathBuf,
+}
+
+#[cfg(test)]
+impl TemporaryDb {
+    pub async fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mirnet-test-{}.db", uuid::Uuid::new_v4()));
+        let options = DatabaseOptions {
+            db_storage: super::StorageKind::Sqlite,
+            db_path: path.clone(),
+            db_pool_size: 4,
+        };
+        let user_db = SqliteUserDb::open(&options).unwrap();
+        user_db.initialize().await.unwrap();
+
+        Self {
+            user_db: std::sync::Arc::new(user_db),
+            path,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TemporaryDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_times(times: &[Option<i64>]) -> Vec<Result<Option<DateTime<Utc>>, rusqlite::Error>> {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute(
+                "create table times (id integer primary key, at integer)",
+                [],
+            )
+            .unwrap();
+        for time in times {
+            connection
+                .execute("INSERT INTO times(at) VALUES (?1)", params![time])
+                .unwrap();
+        }
+
+        let mut statement = connection
+            .prepare("SELECT at FROM times ORDER BY id")
+            .unwrap();
+        let rows = statement
+            .query_map([], |r| Ok(get_optional_timestamp(r, 0)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        rows
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let times = [
+            Utc.ymd(1, 1, 1).and_hms(0, 0, 0),
+            Utc.ymd(1901, 12, 13).and_hms(20, 45, 51),
+            Utc.ymd(1969, 12, 31).and_hms(23, 59, 59),
+            Utc.ymd(1970, 1, 1).and_hms(0, 0, 0),
+            Utc.ymd(2038, 1, 19).and_hms(3, 14, 8),
+            Utc.ymd(9999, 12, 31).and_hms(23, 59, 59),
+            Utc.ymd(200000, 1, 1).and_hms(0, 0, 0),
+        ];
+
+        let stored: Vec<_> = times.iter().map(|t| Some(t.timestamp())).collect();
+        let read = read_times(&stored);
+        for (time, read) in times.iter().zip(read) {
+            assert_eq!(read.unwrap(), Some(*time));
+        }
+    }
+
+    #[test]
+    fn null_timestamps_read_as_none() {
+        let read = read_times(&[None]);
+        assert_eq!(read.into_iter().next().unwrap().unwrap(), None);
+    }
+
+    #[test]
+    fn out_of_range_timestamps_are_errors() {
+        for read in read_times(&[Some(i64::MAX), Some(i64::MIN)]) {
+            assert!(matches!(
+                read,
+                Err(rusqlite::Error::IntegralValueOutOfRange(0, _))
+            ));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Duration, Utc};
+use structopt::StructOpt;
+use tracing::warn;
+
+use super::user_db::UserDb;
+
+/// Failures are counted per login name and per client IP address
+const LOGIN_SCOPE: &str = "login";
+const IP_SCOPE: &str = "ip";
+
+/// Number of lockouts kept for review, the older ones are removed by the periodic cleanup
+const KEPT_LOCKOUTS: u32 = 10_000;
+
+/// Configuration of the protection against password guessing
+#[derive(Debug, Clone, StructOpt)]
+pub struct ThrottleOptions {
+    /// Consecutive failed logins on an account before it's temporarily locked
+    #[structopt(long, default_value = "5")]
+    pub login_max_failures: u32,
+
+    /// Failed logins from an IP address before it's temporarily locked
+    #[structopt(long, default_value = "20")]
+    pub login_max_failures_per_ip: u32,
+
+    /// Duration of the first lockout in seconds, doubled on each new failure
+    #[structopt(long, default_value = "30")]
+    pub login_lockout_seconds: u32,
+
+    /// Maximum duration of a lockout in minutes, failures older than this are forgotten
+    #[structopt(long, default_value = "60")]
+    pub login_max_lockout_minutes: u32,
+}
+
+impl ThrottleOptions {
+    fn max_lockout(&self) -> Duration {
+        Duration::minutes(self.login_max_lockout_minutes.into())
+    }
+
+    /// Lockout after `failures` consecutive failures, `None` while under `max_failures`
+    fn lockout(&self, failures: u32, max_failures: u32) -> Option<Duration> {
+        if failures < max_failures {
+            return None;
+        }
+
+        let exponent = (failures - max_failures).min(20);
+        let seconds = i64::from(self.login_lockout_seconds) << exponent;
+        Some(Duration::seconds(seconds).min(self.max_lockout()))
+    }
+}
+
+/// Remove the failure counters that the next failure would reset anyway and the oldest
+/// lockouts, so that failed logins with random names or addresses can't grow the database
+/// forever. Returns the number of removed counters and lockouts
+pub async fn purge_login_failures(
+    user_db: &UserDb,
+    options: &ThrottleOptions,
+    now: DateTime<Utc>,
+) -> AnyResult<(usize, usize)> {
+    let failures = user_db
+        .remove_expired_login_failures(now - options.max_lockout(), now)
+        .await?;
+    let lockouts = user_db.remove_old_lockouts(KEPT_LOCKOUTS).await?;
+
+    Ok((failures, lockouts))
+}
+
+/// Failure counters of a login attempt
+pub struct LoginThrottle<'a> {
+    user_db: &'a UserDb,
+    options: &'a ThrottleOptions,
+    keys: Vec<(&'static str, String, u32)>,
+}
+
+impl<'a> LoginThrottle<'a> {
+    /// `login` is expected to be normalized
+    pub fn new(
+        user_db: &'a UserDb,
+        options: &'a ThrottleOptions,
+        login: &str,
+        ip: Option<&str>,
+    ) -> Self {
+        let mut keys = vec![(LOGIN_SCOPE, login.to_string(), options.login_max_failures)];
+        if let Some(ip) = ip {
+            keys.push((IP_SCOPE, ip.to_string(), options.login_max_failures_per_ip));
+        }
+
+        Self {
+            user_db,
+            options,
+            keys,
+        }
+    }
+
+    /// End of the current lockout of the account or the IP address, if any
+    pub async fn locked_until(&self, now: DateTime<Utc>) -> AnyResult<Option<DateTime<Utc>>> {
+        let mut locked_until = None;
+        for (scope, key, _) in &self.keys {
+            if let Some(failures) = self.user_db.get_login_failures(scope, key).await? {
+                locked_until = locked_until.max(failures.locked_until.filter(|l| *l > now));
+            }
+        }
+
+        Ok(locked_until)
+    }
+
+    pub async fn record_failure(&self, now: DateTime<Utc>) -> AnyResult<()> {
+        let forget_before = now - self.options.max_lockout();
+        for (scope, key, max_failures) in &self.keys {
+            let failures = self
+                .user_db
+                .add_login_failure(scope, key, now, forget_before)
+                .await?;
+
+            if let Some(lockout) = self.options.lockout(failures, *max_failures) {
+                let locked_until = now + lockout;
+                warn!(scope, %key, failures, %locked_until, "Too many failed logins, locking");
+                self.user_db.lock_login(scope, key, locked_until).await?;
+                self.user_db
+                    .record_lockout(scope, key, failures, now, locked_until)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reset the counter of the account, the one of the IP address is kept so that knowing one
+    /// password doesn't allow guessing the others
+    pub async fn record_success(&self) -> AnyResult<()> {
+        for (scope, key, _) in &self.keys {
+            if *scope == LOGIN_SCOPE {
+                self.user_db.clear_login_failures(scope, key).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{MemoryUserDb, TemporaryDb};
+    use std::sync::Arc;
+
+    async fn check_purge(user_db: &UserDb) {
+        let options = ThrottleOptions::from_iter(&["test"]);
+        let now = Utc::now();
+        let old = now - options.max_lockout() - Duration::minutes(1);
+        let forget_before = now - options.max_lockout();
+
+        user_db
+            .add_login_failure(LOGIN_SCOPE, "old", old, forget_before)
+            .await
+            .unwrap();
+        user_db
+            .add_login_failure(LOGIN_SCOPE, "locked", old, forget_before)
+            .await
+            .unwrap();
+        user_db
+            .lock_login(LOGIN_SCOPE, "locked", now + Duration::minutes(5))
+            .await
+            .unwrap();
+        user_db
+            .add_login_failure(IP_SCOPE, "recent", now, forget_before)
+            .await
+            .unwrap();
+
+        let (failures, lockouts) = purge_login_failures(user_db, &options, now).await.unwrap();
+
+        assert_eq!((failures, lockouts), (1, 0));
+        let remaining = |scope: &'static str, key: &'static str| async move {
+            user_db
+                .get_login_failures(scope, key)
+                .await
+                .unwrap()
+                .is_some()
+        };
+        assert!(!remaining(LOGIN_SCOPE, "old").await);
+        assert!(remaining(LOGIN_SCOPE, "locked").await);
+        assert!(remaining(IP_SCOPE, "recent").await);
+
+        // Once its lock is over the counter goes too
+        let later = now + Duration::minutes(10);
+        let (failures, _) = purge_login_failures(user_db, &options, later)
+            .await
+            .unwrap();
+        assert_eq!(failures, 1);
+        assert!(!remaining(LOGIN_SCOPE, "locked").await);
+        assert!(remaining(IP_SCOPE, "recent").await);
+    }
+
+    async fn check_old_lockouts(user_db: &UserDb) {
+        let now = Utc::now();
+        for i in 0..5 {
+            user_db
+                .record_lockout(LOGIN_SCOPE, &format!("user{}", i), 5, now, now)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(user_db.remove_old_lockouts(2).await.unwrap(), 3);
+        assert_eq!(user_db.remove_old_lockouts(2).await.unwrap(), 0);
+        let keys: Vec<_> = user_db
+            .list_lockouts(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|lockout| lockout.key)
+            .collect();
+        assert_eq!(keys, ["user4", "user3"]);
+    }
+
+    #[actix_web::test]
+    async fn purge_memory() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        check_purge(&user_db).await;
+        check_old_lockouts(&user_db).await;
+    }
+
+    #[actix_web::test]
+    async fn purge_sqlite() {
+        let db = TemporaryDb::new().await;
+        check_purge(&db.user_db).await;
+        check_old_lockouts(&db.user_db).await;
+    }
+}
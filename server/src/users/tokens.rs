@@ -0,0 +1,145 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, instrument};
+
+use super::user_db::{ApiTokenInfo, UserDb};
+use super::{AuthenticatedUser, LoginResponse, SessionUser};
+
+/// Prefix of generated tokens, makes them easy to recognize in configuration files
+const TOKEN_PREFIX: &str = "mirnet_";
+
+/// Generate a new random token starting with `prefix`, only its hash is stored
+pub fn generate_token(prefix: &str) -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    format!("{}{}", prefix, hex::encode(bytes))
+}
+
+/// Hash of a token as stored in the database, tokens are random so a fast hash is enough
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Serialize)]
+pub struct ApiTokenResponse {
+    id: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiTokenInfo> for ApiTokenResponse {
+    fn from(info: ApiTokenInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            created_at: info.created_at,
+            expires_at: info.expires_at,
+            last_used_at: info.last_used_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenArgs {
+    name: String,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct CreateTokenResponse {
+    /// The token itself, only returned once at creation
+    token: String,
+    info: ApiTokenResponse,
+}
+
+#[instrument(
+    name = "Create API Token",
+    skip(session, args, user_db),
+    fields(user_id = session.0.user.id)
+)]
+pub async fn create_token(
+    session: SessionUser,
+    args: web::Json<CreateTokenArgs>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    let name = args.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().json(LoginResponse::error_display("Name is required"));
+    }
+
+    let created_at = Utc::now();
+    let expires_at = match args.expires_in_days {
+        Some(days) => match created_at.checked_add_signed(Duration::days(days.into())) {
+            Some(expires_at) => Some(expires_at),
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(LoginResponse::error_display("Invalid expiration"))
+            }
+        },
+        None => None,
+    };
+    let token = generate_token(TOKEN_PREFIX);
+
+    match user_db
+        .create_api_token(
+            session.0.user.id,
+            name,
+            &hash_token(&token),
+            created_at,
+            expires_at,
+        )
+        .await
+    {
+        Ok(info) => {
+            info!(token_id = info.id, "Created API token");
+            HttpResponse::Ok().json(CreateTokenResponse {
+                token,
+                info: info.into(),
+            })
+        }
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(name = "List API Tokens", skip(auth, user_db), fields(user_id = auth.user.id))]
+pub async fn list_tokens(auth: AuthenticatedUser, user_db: web::Data<UserDb>) -> HttpResponse {
+    match user_db.list_api_tokens(auth.user.id).await {
+        Ok(tokens) => HttpResponse::Ok().json(
+            tokens
+                .into_iter()
+                .map(ApiTokenResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().json(LoginResponse::error(err)),
+    }
+}
+
+#[instrument(
+    name = "Revoke API Token",
+    skip(session, user_db),
+    fields(user_id = session.0.user.id)
+)]
+pub async fn revoke_token(
+    session: SessionUser,
+    token_id: web::Path<i64>,
+    user_db: web::Data<UserDb>,
+) -> HttpResponse {
+    match user_db
+        .remove_api_token(session.0.user.id, token_id.into_inner())
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(LoginResponse::ok()),
+        Ok(false) => HttpResponse::NotFound().json(LoginResponse::error_display("Unknown token")),
+        Err(err) => {
+            error!(?err, "Failed to revoke API token");
+            HttpResponse::InternalServerError().json(LoginResponse::error(err))
+        }
+    }
+}
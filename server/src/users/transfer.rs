@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result as AnyResult};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use super::normalize_login;
+use super::user_db::{Role, UserDb};
+
+/// Version of the export format, bumped when it changes incompatibly
+const EXPORT_VERSION: u32 = 1;
+
+/// Account moved between hosts, the password hash is kept so that passwords keep working
+#[derive(Serialize, Deserialize)]
+struct ExportedUser {
+    login: String,
+    password_hash: String,
+    role: Role,
+    disabled: bool,
+    /// Login of the user whose invite was used, ids aren't kept across hosts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invited_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsersExport {
+    version: u32,
+    users: Vec<ExportedUser>,
+}
+
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Accounts whose login already exists, they are left untouched
+    pub skipped: usize,
+}
+
+/// Write all the accounts to a JSON file, returns the number of exported users
+#[instrument(name = "Export Users", skip(user_db))]
+pub async fn export_users(user_db: &UserDb, path: &Path) -> AnyResult<usize> {
+    let users = user_db.list_users().await?;
+    let logins = users
+        .iter()
+        .map(|user| (user.id, user.login.clone()))
+        .collect::<HashMap<_, _>>();
+    let users = users
+        .into_iter()
+        .map(|user| ExportedUser {
+            invited_by: user.invited_by.and_then(|id| logins.get(&id).cloned()),
+            login: user.login,
+            password_hash: user.password_hash,
+            role: user.role,
+            disabled: user.disabled,
+        })
+        .collect::<Vec<_>>();
+    let count = users.len();
+
+    if path.exists() {
+        bail!("{:?} already exists", path);
+    }
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{:?} isn't a file path", path))?;
+    // Written next to the destination and renamed once complete, so that an interrupted export
+    // never leaves a truncated file behind
+    let temporary = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+    let export = UsersExport {
+        version: EXPORT_VERSION,
+        users,
+    };
+    if let Err(error) = write_export(&temporary, &export) {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+    fs::rename(&temporary, path)
+        .with_context(|| format!("Can't move export file to {:?}", path))?;
+    info!(count, "Exported users");
+
+    Ok(count)
+}
+
+fn write_export(path: &Path, export: &UsersExport) -> AnyResult<()> {
+    // The file holds password hashes, it's only readable by its owner
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options
+        .open(path)
+        .with_context(|| format!("Can't create export file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, export)?;
+    writer.into_inner()?.sync_all()?;
+
+    Ok(())
+}
+
+/// Create the accounts of a file written by `export_users`
+#[instrument(name = "Import Users", skip(user_db))]
+pub async fn import_users(user_db: &UserDb, path: &Path) -> AnyResult<ImportSummary> {
+    let export: UsersExport = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if export.version != EXPORT_VERSION {
+        bail!(
+            "Unsupported export version {}, expected {}",
+            export.version,
+            EXPORT_VERSION
+        );
+    }
+
+    let mut summary = ImportSummary {
+        imported: 0,
+        skipped: 0,
+    };
+    for user in export.users {
+        let login = normalize_login(&user.login);
+        // Users are exported in creation order, so the inviter is already on this host
+        let invited_by = match &user.invited_by {
+            Some(inviter) => user_db
+                .get_user_by_login(&normalize_login(inviter))
+                .await?
+                .map(|inviter| inviter.id),
+            None => None,
+        };
+        if user_db
+            .import_user(
+                &login,
+                &user.password_hash,
+                user.role,
+                user.disabled,
+                invited_by,
+            )
+            .await?
+        {
+            summary.imported += 1;
+        } else {
+            warn!(%login, "User already exists, skipping");
+            summary.skipped += 1;
+        }
+    }
+    info!(summary.imported, summary.skipped, "Imported users");
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{MemoryUserDb, TemporaryDb};
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+
+    /// Register `invitee` with an invite of `inviter` and move both accounts to `target`
+    async fn check_inviter_transfer(source: &UserDb, target: &UserDb) {
+        let now = Utc::now();
+        assert!(source.register("inviter", "hash").await.unwrap());
+        let inviter = source.get_user_by_login("inviter").await.unwrap().unwrap();
+        source
+            .create_invite("code", inviter.id, now, now + Duration::days(1), 1)
+            .await
+            .unwrap();
+        source
+            .register_with_invite("invitee", "hash", "code", now)
+            .await
+            .unwrap();
+        let invitee = source.get_user_by_login("invitee").await.unwrap().unwrap();
+        assert_eq!(invitee.invited_by, Some(inviter.id));
+
+        let path =
+            std::env::temp_dir().join(format!("mirnet-export-{}.json", uuid::Uuid::new_v4()));
+        assert_eq!(export_users(source, &path).await.unwrap(), 2);
+        // Shift the ids on the target, so that the inviter is matched by login and not by id
+        assert!(target.register("other", "hash").await.unwrap());
+        let summary = import_users(target, &path).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.unwrap().imported, 2);
+
+        let inviter = target.get_user_by_login("inviter").await.unwrap().unwrap();
+        let invitee = target.get_user_by_login("invitee").await.unwrap().unwrap();
+        assert_eq!(inviter.invited_by, None);
+        assert_eq!(invitee.invited_by, Some(inviter.id));
+    }
+
+    #[actix_web::test]
+    async fn export_file() {
+        let user_db: UserDb = Arc::new(MemoryUserDb::default());
+        assert!(user_db.register("user", "hash").await.unwrap());
+        let directory =
+            std::env::temp_dir().join(format!("mirnet-export-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&directory).unwrap();
+        let path = directory.join("users.json");
+
+        assert_eq!(export_users(&user_db, &path).await.unwrap(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // An existing file is kept as is
+        fs::write(&path, "kept").unwrap();
+        assert!(export_users(&user_db, &path).await.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+        // Only the export is left, the temporary files are gone
+        let files = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(files, 1);
+    }
+
+    #[actix_web::test]
+    async fn transfer_memory() {
+        let source: UserDb = Arc::new(MemoryUserDb::default());
+        let target: UserDb = Arc::new(MemoryUserDb::default());
+        check_inviter_transfer(&source, &target).await;
+    }
+
+    #[actix_web::test]
+    async fn transfer_sqlite() {
+        let source = TemporaryDb::new().await;
+        let target = TemporaryDb::new().await;
+        check_inviter_transfer(&source.user_db, &target.user_db).await;
+    }
+}
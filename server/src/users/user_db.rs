@@ -1,166 +1,635 @@
-use anyhow::Result as AnyResult;
-use chrono::{DateTime, TimeZone, Utc};
-use futures::lock::{Mutex, MutexLockFuture};
-use rusqlite::{params, Connection};
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{info, instrument};
 
-#[derive(Clone)]
-pub struct UserDb {
-    connection: Arc<Mutex<Connection>>,
+use actix_web::error::BlockingError;
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::ToSql;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use thiserror::Error;
+use ts_rs::TS;
+
+use super::memory_db::MemoryUserDb;
+use super::quotas::QuotaLimits;
+use super::sqlite_db::SqliteUserDb;
+
+/// Where the users, sessions and tokens are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// A SQLite database file
+    Sqlite,
+    /// Process memory, everything is lost when the server stops
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!(
+                "invalid storage '{}', expected 'sqlite' or 'memory'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct DatabaseOptions {
+    /// Storage of the users: "sqlite" or "memory"
+    #[structopt(long, env = "MIRNET_DB_STORAGE", default_value = "sqlite")]
+    pub db_storage: StorageKind,
+
+    /// Path of the SQLite database of the users
+    #[structopt(
+        long,
+        env = "MIRNET_DB_PATH",
+        default_value = "users.db",
+        parse(from_os_str)
+    )]
+    pub db_path: PathBuf,
+
+    /// Maximum number of connections to the users database, one writer at a time
+    #[structopt(long, env = "MIRNET_DB_POOL_SIZE", default_value = "8")]
+    pub db_pool_size: u32,
 }
 
+impl DatabaseOptions {
+    /// Open the configured storage
+    pub fn open(&self) -> AnyResult<UserDb> {
+        let user_db: UserDb = match self.db_storage {
+            StorageKind::Sqlite => Arc::new(SqliteUserDb::open(self)?),
+            StorageKind::Memory => Arc::new(MemoryUserDb::default()),
+        };
+
+        Ok(user_db)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("No database connection available: {0}")]
+    Pool(#[from] r2d2::Error),
+    #[error("Database task was cancelled")]
+    Blocking(#[from] BlockingError),
+    #[error("Not found")]
+    NotFound,
+}
+
+/// Shared handle on the storage used by the endpoints
+pub type UserDb = Arc<dyn UserStore>;
+
+/// Rights of a user, administrators can manage the other accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("invalid role '{}'", s)),
+        }
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+#[derive(Clone)]
 pub struct UserInfo {
     pub id: i32,
     pub login: String,
     pub password_hash: String,
+    pub role: Role,
+    pub disabled: bool,
+    /// Unknown for the accounts created before it was recorded
+    pub created_at: Option<DateTime<Utc>>,
+    /// User who created the invite this account registered with
+    pub invited_by: Option<i32>,
 }
 
+#[derive(Clone)]
 pub struct SessionInfo {
     pub id: String,
     pub user_id: i32,
     pub valid_until: DateTime<Utc>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Consecutive failed logins for a login name or an IP address
+#[derive(Clone)]
+pub struct LoginFailures {
+    pub failures: u32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
 }
 
-impl UserDb {
-    pub fn new(connection: Connection) -> Self {
-        Self {
-            connection: Arc::new(Mutex::new(connection)),
+#[derive(Clone)]
+pub struct LockoutInfo {
+    pub id: i64,
+    pub scope: String,
+    pub key: String,
+    pub failures: u32,
+    pub created_at: DateTime<Utc>,
+    pub locked_until: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct InviteInfo {
+    pub id: i64,
+    pub created_by: i32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub max_uses: u32,
+    pub uses: u32,
+}
+
+/// Result of a registration with an invite code
+pub enum InviteRegistration {
+    Registered,
+    AlreadyExists,
+    InvalidInvite,
+}
+
+/// Result of a reservation of usage under quotas
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsageReservation {
+    Reserved,
+    /// Index of the first quota reached, with the exhausted resource
+    Exhausted {
+        quota: usize,
+        resource: &'static str,
+    },
+}
+
+#[derive(Clone)]
+pub struct ApiTokenInfo {
+    pub id: i64,
+    pub user_id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Kind of a security relevant event recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Login,
+    Register,
+    Logout,
+    ProcessImage,
+    ProcessBatch,
+    DeleteAccount,
+    ChangePassword,
+    /// Password reset token issued from the command line
+    IssuePasswordReset,
+    /// Password reset token redeemed
+    ResetPassword,
+    ChangeRole,
+    DisableUser,
+    EnableUser,
+    DeleteUser,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::Register => "register",
+            AuditEvent::Logout => "logout",
+            AuditEvent::ProcessImage => "process_image",
+            AuditEvent::ProcessBatch => "process_batch",
+            AuditEvent::DeleteAccount => "delete_account",
+            AuditEvent::ChangePassword => "change_password",
+            AuditEvent::IssuePasswordReset => "issue_password_reset",
+            AuditEvent::ResetPassword => "reset_password",
+            AuditEvent::ChangeRole => "change_role",
+            AuditEvent::DisableUser => "disable_user",
+            AuditEvent::EnableUser => "enable_user",
+            AuditEvent::DeleteUser => "delete_user",
+        }
+    }
+}
+
+impl FromStr for AuditEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login" => Ok(AuditEvent::Login),
+            "register" => Ok(AuditEvent::Register),
+            "logout" => Ok(AuditEvent::Logout),
+            "process_image" => Ok(AuditEvent::ProcessImage),
+            "process_batch" => Ok(AuditEvent::ProcessBatch),
+            "delete_account" => Ok(AuditEvent::DeleteAccount),
+            "change_password" => Ok(AuditEvent::ChangePassword),
+            "issue_password_reset" => Ok(AuditEvent::IssuePasswordReset),
+            "reset_password" => Ok(AuditEvent::ResetPassword),
+            "change_role" => Ok(AuditEvent::ChangeRole),
+            "disable_user" => Ok(AuditEvent::DisableUser),
+            "enable_user" => Ok(AuditEvent::EnableUser),
+            "delete_user" => Ok(AuditEvent::DeleteUser),
+            _ => Err(format!("invalid audit event '{}'", s)),
         }
     }
+}
 
-    pub fn connection(&self) -> MutexLockFuture<Connection> {
-        self.connection.lock()
+impl FromSql for AuditEvent {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
     }
+}
 
-    #[instrument(name = "UserDb::initialize", skip(self))]
-    pub async fn initialize(&self) -> AnyResult<()> {
-        info!("Initializing database");
-
-        let connection = self.connection().await;
-
-        connection.execute(
-            "create table if not exists users (
-                 id integer primary key,
-                 login text not null unique,
-                 password_hash text not null
-             )",
-            [],
-        )?;
-
-        connection.execute(
-            "create table if not exists sessions (
-                 id string primary key,
-                 user_id integer not null,
-                 valid_until integer not null
-             )",
-            [],
-        )?;
-
-        Ok(())
+impl ToSql for AuditEvent {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
     }
+}
 
-    #[instrument(name = "UserDb::register", skip(self, password_hash))]
-    pub async fn register(
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+        }
+    }
+}
+
+impl FromStr for AuditOutcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(AuditOutcome::Success),
+            "failure" => Ok(AuditOutcome::Failure),
+            _ => Err(format!("invalid audit outcome '{}'", s)),
+        }
+    }
+}
+
+impl FromSql for AuditOutcome {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+impl ToSql for AuditOutcome {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+/// Event of the audit log. The actor is the user who caused it, when known
+#[derive(Clone)]
+pub struct AuditEntry {
+    pub created_at: DateTime<Utc>,
+    pub event: AuditEvent,
+    pub actor_id: Option<i32>,
+    pub actor_login: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuditOutcome,
+    pub details: Option<String>,
+}
+
+/// Entry stored in the audit log
+#[derive(Clone)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub entry: AuditEntry,
+}
+
+/// Criteria of the audit entries to list, the newest come first
+#[derive(Debug, Clone)]
+pub struct AuditFilter {
+    pub actor_id: Option<i32>,
+    pub event: Option<AuditEvent>,
+    pub outcome: Option<AuditOutcome>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only the entries older than this one, to get the next page
+    pub before_id: Option<i64>,
+    pub limit: u32,
+}
+
+/// Resources consumed by image processing requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct Usage {
+    pub requests: u32,
+    /// Size of the input images, in millions of pixels
+    pub megapixels: f64,
+    /// Time spent decoding and enhancing the images, without waiting in the queue
+    pub compute_seconds: f64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.megapixels += other.megapixels;
+        self.compute_seconds += other.compute_seconds;
+    }
+}
+
+/// Usage of a single processing request
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub usage: Usage,
+}
+
+/// Quotas of a user chosen by an administrator. A `None` limit keeps the default one and 0
+/// removes the limit
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../client/src/bindings/")]
+pub struct QuotaOverride {
+    pub daily_requests: Option<u32>,
+    pub daily_megapixels: Option<f64>,
+    pub daily_compute_seconds: Option<f64>,
+    pub monthly_requests: Option<u32>,
+    pub monthly_megapixels: Option<f64>,
+    pub monthly_compute_seconds: Option<f64>,
+}
+
+/// Operations on the users and their credentials, implemented by each storage
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Bring the schema of the database up to date
+    async fn initialize(&self) -> AnyResult<()>;
+
+    /// Copy the storage to the new file `destination`, the server can keep running meanwhile
+    async fn backup(&self, destination: PathBuf) -> AnyResult<()>;
+
+    async fn register(&self, login: &str, password_hash: &str) -> Result<bool, DbError>;
+
+    /// Create an account with its role and state in a single step, returns `false` if the login
+    /// already exists
+    async fn import_user(
         &self,
         login: &str,
         password_hash: &str,
-    ) -> Result<bool, rusqlite::Error> {
-        let connection = self.connection().await;
-
-        let result = connection.execute(
-            "INSERT INTO users(login, password_hash) VALUES (?1, ?2)",
-            params![login, password_hash],
-        );
-
-        if let Err(error) = result {
-            match error {
-                rusqlite::Error::SqliteFailure(sql_error, _) => {
-                    if sql_error.code == rusqlite::ErrorCode::ConstraintViolation {
-                        Ok(false)
-                    } else {
-                        Err(error)
-                    }
-                }
-                _ => Err(error),
-            }
-        } else {
-            Ok(true)
-        }
-    }
+        role: Role,
+        disabled: bool,
+        invited_by: Option<i32>,
+    ) -> Result<bool, DbError>;
 
-    #[instrument(name = "UserDb::get_user_by_login", skip(self))]
-    pub async fn get_user_by_login(
+    /// Register a user if the invite code is valid, and count its use
+    async fn register_with_invite(
         &self,
         login: &str,
-    ) -> Result<Option<UserInfo>, rusqlite::Error> {
-        let connection = self.connection().await;
-
-        let result = connection.query_row(
-            "SELECT id, password_hash FROM users WHERE login=?1",
-            [login],
-            |r| {
-                let id: i32 = r.get(0)?;
-                let password_hash: String = r.get(1)?;
-                Ok(UserInfo {
-                    id,
-                    login: login.into(),
-                    password_hash,
-                })
-            },
-        );
-
-        match result {
-            Ok(info) => Ok(Some(info)),
-            Err(err) => match err {
-                rusqlite::Error::QueryReturnedNoRows => Ok(None),
-                _ => Err(err),
-            },
-        }
-    }
+        password_hash: &str,
+        code_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<InviteRegistration, DbError>;
+
+    async fn get_user_by_login(&self, login: &str) -> Result<Option<UserInfo>, DbError>;
+
+    async fn get_user_by_id(&self, id: i32) -> Result<Option<UserInfo>, DbError>;
+
+    async fn list_users(&self) -> Result<Vec<UserInfo>, DbError>;
+
+    /// Returns `false` if the user doesn't exist
+    async fn set_user_role(&self, id: i32, role: Role) -> Result<bool, DbError>;
+
+    /// Returns `false` if the user doesn't exist
+    async fn set_user_disabled(&self, id: i32, disabled: bool) -> Result<bool, DbError>;
+
+    /// Remove a user with their sessions, tokens and usage, and clear the user, IP address and user
+    /// agent of their audit entries. Returns `false` if the user doesn't exist
+    async fn delete_user(&self, id: i32) -> Result<bool, DbError>;
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), DbError>;
 
-    #[instrument(name = "UserDb::create_session", skip(self))]
-    pub async fn create_session(
+    async fn create_session(
         &self,
         id: &str,
         user_id: i32,
         valid_until: DateTime<Utc>,
-    ) -> Result<(), rusqlite::Error> {
-        let connection = self.connection().await;
+        created_at: DateTime<Utc>,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<(), DbError>;
 
-        connection.execute(
-            "INSERT INTO sessions(id, user_id, valid_until) VALUES (?1, ?2, ?3)",
-            params![id, user_id, valid_until.timestamp()],
-        )?;
+    /// Record activity on a session and extend its validity
+    async fn touch_session(
+        &self,
+        id: &str,
+        last_seen_at: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+    ) -> Result<(), DbError>;
 
-        Ok(())
-    }
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>, DbError>;
 
-    #[instrument(name = "UserDb::remove_session", skip(self))]
-    pub async fn remove_session(&self, id: &str) -> Result<(), rusqlite::Error> {
-        let connection = self.connection().await;
+    /// Remove all the sessions that expired before `now`, returns the number of removed sessions
+    async fn remove_expired_sessions(&self, now: DateTime<Utc>) -> Result<usize, DbError>;
 
-        connection.execute("DELETE FROM sessions WHERE id=?1", params![id])?;
+    async fn remove_session(&self, id: &str) -> Result<(), DbError>;
 
-        Ok(())
-    }
+    /// Remove all the sessions of a user but `except`, returns the number of removed sessions
+    async fn remove_user_sessions(
+        &self,
+        user_id: i32,
+        except: Option<&str>,
+    ) -> Result<usize, DbError>;
 
-    #[instrument(name = "UserDb::get_session_by_id", skip(self))]
-    pub async fn get_session_by_id(&self, id: &str) -> Result<SessionInfo, rusqlite::Error> {
-        let connection = self.connection().await;
-
-        connection.query_row(
-            "SELECT user_id, valid_until FROM sessions WHERE id=?1",
-            [id],
-            |r| {
-                let user_id: i32 = r.get(0)?;
-                let valid_until: i32 = r.get(1)?;
-                Ok(SessionInfo {
-                    id: id.into(),
-                    user_id,
-                    valid_until: Utc.timestamp(valid_until as i64, 0),
-                })
-            },
-        )
-    }
+    /// Fails with `DbError::NotFound` if the session doesn't exist
+    async fn get_session_by_id(&self, id: &str) -> Result<SessionInfo, DbError>;
+
+    async fn create_api_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiTokenInfo, DbError>;
+
+    async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiTokenInfo>, DbError>;
+
+    /// Remove a token of the user, returns `false` if it doesn't exist
+    async fn remove_api_token(&self, user_id: i32, id: i64) -> Result<bool, DbError>;
+
+    async fn get_api_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiTokenInfo>, DbError>;
+
+    async fn set_api_token_last_used(
+        &self,
+        id: i64,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<(), DbError>;
+
+    /// Store a password reset token for a user, replacing the previous ones
+    async fn create_password_reset(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), DbError>;
+
+    /// Remove a password reset token and return its user, `None` if it's unknown or expired
+    async fn consume_password_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i32>, DbError>;
+
+    async fn get_login_failures(
+        &self,
+        scope: &str,
+        key: &str,
+    ) -> Result<Option<LoginFailures>, DbError>;
+
+    /// Count a failed login at `now` and return the number of consecutive failures, the previous
+    /// ones are forgotten when the last of them happened before `forget_before`
+    async fn add_login_failure(
+        &self,
+        scope: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        forget_before: DateTime<Utc>,
+    ) -> Result<u32, DbError>;
+
+    /// Lock the login name or IP address until `locked_until`, unless it's already locked longer
+    async fn lock_login(
+        &self,
+        scope: &str,
+        key: &str,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError>;
+
+    async fn clear_login_failures(&self, scope: &str, key: &str) -> Result<(), DbError>;
+
+    /// Remove the failure counters whose last failure happened before `forget_before` and that
+    /// aren't locked at `now` anymore, returns the number of removed counters
+    async fn remove_expired_login_failures(
+        &self,
+        forget_before: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<usize, DbError>;
+
+    /// Keep track of a lockout so that administrators can review them
+    async fn record_lockout(
+        &self,
+        scope: &str,
+        key: &str,
+        failures: u32,
+        created_at: DateTime<Utc>,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), DbError>;
+
+    async fn list_lockouts(&self, limit: u32) -> Result<Vec<LockoutInfo>, DbError>;
+
+    /// Remove all the lockouts but the `keep` most recent ones, returns the number of removed ones
+    async fn remove_old_lockouts(&self, keep: u32) -> Result<usize, DbError>;
+
+    async fn create_invite(
+        &self,
+        code_hash: &str,
+        created_by: i32,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        max_uses: u32,
+    ) -> Result<InviteInfo, DbError>;
+
+    async fn list_invites(&self) -> Result<Vec<InviteInfo>, DbError>;
+
+    /// Returns `false` if the invite doesn't exist
+    async fn remove_invite(&self, id: i64) -> Result<bool, DbError>;
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<(), DbError>;
+
+    async fn list_audit_entries(&self, filter: AuditFilter) -> Result<Vec<AuditRecord>, DbError>;
+
+    /// Remove the audit entries created before `before`, returns the number of removed entries
+    async fn remove_audit_entries_before(&self, before: DateTime<Utc>) -> Result<usize, DbError>;
+
+    async fn add_usage(&self, record: UsageRecord) -> Result<(), DbError>;
+
+    /// Add `record` unless the usage of the user since the start of one of the `quotas` reached
+    /// its limits. The check and the addition are a single step, concurrent reservations can't
+    /// both take the last request of a quota
+    async fn reserve_usage(
+        &self,
+        record: UsageRecord,
+        quotas: Vec<(DateTime<Utc>, QuotaLimits)>,
+    ) -> Result<UsageReservation, DbError>;
+
+    /// Total usage of a user since `since`
+    async fn sum_usage(&self, user_id: i32, since: DateTime<Utc>) -> Result<Usage, DbError>;
+
+    /// All the usage records of a user, oldest first
+    async fn list_usage(&self, user_id: i32) -> Result<Vec<UsageRecord>, DbError>;
+
+    async fn get_quota_override(&self, user_id: i32) -> Result<Option<QuotaOverride>, DbError>;
+
+    /// Replace the quotas of a user, returns `false` if the user doesn't exist
+    async fn set_quota_override(
+        &self,
+        user_id: i32,
+        quotas: QuotaOverride,
+    ) -> Result<bool, DbError>;
+
+    /// Go back to the default quotas, returns `false` if the user had none of their own
+    async fn remove_quota_override(&self, user_id: i32) -> Result<bool, DbError>;
 }
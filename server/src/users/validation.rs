@@ -0,0 +1,111 @@
+use serde::Serialize;
+use structopt::StructOpt;
+
+/// Rules applied to new logins and passwords
+#[derive(Debug, Clone, StructOpt)]
+pub struct ValidationOptions {
+    #[structopt(long, default_value = "3")]
+    pub login_min_length: usize,
+
+    #[structopt(long, default_value = "32")]
+    pub login_max_length: usize,
+
+    /// Characters allowed in logins besides ASCII letters and digits
+    #[structopt(long, default_value = "._-")]
+    pub login_extra_chars: String,
+
+    #[structopt(long, default_value = "8")]
+    pub password_min_length: usize,
+
+    /// Number of character classes (lowercase, uppercase, digits, others) a password must mix
+    #[structopt(long, default_value = "2")]
+    pub password_min_classes: usize,
+}
+
+/// Validation error of one field of a request
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Form in which logins are stored and compared, so that their case doesn't matter
+pub fn normalize_login(login: &str) -> String {
+    login.trim().to_lowercase()
+}
+
+impl ValidationOptions {
+    /// Check a normalized login
+    pub fn validate_login(&self, login: &str, errors: &mut Vec<FieldError>) {
+        let length = login.chars().count();
+        if length < self.login_min_length || length > self.login_max_length {
+            errors.push(FieldError::new(
+                "login",
+                format!(
+                    "Login must be between {} and {} characters long",
+                    self.login_min_length, self.login_max_length
+                ),
+            ));
+        }
+
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || self.login_extra_chars.contains(c);
+        if !login.chars().all(valid_char) {
+            errors.push(FieldError::new(
+                "login",
+                format!(
+                    "Login can only contain letters, digits and '{}'",
+                    self.login_extra_chars
+                ),
+            ));
+        }
+    }
+
+    /// Check the strength of a password, and that it's different from the login when it's known
+    pub fn validate_password(
+        &self,
+        field: &'static str,
+        password: &str,
+        login: Option<&str>,
+        errors: &mut Vec<FieldError>,
+    ) {
+        if password.chars().count() < self.password_min_length {
+            errors.push(FieldError::new(
+                field,
+                format!(
+                    "Password must be at least {} characters long",
+                    self.password_min_length
+                ),
+            ));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|present| **present).count() < self.password_min_classes {
+            errors.push(FieldError::new(
+                field,
+                format!(
+                    "Password must mix at least {} of lowercase letters, uppercase letters, digits \
+                     and symbols",
+                    self.password_min_classes
+                ),
+            ));
+        }
+
+        if matches!(login, Some(login) if normalize_login(password) == login) {
+            errors.push(FieldError::new(field, "Password can't be the login"));
+        }
+    }
+}